@@ -0,0 +1,128 @@
+//! Where `HashMap` gets the memory for its bucket array and entries.
+//!
+//! With the nightly `allocator_api` feature, `Allocator` and `Global` are the
+//! ones from `alloc`, so any allocator can be plugged in through `new_in`.
+//! Without it they are stand-ins with the same shape, and `Global` is the
+//! only allocator. Either way the map only allocates through the helpers
+//! below, which work the same on both.
+
+use core::{alloc::Layout, ptr::{self, NonNull}};
+use alloc::alloc::handle_alloc_error;
+
+#[cfg(feature = "allocator_api")]
+pub use alloc::alloc::{AllocError, Allocator, Global};
+
+#[cfg(not(feature = "allocator_api"))]
+pub use shim::{AllocError, Allocator, Global};
+
+#[cfg(not(feature = "allocator_api"))]
+mod shim {
+
+    use core::{alloc::Layout, fmt, ptr::{self, NonNull}};
+
+    mod sealed {
+        pub trait Sealed {}
+    }
+
+    /// The allocation failed
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AllocError;
+
+    impl fmt::Display for AllocError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("memory allocation failed")
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl std::error::Error for AllocError {}
+
+    /// Stand-in for `alloc::alloc::Allocator`, only implemented by `Global`
+    /// until the `allocator_api` feature is enabled
+    pub trait Allocator: sealed::Sealed {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError>;
+        fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError>;
+        /// # Safety
+        ///
+        /// `ptr` must come from this allocator with the same `layout`
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+    }
+
+    /// The global allocator
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Global;
+
+    impl sealed::Sealed for Global {}
+
+    impl Global {
+        fn alloc_impl(layout: Layout, zeroed: bool) -> Result<NonNull<[u8]>, AllocError> {
+            let raw = if layout.size() == 0 {
+                // The global allocator can't take zero sized requests
+                ptr::without_provenance_mut(layout.align())
+            } else if zeroed {
+                unsafe { alloc::alloc::alloc_zeroed(layout) }
+            } else {
+                unsafe { alloc::alloc::alloc(layout) }
+            };
+
+            NonNull::new(raw)
+                .map(|raw| NonNull::slice_from_raw_parts(raw, layout.size()))
+                .ok_or(AllocError)
+        }
+    }
+
+    impl Allocator for Global {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            Self::alloc_impl(layout, false)
+        }
+
+        fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            Self::alloc_impl(layout, true)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            if layout.size() != 0 {
+                alloc::alloc::dealloc(ptr.as_ptr(), layout);
+            }
+        }
+    }
+}
+
+/// Move `val` into memory from `alloc`
+pub(crate) fn alloc_in<T, A: Allocator>(alloc: &A, val: T) -> *mut T {
+    let layout = Layout::new::<T>();
+    let raw = match alloc.allocate(layout) {
+        Ok(raw) => raw.cast::<T>().as_ptr(),
+        Err(_)  => handle_alloc_error(layout),
+    };
+
+    unsafe { ptr::write(raw, val) };
+    raw
+}
+
+/// A zeroed `T` from `alloc`. The caller must make sure all zeroes is a
+/// valid `T`, like an array of null `AtomicPtr`.
+pub(crate) unsafe fn try_alloc_zeroed_in<T, A: Allocator>(alloc: &A) -> Result<*mut T, AllocError> {
+    alloc.allocate_zeroed(Layout::new::<T>())
+        .map(|raw| raw.cast::<T>().as_ptr())
+}
+
+/// Give back memory from `alloc_in` or `try_alloc_zeroed_in` without dropping
+/// what it holds
+pub(crate) unsafe fn dealloc_in<T, A: Allocator>(alloc: &A, raw: *mut T) {
+    alloc.deallocate(NonNull::new_unchecked(raw).cast(), Layout::new::<T>());
+}
+
+/// Drop the `T` at `raw` and give back its memory, even if the drop panics
+pub(crate) unsafe fn free_in<T, A: Allocator>(alloc: &A, raw: *mut T) {
+    struct Dealloc<'a, T, A: Allocator>(&'a A, *mut T);
+
+    impl<T, A: Allocator> Drop for Dealloc<'_, T, A> {
+        fn drop(&mut self) {
+            unsafe { dealloc_in(self.0, self.1) };
+        }
+    }
+
+    let _dealloc = Dealloc(alloc, raw);
+    ptr::drop_in_place(raw);
+}
@@ -0,0 +1,172 @@
+//! A capped map evicting its oldest keys to make room, for caches.
+//!
+//! The age of a key is its place in the insertion order of the map, see
+//! `HashMap::iter_ordered`. Inserts through `&self` are the concurrent ones
+//! of a capped `HashMap`, failing with `HashMapErr::HashMapFull` once the cap
+//! is hit.
+//! Evicting moves values out of the map, which is only safe with no `&V`
+//! around, so it takes `&mut self`: `insert_evicting` makes room for one key,
+//! `evict_oldest` for a batch at once, e.g. under the write lock of a cache
+//! whose readers and inserters share a read lock.
+//!
+//! Finding the oldest keys walks and sorts the whole map, so evicting in
+//! batches is what keeps the cost down for big caps.
+
+use alloc::vec::Vec;
+
+use crate::{FibonacciHasher, HashMap, HashMapErr, IndexHasher};
+
+pub struct CacheHashMap<V, const N: usize, H = FibonacciHasher> {
+
+    /// Capped at the capacity of the cache
+    map             : HashMap<V, N, H>,
+}
+
+impl<V, const N: usize> CacheHashMap<V, N> {
+
+    /// Cache holding up to `capacity` keys
+    pub fn new(capacity: usize) -> Self {
+        Self::with_hasher(capacity, FibonacciHasher)
+    }
+}
+
+impl<V, const N: usize, H: IndexHasher> CacheHashMap<V, N, H> {
+
+    /// Build a cache placing keys with `hasher`
+    pub fn with_hasher(capacity: usize, hasher: H) -> Self {
+        CacheHashMap {
+            map:           HashMap::with_hasher(hasher).with_max_entries(capacity),
+        }
+    }
+
+    pub fn entries(&self) -> usize {
+        self.map.entries()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Most keys the cache holds before evicting
+    pub fn capacity(&self) -> usize {
+        self.map.max_entries
+    }
+
+    pub fn lookup(&self, key: usize) -> Option<&V> {
+        self.map.lookup(key)
+    }
+
+    pub fn contains_key(&self, key: usize) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Insert a entry like `HashMap::insert`, failing with `HashMapFull` once
+    /// the cache is at capacity
+    #[must_use = "the insert may have been rejected"]
+    pub fn insert(&self, key: usize, value: V) -> Result<&V, HashMapErr<'_, V>> {
+        self.map.insert(key, value)
+    }
+
+    /// Insert `key`, evicting the oldest key first if the cache is full, and
+    /// return the evicted pair. A key already there keeps its value and age,
+    /// `value` is dropped.
+    pub fn insert_evicting(&mut self, key: usize, value: V) -> Option<(usize, V)> {
+        if self.contains_key(key) || self.len() < self.capacity() {
+            let _ = self.insert(key, value);
+            return None;
+        }
+
+        let evicted = self.evict_oldest(1).pop();
+        let _ = self.insert(key, value);
+        evicted
+    }
+
+    /// Evict the `count` oldest keys, returning them oldest first
+    pub fn evict_oldest(&mut self, count: usize) -> Vec<(usize, V)> {
+        let oldest: Vec<_> = self.map.iter_ordered()
+            .take(count)
+            .map(|(&key, _)| key)
+            .collect();
+
+        let evicted = oldest.into_iter()
+            // No `&V` outlives `&mut self`
+            .filter_map(|key| self.map.remove(key).map(|val| (key, val)))
+            .collect();
+
+        // The tombstones go to the free list, for the inserts taking their room
+        self.map.compact();
+        evicted
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn test_cache_evicts_oldest() {
+        let mut cache = CacheHashMap::<u64, 16>::new(10);
+
+        let mut evicted = Vec::new();
+        for key in 0..15 {
+            evicted.extend(cache.insert_evicting(key * 7, key as u64));
+        }
+
+        assert_eq!(cache.len(), 10);
+        assert_eq!(evicted, (0..5).map(|key| (key * 7, key as u64)).collect::<Vec<_>>());
+        for key in 0..15 {
+            assert_eq!(cache.lookup(key * 7), (key >= 5).then_some(&(key as u64)));
+        }
+
+        // A key already there is no insert, nothing gets evicted for it
+        assert_eq!(cache.insert_evicting(14 * 7, 0), None);
+        assert_eq!(cache.lookup(14 * 7), Some(&14));
+        assert_eq!(cache.len(), 10);
+
+        // The age of a key isn't refreshed either
+        assert_eq!(cache.insert_evicting(1000, 1000), Some((5 * 7, 5)));
+        assert_eq!(cache.map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_cache_evict_batch() {
+        let cache = Arc::new(CacheHashMap::<u64, 64>::new(100));
+
+        // Concurrent inserts until the cap, then a batch eviction
+        let handles: Vec<_> = (0..4).map(|t| {
+            let cache_tx = cache.clone();
+            std::thread::spawn(move || {
+                (0..50).filter(|key| cache_tx.insert(key * 4 + t, 0).is_ok()).count()
+            })
+        }).collect();
+        let inserted: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(inserted, 100);
+
+        let mut cache = Arc::try_unwrap(cache).ok().unwrap();
+        assert!(matches!(cache.insert(1000, 0), Err(HashMapErr::HashMapFull)));
+
+        // Whatever the interleaving, the first 25 in the order go
+        let order: Vec<_> = cache.map.iter_ordered().map(|(&key, _)| key).collect();
+
+        let evicted = cache.evict_oldest(25);
+        assert_eq!(evicted.len(), 25);
+        assert_eq!(cache.len(), 75);
+        assert_eq!(evicted.iter().map(|&(key, _)| key).collect::<Vec<_>>(), order[..25]);
+        assert!(cache.map.iter_ordered().map(|(&key, _)| key).eq(order[25..].iter().copied()));
+
+        for key in 1000..1025 {
+            assert!(cache.insert(key, 0).is_ok());
+        }
+        assert!(cache.insert(2000, 0).is_err());
+        assert_eq!(cache.evict_oldest(1000).len(), 100);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! Sorted arrays over the deep end of the bucket chains.
+//!
+//! Past `threshold` entries a chain is also indexed by a run per bucket: its
+//! entries from the threshold on, sorted by key. A lookup walks at most
+//! `threshold` entries, binary searches the run and then walks what got
+//! appended since, instead of the whole chain. The entries stay chained, the
+//! run only points into the chain, which keeps removal, iteration and drop
+//! unaware of it.
+//!
+//! Runs are immutable. Every `threshold` entries appended past the threshold
+//! the insert of the last one merges them into a new run and swaps it in, so
+//! no more than `threshold` entries are ever left to walk after the search.
+//! A replaced run is freed once no lookup of its bucket is reading it.
+
+use core::ptr;
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{Bucket, Entry};
+use crate::sync::{AtomicPtr, AtomicUsize, Ordering, UnsyncAccess};
+
+/// The entries of a chain from the threshold up to `last`, sorted by key.
+/// Entries of the same key keep their chain order.
+#[derive(Debug)]
+struct Run<V> {
+    entries     : Box<[(usize, *mut Entry<V>)]>,
+    /// Last entry covered, the chain goes on from its `next`
+    last        : *mut Entry<V>,
+    /// Link in the list of retired runs
+    next        : AtomicPtr<Run<V>>,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    /// Null until the chain gets a run
+    run         : AtomicPtr<Run<V>>,
+    /// Threads reading `run`
+    readers     : AtomicUsize,
+    /// Runs replaced while being read
+    retired     : AtomicPtr<Run<V>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ChainIndex<V> {
+
+    /// Chain position from which entries get indexed, at least 1
+    pub(crate) threshold    : usize,
+
+    /// Run of every bucket
+    slots                   : Box<[Slot<V>]>,
+}
+
+impl<V> ChainIndex<V> {
+
+    pub(crate) fn new(buckets: usize, threshold: usize) -> Self {
+        ChainIndex {
+            threshold:  threshold.max(1),
+            slots:      (0..buckets).map(|_| Slot {
+                run:        AtomicPtr::new(ptr::null_mut()),
+                readers:    AtomicUsize::new(0),
+                retired:    AtomicPtr::new(ptr::null_mut()),
+            }).collect(),
+        }
+    }
+
+    /// Whether the entry appended at chain position `depth` closes a batch
+    /// to merge into the run
+    #[inline]
+    pub(crate) fn due(&self, depth: usize) -> bool {
+        depth >= self.threshold && (depth + 1 - self.threshold).is_multiple_of(self.threshold)
+    }
+
+    /// Publish a run of `bucket` covering the chain up to `last_ptr`, at
+    /// position `depth`. A run covering more already published is kept.
+    pub(crate) fn extend(&self, bucket: &Bucket<V>, idx: usize, depth: usize, last_ptr: *mut Entry<V>) {
+        let slot = &self.slots[idx];
+        let len = depth + 1 - self.threshold;
+
+        slot.readers.fetch_add(1, Ordering::SeqCst);
+        let mut cur_ptr = slot.run.load(Ordering::SeqCst);
+
+        let mut new_ptr = match unsafe { cur_ptr.as_ref() } {
+            Some(run) if run.entries.len() >= len => ptr::null_mut(),
+            run => {
+                // The entries past the current run, or past the threshold
+                let (done, mut entry_ptr): (&[_], _) = match run {
+                    Some(run) => (&run.entries, unsafe { (*run.last).next_ptr() }),
+                    None => {
+                        let mut entry_ptr = bucket.load(Ordering::Acquire);
+                        for _ in 0..self.threshold {
+                            entry_ptr = unsafe { (*entry_ptr).next_ptr() };
+                        }
+                        (&[], entry_ptr)
+                    }
+                };
+
+                let mut batch = Vec::with_capacity(len - done.len());
+                loop {
+                    batch.push((unsafe { (*entry_ptr).key }, entry_ptr));
+                    if entry_ptr == last_ptr {
+                        break;
+                    }
+                    entry_ptr = unsafe { (*entry_ptr).next_ptr() };
+                }
+                // Stable, like the merge: equal keys stay in chain order
+                batch.sort_by_key(|&(key, _)| key);
+
+                Box::into_raw(Box::new(Run {
+                    entries:    merge(done, &batch),
+                    last:       last_ptr,
+                    next:       AtomicPtr::new(ptr::null_mut()),
+                }))
+            }
+        };
+
+        // Any run covering `len` entries holds the same ones, a racing
+        // extend of a longer chain only makes ours useless
+        while !new_ptr.is_null() {
+            match slot.run.compare_exchange(cur_ptr, new_ptr,
+                Ordering::SeqCst,
+                Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(actual) if unsafe { &*actual }.entries.len() >= len => {
+                    drop(unsafe { Box::from_raw(new_ptr) });
+                    new_ptr = ptr::null_mut();
+                }
+                Err(actual) => cur_ptr = actual,
+            }
+        }
+
+        slot.readers.fetch_sub(1, Ordering::SeqCst);
+        if new_ptr.is_null() {
+            return;
+        }
+
+        if !cur_ptr.is_null() {
+            push(&slot.retired, cur_ptr);
+        }
+        reclaim(slot);
+    }
+
+    /// Where a lookup past the threshold goes on: the first entry of `key`
+    /// in the run, else the first one past the run. `None` while the chain
+    /// has no run, counting the keys compared in `probes`.
+    pub(crate) fn find(&self, idx: usize, key: usize, probes: &mut usize) -> Option<*mut Entry<V>> {
+        let slot = &self.slots[idx];
+
+        slot.readers.fetch_add(1, Ordering::SeqCst);
+        let run_ptr = slot.run.load(Ordering::SeqCst);
+
+        let resume_ptr = unsafe { run_ptr.as_ref() }.map(|run| {
+            let (mut lo, mut hi) = (0, run.entries.len());
+            while lo < hi {
+                *probes += 1;
+                let mid = lo + (hi - lo) / 2;
+                if run.entries[mid].0 < key {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+
+            match run.entries.get(lo) {
+                Some(&(found, entry_ptr)) if found == key => entry_ptr,
+                _ => unsafe { (*run.last).next_ptr() },
+            }
+        });
+
+        slot.readers.fetch_sub(1, Ordering::SeqCst);
+        resume_ptr
+    }
+}
+
+/// `done` and `batch` merged, the entries of `done` first on equal keys
+fn merge<V>(done: &[(usize, *mut Entry<V>)], batch: &[(usize, *mut Entry<V>)])
+    -> Box<[(usize, *mut Entry<V>)]> {
+    let mut merged = Vec::with_capacity(done.len() + batch.len());
+    let (mut i, mut j) = (0, 0);
+    while i < done.len() && j < batch.len() {
+        if batch[j].0 < done[i].0 {
+            merged.push(batch[j]);
+            j += 1;
+        } else {
+            merged.push(done[i]);
+            i += 1;
+        }
+    }
+    merged.extend_from_slice(&done[i..]);
+    merged.extend_from_slice(&batch[j..]);
+    merged.into_boxed_slice()
+}
+
+fn push<V>(list: &AtomicPtr<Run<V>>, run_ptr: *mut Run<V>) {
+    let mut head = list.load(Ordering::SeqCst);
+    loop {
+        unsafe { (*run_ptr).next.store(head, Ordering::Relaxed) };
+        match list.compare_exchange_weak(head, run_ptr,
+            Ordering::SeqCst,
+            Ordering::SeqCst) {
+            Ok(_)       => return,
+            Err(actual) => head = actual,
+        }
+    }
+}
+
+/// Free the retired runs of `slot` if nobody reads it. A reader arriving
+/// after the check loads a run swapped in after they were taken.
+fn reclaim<V>(slot: &Slot<V>) {
+    let mut run_ptr = slot.retired.swap(ptr::null_mut(), Ordering::SeqCst);
+    let free = slot.readers.load(Ordering::SeqCst) == 0;
+
+    while !run_ptr.is_null() {
+        let next_ptr = unsafe { (*run_ptr).next.load(Ordering::Relaxed) };
+        if free {
+            drop(unsafe { Box::from_raw(run_ptr) });
+        } else {
+            push(&slot.retired, run_ptr);
+        }
+        run_ptr = next_ptr;
+    }
+}
+
+impl<V> Drop for ChainIndex<V> {
+    fn drop(&mut self) {
+        // Only the runs, the entries belong to the chains
+        for slot in self.slots.iter_mut() {
+            let mut run_ptr = slot.run.read_mut();
+            if !run_ptr.is_null() {
+                drop(unsafe { Box::from_raw(run_ptr) });
+            }
+
+            run_ptr = slot.retired.read_mut();
+            while !run_ptr.is_null() {
+                let mut run = unsafe { Box::from_raw(run_ptr) };
+                run_ptr = run.next.read_mut();
+            }
+        }
+    }
+}
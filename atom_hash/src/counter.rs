@@ -0,0 +1,79 @@
+//! The entries counter of `HashMap`, split over cache lines.
+//!
+//! A single counter is a cache line every inserting thread writes to, which
+//! bounces between their cores on every insert. Here uncapped maps count the
+//! entries of a bucket in shard `idx % SHARDS`, so threads inserting random
+//! keys mostly write to different lines, and `entries()` adds the shards up.
+//!
+//! A cap has to be checked against the exact total in one atomic step, so
+//! capped maps count everything in shard 0 and pay for the shared line.
+
+use crate::sync::{AtomicUsize, Ordering, UnsyncAccess};
+
+const SHARDS: usize = 16;
+
+/// A shard alone on its cache line
+#[repr(align(64))]
+struct Shard(AtomicUsize);
+
+pub(crate) struct Counter {
+    shards      : [Shard; SHARDS],
+}
+
+impl Counter {
+
+    #[cfg(not(loom))]
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: Shard = Shard(AtomicUsize::new(0));
+
+    #[cfg(not(loom))]
+    pub(crate) const fn new() -> Self {
+        Counter { shards: [Self::ZERO; SHARDS] }
+    }
+
+    /// Loom's atomics can't be built in a const
+    #[cfg(loom)]
+    pub(crate) fn new() -> Self {
+        Counter { shards: core::array::from_fn(|_| Shard(AtomicUsize::new(0))) }
+    }
+
+    /// The shard counting the entries of bucket `idx`
+    #[inline]
+    pub(crate) fn shard(idx: usize, capped: bool) -> usize {
+        if capped { 0 } else { idx % SHARDS }
+    }
+
+    /// The total. A shard may wrap below zero when an entry counted by a
+    /// batch in shard 0 is removed from its own, the signed sum wraps back.
+    /// An entry removed before the batch that linked it got counted takes
+    /// the total itself below zero for a moment, read as 0.
+    pub(crate) fn sum(&self) -> usize {
+        let sum = self.shards.iter()
+            .fold(0isize, |sum, shard| sum.wrapping_add(shard.0.load(Ordering::Acquire) as isize));
+        sum.max(0) as usize
+    }
+
+    #[inline]
+    pub(crate) fn add(&self, shard: usize, n: usize) {
+        self.shards[shard].0.fetch_add(n, Ordering::AcqRel);
+    }
+
+    #[inline]
+    pub(crate) fn sub(&self, shard: usize, n: usize) {
+        self.shards[shard].0.fetch_sub(n, Ordering::AcqRel);
+    }
+
+    /// Count one more in shard 0 unless the total reached `max`
+    pub(crate) fn add_capped(&self, max: usize) -> bool {
+        self.shards[0].0.fetch_update(Ordering::AcqRel, Ordering::Acquire,
+            |n| (n < max).then_some(n + 1)).is_ok()
+    }
+
+    /// Move the whole count to shard 0, where a cap sees it
+    pub(crate) fn set(&mut self, n: usize) {
+        for shard in &mut self.shards {
+            shard.0.write_mut(0);
+        }
+        self.shards[0].0.write_mut(n);
+    }
+}
@@ -0,0 +1,243 @@
+//! Replayable interleavings of threads working on the maps, for tests.
+//!
+//! The inserts and removals of `HashMap` call a seam right before each CAS
+//! that can lose a race: claiming an empty bucket, appending to a chain tail,
+//! reviving a tombstone and taking a live entry. With the `deterministic`
+//! feature, threads started by `run_interleaved` run one at a time, and each
+//! seam hands the turn to the thread the `Schedule` picks. The picks are
+//! returned as a trace, and `Schedule::Replay` of that trace runs the same
+//! interleaving again, on the real atomics: a property test draws seeds, and
+//! a failing seed turns into a trace to debug or keep as a regression test.
+//! Threads not started by `run_interleaved` pass the seams as if they weren't
+//! there, and without the feature the seams compile to nothing.
+//!
+//! This is not a model checker. Between seams a thread runs alone on real
+//! hardware, so only the interleavings at the seams are explored, and only
+//! the ones the seeds happen to draw, under the memory model of the machine.
+//! Exhaustive exploration, weak orderings included, is loom's job, see
+//! `--cfg loom`, and the seams are where its model branches the most. The
+//! two go together, loom on small maps for the orderings, this on whole
+//! scenarios too big for it.
+
+use std::cell::RefCell;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::vec::Vec;
+use std::boxed::Box;
+
+use xorshift::Rng;
+
+/// Which thread gets the turn at each seam
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Random picks among the running threads, fixed by the seed
+    Seeded(usize),
+    /// The picks of a trace returned by `run_interleaved`, then the lowest
+    /// running thread once it runs out
+    Replay(Vec<usize>),
+}
+
+struct State {
+    /// The thread allowed to run
+    turn        : usize,
+    done        : Vec<bool>,
+    /// Picks so far
+    trace       : Vec<usize>,
+    schedule    : Schedule,
+    rng         : Rng,
+}
+
+struct Shared {
+    state       : Mutex<State>,
+    turn_passed : Condvar,
+}
+
+std::thread_local! {
+    /// The interleaving the thread runs in, and its index there
+    static CURRENT: RefCell<Option<(Arc<Shared>, usize)>> = const { RefCell::new(None) };
+}
+
+impl Shared {
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A panicking thread never holds the lock, but its guard still runs
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Pick the next thread to run, none once all are done
+    fn pick(state: &mut State) -> Option<usize> {
+        let running: Vec<usize> = (0..state.done.len()).filter(|&t| !state.done[t]).collect();
+        if running.is_empty() {
+            return None;
+        }
+
+        let next = match &state.schedule {
+            Schedule::Seeded(_) => running[state.rng.get_random(running.len())],
+            Schedule::Replay(trace) => trace.get(state.trace.len())
+                .copied()
+                .filter(|t| running.contains(t))
+                .unwrap_or(running[0]),
+        };
+        state.trace.push(next);
+        Some(next)
+    }
+
+    /// Give the turn away, and wait to get it back unless `me` is done
+    fn pass_turn(&self, me: usize, finished: bool) {
+        let mut state = self.lock();
+        if finished {
+            state.done[me] = true;
+        }
+        if let Some(next) = Self::pick(&mut state) {
+            state.turn = next;
+        }
+        self.turn_passed.notify_all();
+
+        if !finished {
+            drop(self.wait_turn(state, me));
+        }
+    }
+
+    fn wait_turn<'a>(&self, state: MutexGuard<'a, State>, me: usize) -> MutexGuard<'a, State> {
+        self.turn_passed.wait_while(state, |state| state.turn != me)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Called by the maps before a CAS that can lose a race
+pub(crate) fn seam() {
+    CURRENT.with(|current| {
+        if let Some((shared, me)) = &*current.borrow() {
+            shared.pass_turn(*me, false);
+        }
+    });
+}
+
+/// Marks its thread done when the closure returns or panics
+struct Finish;
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        if let Some((shared, me)) = CURRENT.with(|current| current.borrow_mut().take()) {
+            shared.pass_turn(me, true);
+        }
+    }
+}
+
+/// Run every closure on a thread of its own, one at a time, switching
+/// threads at the seams as `schedule` says. Returns the trace of the picks,
+/// for `Schedule::Replay`.
+///
+/// The closures should only synchronize through the maps: a thread blocking
+/// on anything else, e.g. a channel, deadlocks the others waiting for their
+/// turn. A panic in a closure is resumed here once all threads are done.
+pub fn run_interleaved<'a>(schedule: Schedule, threads: Vec<Box<dyn FnOnce() + Send + 'a>>) -> Vec<usize> {
+    let seed = match schedule {
+        Schedule::Seeded(seed) => seed,
+        Schedule::Replay(_) => 0,
+    };
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            turn:     0,
+            done:     std::vec![false; threads.len()],
+            trace:    Vec::new(),
+            schedule,
+            rng:      Rng::new(seed),
+        }),
+        turn_passed: Condvar::new(),
+    });
+
+    {
+        let mut state = shared.lock();
+        if let Some(first) = Shared::pick(&mut state) {
+            state.turn = first;
+        }
+    }
+
+    std::thread::scope(|scope| {
+        for (me, thread) in threads.into_iter().enumerate() {
+            let shared = shared.clone();
+            scope.spawn(move || {
+                drop(shared.wait_turn(shared.lock(), me));
+                CURRENT.with(|current| *current.borrow_mut() = Some((shared, me)));
+
+                let _finish = Finish;
+                thread();
+            });
+        }
+    });
+
+    let trace = core::mem::take(&mut shared.lock().trace);
+    trace
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::{EpochHashMap, HashMap, IdentityHasher};
+
+    /// Two threads inserting the same key, the winner depends on the seed
+    /// alone
+    #[test]
+    fn test_interleaved_same_key() {
+        let insert = |schedule| {
+            let map = HashMap::<u64, 8>::new();
+            let trace = run_interleaved(schedule, std::vec![
+                Box::new(|| { let _ = map.insert(3, 1); }) as Box<dyn FnOnce() + Send>,
+                Box::new(|| { let _ = map.insert(3, 2); }),
+            ]);
+            (*map.lookup(3).unwrap(), trace)
+        };
+
+        let mut winners = BTreeSet::new();
+        for seed in 0..32 {
+            let (winner, trace) = insert(Schedule::Seeded(seed));
+            assert_eq!(insert(Schedule::Seeded(seed)).0, winner);
+            assert_eq!(insert(Schedule::Replay(trace)).0, winner);
+            winners.insert(winner);
+        }
+        assert_eq!(winners, BTreeSet::from([1, 2]));
+    }
+
+    /// Both threads see the bucket empty, the loser of the CAS on the head
+    /// appends behind the winner
+    #[test]
+    fn test_interleaved_lost_head() {
+        let map = HashMap::<u64, 8, IdentityHasher>::with_hasher(IdentityHasher);
+        let trace = run_interleaved(Schedule::Replay(std::vec![0, 1, 0, 1]), std::vec![
+            Box::new(|| assert!(map.insert(3, 3).is_ok())) as Box<dyn FnOnce() + Send>,
+            Box::new(|| assert!(map.insert(11, 11).is_ok())),
+        ]);
+
+        assert_eq!(trace, [0, 1, 0, 1, 1]);
+        assert_eq!(map.iter().map(|(&key, _)| key).collect::<Vec<_>>(), [3, 11]);
+        assert_eq!(map.collisions(), 1);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    /// An insert of a key racing with the concurrent remove retiring it
+    #[test]
+    fn test_interleaved_remove() {
+        let mut outcomes = BTreeSet::new();
+        for seed in 0..64 {
+            let map = EpochHashMap::<u64, 8, IdentityHasher>::with_hasher(IdentityHasher);
+            assert!(map.insert(3, 0, &map.pin()).is_ok());
+
+            let removed = Mutex::new(None);
+            run_interleaved(Schedule::Seeded(seed), std::vec![
+                Box::new(|| *removed.lock().unwrap() = map.remove(3, &map.pin()).copied())
+                    as Box<dyn FnOnce() + Send>,
+                Box::new(|| { let _ = map.insert(3, 1, &map.pin()); }),
+                Box::new(|| assert!(map.insert(11, 2, &map.pin()).is_ok())),
+            ]);
+
+            // The insert finds the key either live or retired
+            assert_eq!(removed.into_inner().unwrap(), Some(0));
+            assert_eq!(map.get_cloned(11), Some(2));
+            outcomes.insert(map.get_cloned(3));
+        }
+        assert_eq!(outcomes, BTreeSet::from([None, Some(1)]));
+    }
+}
@@ -0,0 +1,567 @@
+//! Safe removal through epoch based reclamation.
+//!
+//! `HashMap::remove` takes `&mut self` because `lookup` hands out `&V` for
+//! as long as the map is borrowed. `EpochHashMap` wraps a `HashMap` and only
+//! hands out references bound to a `Guard`, so a removed value can be dropped
+//! as soon as every guard that could have seen it is gone.
+//!
+//! The scheme is the classic one: a global epoch, and every pinned guard
+//! publishes the epoch it saw. The epoch only advances once every pinned guard
+//! has caught up with it, so a value retired at epoch `e` is unreachable once
+//! the global epoch reaches `e + 2`.
+//!
+//! Removed entries are unlinked from their chain by `collect`, one collector
+//! at a time so two unlinks never race on a link, and recycled two epochs
+//! after that. A removed tail stays chained until an insert appends to it,
+//! since the append could be racing the unlink.
+
+use core::{marker::PhantomData, mem::ManuallyDrop, ptr};
+use core::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+
+use crate::{Entry, FibonacciHasher, HashMap, HashMapErr, IndexHasher};
+
+/// Low bit of a participant's epoch, set while pinned
+const PINNED: usize = 1;
+
+/// A pin slot. Slots are recycled between guards and only freed with the
+/// collector, so walking the list never touches freed memory.
+struct Participant {
+    /// `epoch << 1 | PINNED` while pinned, 0 otherwise
+    epoch       : AtomicUsize,
+    in_use      : AtomicBool,
+    next        : AtomicPtr<Participant>,
+}
+
+/// A retired entry waiting for its value to be dropped, then to be recycled
+struct Garbage<V> {
+    entry       : *mut Entry<V>,
+    /// When it got retired, or unlinked once `unlinked`
+    epoch       : usize,
+    /// The value is gone
+    dropped     : bool,
+    unlinked    : bool,
+    next        : *mut Garbage<V>,
+}
+
+struct Collector<V> {
+    epoch           : AtomicUsize,
+    participants    : AtomicPtr<Participant>,
+    garbage         : AtomicPtr<Garbage<V>>,
+    /// Held by the one collector unlinking entries
+    unlinking       : AtomicBool,
+}
+
+impl<V> Collector<V> {
+
+    fn new() -> Self {
+        Collector {
+            epoch:          AtomicUsize::new(0),
+            participants:   AtomicPtr::new(ptr::null_mut()),
+            garbage:        AtomicPtr::new(ptr::null_mut()),
+            unlinking:      AtomicBool::new(false),
+        }
+    }
+
+    /// Grab a free slot or push a new one
+    fn participant(&self) -> &Participant {
+        let mut cur = self.participants.load(Ordering::Acquire);
+        while !cur.is_null() {
+            let participant = unsafe { &*cur };
+            if participant.in_use.compare_exchange(false, true,
+                Ordering::Acquire,
+                Ordering::Relaxed).is_ok() {
+                return participant;
+            }
+            cur = participant.next.load(Ordering::Acquire);
+        }
+
+        let new_ptr = Box::into_raw(Box::new(Participant {
+            epoch:  AtomicUsize::new(0),
+            in_use: AtomicBool::new(true),
+            next:   AtomicPtr::new(ptr::null_mut()),
+        }));
+        let new = unsafe { &*new_ptr };
+
+        let mut head = self.participants.load(Ordering::Acquire);
+        loop {
+            new.next.store(head, Ordering::Relaxed);
+            match self.participants.compare_exchange_weak(head, new_ptr,
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => return new,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    fn pin(&self) -> Guard<'_> {
+        let participant = self.participant();
+
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        // Release so collectors reading it also see what the previous guard
+        // of the slot read
+        participant.epoch.store(epoch << 1 | PINNED, Ordering::Release);
+        // Publish the pin before reading anything out of the map
+        fence(Ordering::SeqCst);
+
+        Guard { participant, collector: self as *const Self as *const (), _marker: PhantomData }
+    }
+
+    /// Advance the epoch if every pinned guard saw the current one
+    fn try_advance(&self) -> usize {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+
+        let mut cur = self.participants.load(Ordering::Acquire);
+        while !cur.is_null() {
+            let participant = unsafe { &*cur };
+            let local = participant.epoch.load(Ordering::Relaxed);
+            if local & PINNED != 0 && local >> 1 != epoch {
+                return epoch;
+            }
+            cur = participant.next.load(Ordering::Acquire);
+        }
+        fence(Ordering::Acquire);
+
+        // Losing means another collector advanced it, after checking the guards
+        match self.epoch.compare_exchange(epoch, epoch.wrapping_add(1),
+            Ordering::SeqCst,
+            Ordering::Acquire) {
+            Ok(_)       => epoch.wrapping_add(1),
+            Err(actual) => actual,
+        }
+    }
+
+    fn push_garbage(&self, node_ptr: *mut Garbage<V>) {
+        let mut head = self.garbage.load(Ordering::Acquire);
+        loop {
+            unsafe { (*node_ptr).next = head };
+            match self.garbage.compare_exchange_weak(head, node_ptr,
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => return,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Queue a retired entry of the map
+    fn retire(&self, entry: *mut Entry<V>) {
+        // Ordered after the retirement, like pins are before their reads
+        fence(Ordering::SeqCst);
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        self.push_garbage(Box::into_raw(Box::new(Garbage {
+            entry,
+            epoch,
+            dropped:    false,
+            unlinked:   false,
+            next:       ptr::null_mut(),
+        })));
+    }
+
+    /// Drop the values no guard can see anymore and recycle the entries no
+    /// guard can reach into `map`, unlinking the others. Returns how many
+    /// values were dropped.
+    fn collect<const N: usize, H: IndexHasher>(&self, map: &HashMap<V, N, H>) -> usize {
+        let epoch = self.try_advance();
+
+        // Another collector unlinking leaves ours for the next round
+        let unlinking = self.unlinking.compare_exchange(false, true,
+            Ordering::Acquire,
+            Ordering::Relaxed).is_ok();
+
+        // Take the whole list, put back what isn't done yet
+        let mut cur = self.garbage.swap(ptr::null_mut(), Ordering::AcqRel);
+        let mut dropped = 0;
+
+        while !cur.is_null() {
+            let node = unsafe { &mut *cur };
+            let next = node.next;
+
+            // Entries retired after we read the epoch can be ahead of it
+            if epoch.wrapping_sub(node.epoch) as isize >= 2 {
+                if !node.dropped {
+                    unsafe { ManuallyDrop::drop(&mut *(*node.entry).val.get()) };
+                    node.dropped = true;
+                    dropped += 1;
+                }
+
+                if node.unlinked {
+                    unsafe { map.recycle(node.entry) };
+                    drop(unsafe { Box::from_raw(cur) });
+                    cur = next;
+                    continue;
+                }
+            }
+
+            if unlinking && !node.unlinked && unsafe { map.unlink(node.entry) } {
+                // Walkers already on it may still be pinned, wait two more epochs
+                node.unlinked = true;
+                fence(Ordering::SeqCst);
+                node.epoch = self.epoch.load(Ordering::Relaxed);
+            }
+
+            self.push_garbage(cur);
+            cur = next;
+        }
+
+        if unlinking {
+            self.unlinking.store(false, Ordering::Release);
+        }
+
+        dropped
+    }
+
+    /// Drop the pending values and recycle the unlinked entries, once no
+    /// guard is left
+    fn release<const N: usize, H>(&mut self, map: &HashMap<V, N, H>) {
+        let mut cur = *self.garbage.get_mut();
+        *self.garbage.get_mut() = ptr::null_mut();
+
+        while !cur.is_null() {
+            let node = unsafe { Box::from_raw(cur) };
+            if !node.dropped {
+                unsafe { ManuallyDrop::drop(&mut *(*node.entry).val.get()) };
+            }
+            // The chained ones go with the map
+            if node.unlinked {
+                unsafe { map.recycle(node.entry) };
+            }
+            cur = node.next;
+        }
+    }
+}
+
+impl<V> Drop for Collector<V> {
+    fn drop(&mut self) {
+        let mut cur = *self.participants.get_mut();
+        while !cur.is_null() {
+            let participant = unsafe { Box::from_raw(cur) };
+            cur = participant.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+/// Keeps the values read through it alive until dropped. Hold guards briefly:
+/// a guard that stays pinned holds back the reclamation of every removal.
+pub struct Guard<'a> {
+    participant : &'a Participant,
+    /// Identity of the collector, to catch guards used on another map
+    collector   : *const (),
+    _marker     : PhantomData<&'a ()>,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.participant.epoch.store(0, Ordering::Release);
+        self.participant.in_use.store(false, Ordering::Release);
+    }
+}
+
+pub struct EpochHashMap<V, const N: usize, H = FibonacciHasher> {
+    collector       : Collector<V>,
+    map             : HashMap<V, N, H>,
+}
+
+impl<V, const N: usize, H> Drop for EpochHashMap<V, N, H> {
+    fn drop(&mut self) {
+        // No guard can outlive the map, every pending value can go
+        self.collector.release(&self.map);
+    }
+}
+
+impl<V, const N: usize> EpochHashMap<V, N> {
+
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::with_hasher(FibonacciHasher)
+    }
+}
+
+impl<V, const N: usize, H: IndexHasher> EpochHashMap<V, N, H> {
+
+    pub fn with_hasher(hasher: H) -> Self {
+        EpochHashMap {
+            collector:  Collector::new(),
+            map:        HashMap::with_hasher(hasher),
+        }
+    }
+
+    pub fn entries(&self) -> usize {
+        self.map.entries()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Pin the current epoch, values read through the guard stay valid
+    /// until it is dropped
+    pub fn pin(&self) -> Guard<'_> {
+        self.collector.pin()
+    }
+
+    #[inline]
+    fn check_guard(&self, guard: &Guard<'_>) {
+        assert!(ptr::eq(guard.collector, &self.collector as *const Collector<V> as *const ()),
+            "guard pinned on another map");
+    }
+
+    pub fn lookup<'g>(&'g self, key: usize, guard: &'g Guard<'_>) -> Option<&'g V> {
+        self.check_guard(guard);
+        self.map.lookup(key)
+    }
+
+    /// Clone of the value taken under a short lived pin, so it survives any
+    /// later removal of the key
+    pub fn get_cloned(&self, key: usize) -> Option<V> where V: Clone {
+        let guard = self.pin();
+        self.lookup(key, &guard).cloned()
+    }
+
+    /// Pinned for the walk, removed entries get recycled
+    pub fn contains_key(&self, key: usize) -> bool {
+        let _guard = self.pin();
+        self.map.contains_key(key)
+    }
+
+    /// Insert a entry into the table
+    pub fn insert<'g>(&'g self, key: usize, value: V, guard: &'g Guard<'_>)
+        -> Result<&'g V, HashMapErr<'g, V>> {
+        self.check_guard(guard);
+        self.map.insert(key, value)
+    }
+
+    /// Remove `key`, returning its value which stays readable until `guard`
+    /// is dropped. A later insert of the key adds a new entry.
+    pub fn remove<'g>(&'g self, key: usize, guard: &'g Guard<'_>) -> Option<&'g V> {
+        self.check_guard(guard);
+
+        let entry_ptr = self.map.retire(key)?;
+        self.collector.retire(entry_ptr);
+
+        // Opportunistically reclaim what older removals left behind
+        self.collector.collect(&self.map);
+
+        Some(unsafe { (*entry_ptr).value() })
+    }
+
+    /// Replace the value of `key`, returning the old one which stays
+    /// readable until `guard` is dropped, or insert `value` and return `None`.
+    ///
+    /// The new value goes into a fresh entry appended to the chain before
+    /// the old one is retired, so a concurrent `lookup` finds one of them,
+    /// never a moment without the key. It's meant for one writer per key:
+    /// two threads replacing the same key at once leave both new values in,
+    /// and lookups only see one of them.
+    pub fn insert_or_replace<'g>(&'g self, key: usize, value: V, guard: &'g Guard<'_>) -> Option<&'g V> {
+        self.check_guard(guard);
+
+        let entry_ptr = self.map.replace(key, value)?;
+        self.collector.retire(entry_ptr);
+        self.collector.collect(&self.map);
+
+        Some(unsafe { (*entry_ptr).value() })
+    }
+
+    /// Drop the removed values no guard can see anymore and recycle their
+    /// entries for later inserts. Removals already do this, returns how many
+    /// values were dropped.
+    pub fn collect(&self) -> usize {
+        self.collector.collect(&self.map)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_util::DropCounter;
+
+    #[test]
+    fn test_epoch_remove() {
+        let map = EpochHashMap::<String, 8>::new();
+        let guard = map.pin();
+
+        let _ = map.insert(0, "first string".into(), &guard);
+        let _ = map.insert(8, "second string".into(), &guard);
+
+        assert_eq!(map.remove(0, &guard).unwrap(), "first string");
+        assert!(map.remove(0, &guard).is_none());
+        assert!(map.lookup(0, &guard).is_none());
+        assert_eq!(map.entries(), 1);
+
+        // A new entry for the key follows the retired one
+        assert!(map.insert(0, "third string".into(), &guard).is_ok());
+        assert_eq!(map.lookup(0, &guard).unwrap(), "third string");
+        assert!(map.insert(0, "nope".into(), &guard).is_err());
+        assert_eq!(map.entries(), 2);
+    }
+
+    /// Values are only dropped once no guard can see them, and exactly once
+    #[test]
+    fn test_epoch_reclaim() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = EpochHashMap::<DropCounter, 8>::new();
+
+        {
+            let guard = map.pin();
+            for key in 0..4 {
+                let _ = map.insert(key, DropCounter::new(&drops), &guard);
+            }
+
+            let removed = map.remove(1, &guard).unwrap();
+            let reader = map.pin();
+
+            // Both guards hold the value back
+            assert_eq!(map.collect() + map.collect() + map.collect(), 0);
+            assert_eq!(drops.load(Ordering::Relaxed), 0);
+            assert_eq!(removed.0.load(Ordering::Relaxed), 0);
+            drop(reader);
+        }
+
+        // Unpinned, a couple of epochs later it's gone
+        let mut freed = 0;
+        for _ in 0..3 {
+            freed += map.collect();
+        }
+        assert_eq!(freed, 1);
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+
+        // Pending and live values go with the map
+        let guard = map.pin();
+        let _ = map.remove(2, &guard);
+        drop(guard);
+        drop(map);
+        assert_eq!(drops.load(Ordering::Relaxed), 4);
+    }
+
+    /// Entries chained, on the free list or waiting to be recycled
+    fn held<V, const N: usize>(map: &EpochHashMap<V, N>) -> usize {
+        let mut unlinked = 0;
+        let mut cur = map.collector.garbage.load(Ordering::Acquire);
+        while !cur.is_null() {
+            unlinked += unsafe { (*cur).unlinked } as usize;
+            cur = unsafe { (*cur).next };
+        }
+        map.map.bucket_histogram().iter().sum::<usize>() + map.map.free.len() + unlinked
+    }
+
+    /// Removed entries get reused, churning a key doesn't pile them up
+    #[test]
+    fn test_epoch_churn() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = EpochHashMap::<DropCounter, 8>::new();
+
+        for round in 0..1000 {
+            let guard = map.pin();
+            assert!(map.insert(3, DropCounter::new(&drops), &guard).is_ok());
+            assert!(map.remove(3, &guard).is_some());
+            drop(guard);
+
+            assert!(held(&map) <= 4, "{} entries held after {} rounds", held(&map), round + 1);
+        }
+
+        for _ in 0..4 {
+            map.collect();
+        }
+        assert_eq!(drops.load(Ordering::Relaxed), 1000);
+        assert!(map.map.free.len() > 0);
+
+        // The removed tail stays chained, behind no other entry
+        assert_eq!(map.map.bucket_histogram().iter().sum::<usize>(), 1);
+        assert!(!map.contains_key(3));
+    }
+
+    #[test]
+    fn test_epoch_insert_or_replace() {
+        let map = EpochHashMap::<String, 8>::new();
+        let guard = map.pin();
+
+        assert!(map.insert_or_replace(0, "first".into(), &guard).is_none());
+        let old = map.insert_or_replace(0, "second".into(), &guard).unwrap();
+        assert_eq!(map.lookup(0, &guard).unwrap(), "second");
+
+        // Still readable, the retired entry is skipped by removal
+        assert_eq!(old, "first");
+        assert_eq!(map.remove(0, &guard).unwrap(), "second");
+        assert!(map.lookup(0, &guard).is_none());
+        assert_eq!(map.entries(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "guard pinned on another map")]
+    fn test_epoch_foreign_guard() {
+        let map1 = EpochHashMap::<u64, 8>::new();
+        let map2 = EpochHashMap::<u64, 8>::new();
+
+        let guard = map2.pin();
+        let _ = map1.lookup(0, &guard);
+    }
+
+    /// Insert, remove and lookup hammering the same keys while values get
+    /// reclaimed. Readers check the values they see are intact.
+    #[test]
+    fn test_epoch_stress() {
+        let map = Arc::new(EpochHashMap::<String, 16>::new());
+
+        let handles: Vec<_> = (0..8).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for i in 0..2000 {
+                    let key = (i * 7 + x) % 64;
+                    let guard = map_tx.pin();
+
+                    match i % 3 {
+                        0 => { let _ = map_tx.insert(key, format!("value {}", key), &guard); },
+                        1 => {
+                            if let Some(v) = map_tx.remove(key, &guard) {
+                                assert_eq!(*v, format!("value {}", key));
+                            }
+                        },
+                        _ => {
+                            if let Some(v) = map_tx.lookup(key, &guard) {
+                                assert_eq!(*v, format!("value {}", key));
+                            }
+                        },
+                    }
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let guard = map.pin();
+        let live = (0..64).filter(|&key| map.lookup(key, &guard).is_some()).count();
+        assert_eq!(live, map.entries());
+    }
+
+    #[test]
+    fn test_epoch_get_cloned() {
+        let map = EpochHashMap::<String, 8>::new();
+        let guard = map.pin();
+        let _ = map.insert(0, "first string".into(), &guard);
+        drop(guard);
+
+        let v = map.get_cloned(0).unwrap();
+
+        let guard = map.pin();
+        let _ = map.remove(0, &guard);
+        drop(guard);
+        for _ in 0..3 {
+            map.collect();
+        }
+
+        assert_eq!(v, "first string");
+        assert!(map.get_cloned(0).is_none());
+    }
+}
@@ -0,0 +1,112 @@
+//! Entries freed by `compact`, `retain`, `clear` and `EpochHashMap::collect`,
+//! kept for later inserts.
+//!
+//! A Treiber stack linked through the `next` pointer of the entries. The ABA
+//! problem of Treiber stacks needs two pops overlapping, an entry popped and
+//! pushed back while another pop still holds it as the head. So only one
+//! thread pops at a time, the others just allocate. Nothing here is freed
+//! while the map is shared, so reading the next pointer of a popped entry is
+//! fine.
+
+use core::ptr;
+
+use crate::{dealloc_in, Allocator, Entry};
+use crate::sync::{AtomicBool, AtomicPtr, AtomicUsize, Ordering, UnsyncAccess};
+
+pub(crate) struct FreeList<V> {
+    head        : AtomicPtr<Entry<V>>,
+    len         : AtomicUsize,
+    /// Held by the one thread popping
+    popping     : AtomicBool,
+}
+
+impl<V> FreeList<V> {
+
+    #[cfg(not(loom))]
+    pub(crate) const fn new() -> Self {
+        FreeList {
+            head:       AtomicPtr::new(ptr::null_mut()),
+            len:        AtomicUsize::new(0),
+            popping:    AtomicBool::new(false),
+        }
+    }
+
+    /// Loom's atomics can't be built in a const
+    #[cfg(loom)]
+    pub(crate) fn new() -> Self {
+        FreeList {
+            head:       AtomicPtr::new(ptr::null_mut()),
+            len:        AtomicUsize::new(0),
+            popping:    AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Keep `entry_ptr`, an unlinked entry whose value is already gone
+    pub(crate) fn push(&self, entry_ptr: *mut Entry<V>) {
+        // Counted first so a racing pop never takes the count below zero
+        self.len.fetch_add(1, Ordering::AcqRel);
+
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            unsafe { (*entry_ptr).next.store(head, Ordering::Relaxed) };
+            match self.head.compare_exchange_weak(head, entry_ptr,
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => break,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// An entry to overwrite, if there is one and no one else is popping
+    pub(crate) fn pop(&self) -> Option<*mut Entry<V>> {
+        if self.head.load(Ordering::Acquire).is_null() {
+            return None;
+        }
+
+        if self.popping.compare_exchange(false, true,
+            Ordering::Acquire,
+            Ordering::Relaxed).is_err() {
+            return None;
+        }
+
+        // Pushes may move the head, but nobody else takes it from us
+        let mut entry_ptr = self.head.load(Ordering::Acquire);
+        while !entry_ptr.is_null() {
+            let next_ptr = unsafe { (*entry_ptr).next.load(Ordering::Relaxed) };
+            match self.head.compare_exchange_weak(entry_ptr, next_ptr,
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => break,
+                Err(actual) => entry_ptr = actual,
+            }
+        }
+
+        self.popping.store(false, Ordering::Release);
+
+        if entry_ptr.is_null() {
+            return None;
+        }
+        self.len.fetch_sub(1, Ordering::AcqRel);
+        Some(entry_ptr)
+    }
+
+    /// Give every entry back to `alloc`
+    pub(crate) fn release<A: Allocator>(&mut self, alloc: &A) -> usize {
+        let mut released = 0;
+        let mut entry_ptr = self.head.read_mut();
+        self.head.write_mut(ptr::null_mut());
+        while !entry_ptr.is_null() {
+            let next_ptr = unsafe { (*entry_ptr).next.read_mut() };
+            unsafe { dealloc_in(alloc, entry_ptr) };
+            entry_ptr = next_ptr;
+            released += 1;
+        }
+        self.len.write_mut(0);
+        released
+    }
+}
@@ -0,0 +1,359 @@
+//! A map that grows by chaining fixed maps of increasing size.
+//!
+//! `N` is a const generic, so a `HashMap` can't grow. `GrowableHashMap` keeps
+//! a list of segments, each one a `HashMap` with 4 times the buckets of the
+//! previous one and capped at one entry per bucket. Inserts go to the newest
+//! segment, and the first insert to find it full publishes the next one and
+//! bumps the generation. Lookups check the segments newest-first, which is
+//! where most of the entries live.
+//!
+//! Entries are never moved to the newer segments, and segments live as long
+//! as the map, so a `&V` stays valid across growth. That makes the map usable
+//! as an interner whose ids are references or pointers.
+//!
+//! A key must not end up in two segments. Before inserting into the newest
+//! segment a writer waits for the inserts still in flight in the older ones,
+//! which can't take new ones, and then checks they don't have the key. That
+//! wait only happens right after a growth and lasts as long as those inserts.
+
+use core::{hint, ptr};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+
+use crate::{FibonacciHasher, Global, HashMap, HashMapErr, IndexHasher, Iter, Staged};
+
+/// One variant per segment size, dispatching to the fixed map inside
+macro_rules! segments {
+    ($($level:literal => $variant:ident($buckets:literal)),* $(,)?) => {
+
+        enum Segment<V, H> {
+            $($variant(HashMap<V, $buckets, H>),)*
+        }
+
+        impl<V, H: IndexHasher> Segment<V, H> {
+
+            /// The last level takes every insert, the others fill up
+            fn new(level: usize, hasher: H) -> Self {
+                match level {
+                    $($level => Segment::$variant(HashMap::with_hasher(hasher)
+                        .with_max_entries(if level + 1 == LEVELS { usize::MAX } else { $buckets }))),*,
+                    _ => unreachable!("no segment past the last level"),
+                }
+            }
+
+            fn lookup(&self, key: usize) -> Option<&V> {
+                match self {
+                    $(Segment::$variant(map) => map.lookup(key)),*
+                }
+            }
+
+            fn insert_staged(&self, key: usize, staged: &mut Staged<'_, V, fn() -> V>)
+                -> Result<&V, HashMapErr<'_, V>> {
+                match self {
+                    $(Segment::$variant(map) => map.insert_staged(key, staged)),*
+                }
+            }
+
+            fn entries(&self) -> usize {
+                match self {
+                    $(Segment::$variant(map) => map.entries()),*
+                }
+            }
+
+            fn iter(&self) -> Iter<'_, V> {
+                match self {
+                    $(Segment::$variant(map) => map.iter()),*
+                }
+            }
+        }
+    };
+}
+
+segments! {
+    0 => S0(64),
+    1 => S1(256),
+    2 => S2(1024),
+    3 => S3(4096),
+    4 => S4(16384),
+    5 => S5(65536),
+    6 => S6(262144),
+    7 => S7(1048576),
+    8 => S8(4194304),
+    9 => S9(16777216),
+}
+
+/// Number of segment sizes
+const LEVELS: usize = 10;
+
+struct SegmentSlot<V, H> {
+    segment     : Segment<V, H>,
+
+    /// Inserts in flight into this segment
+    writers     : AtomicUsize,
+}
+
+pub struct GrowableHashMap<V, H = FibonacciHasher> {
+
+    /// Number of published segments, at least 1
+    generation      : AtomicUsize,
+
+    /// Published segments, from the smallest
+    segments        : [AtomicPtr<SegmentSlot<V, H>>; LEVELS],
+
+    /// Cloned into every new segment
+    hasher          : H,
+}
+
+// The segments sit behind `AtomicPtr`s, which are `Send + Sync` whatever
+// they point to, so the bounds are those of `HashMap` spelled out.
+
+/// Safety: the map owns its segments, moving it moves their values and hashers
+unsafe impl<V: Send, H: Send> Send for GrowableHashMap<V, H> {}
+
+/// Safety: segments are `HashMap`s shared like any other, and the hasher is
+/// only cloned through `&H` when a thread publishes a new segment
+unsafe impl<V: Send + Sync, H: Sync> Sync for GrowableHashMap<V, H> {}
+
+impl<V, H> Drop for GrowableHashMap<V, H> {
+    fn drop(&mut self) {
+        for segment in &mut self.segments {
+            let slot_ptr = *segment.get_mut();
+            if !slot_ptr.is_null() {
+                drop(unsafe { Box::from_raw(slot_ptr) });
+            }
+        }
+    }
+}
+
+impl<V> GrowableHashMap<V> {
+
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::with_hasher(FibonacciHasher)
+    }
+}
+
+impl<V, H: IndexHasher + Clone> GrowableHashMap<V, H> {
+
+    /// Build a map placing keys with `hasher` in every segment
+    pub fn with_hasher(hasher: H) -> Self {
+        let first = Box::into_raw(Box::new(SegmentSlot {
+            segment: Segment::new(0, hasher.clone()),
+            writers: AtomicUsize::new(0),
+        }));
+
+        let segments = [const { AtomicPtr::new(ptr::null_mut()) }; LEVELS];
+        segments[0].store(first, Ordering::Relaxed);
+
+        GrowableHashMap {
+            generation:    AtomicUsize::new(1),
+            segments,
+            hasher,
+        }
+    }
+
+    /// Number of segments published so far
+    pub fn segments(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Sum of the live entries of every segment
+    pub fn entries(&self) -> usize {
+        self.published().map(|slot| slot.segment.entries()).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Segment `level`, which must be published
+    #[inline]
+    fn slot(&self, level: usize) -> &SegmentSlot<V, H> {
+        unsafe { &*self.segments[level].load(Ordering::Acquire) }
+    }
+
+    /// Published segments, newest first
+    fn published(&self) -> impl Iterator<Item = &SegmentSlot<V, H>> + '_ {
+        (0..self.segments()).rev().map(|level| self.slot(level))
+    }
+
+    pub fn lookup(&self, key: usize) -> Option<&V> {
+        self.published().find_map(|slot| slot.segment.lookup(key))
+    }
+
+    pub fn contains_key(&self, key: usize) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    /// Insert a entry into the newest segment, growing the map if it's full.
+    /// Never fails with `HashMapErr::HashMapFull`.
+    pub fn insert(&self, key: usize, value: V) -> Result<&V, HashMapErr<'_, V>> {
+        // Entries are the same type in every segment, so the staged value
+        // moves on to the next one if a segment is full
+        let mut staged = Staged::<V, fn() -> V>::ready(value, &Global, None);
+
+        loop {
+            let generation = self.generation.load(Ordering::SeqCst);
+            let slot = self.slot(generation - 1);
+
+            // Register before checking the generation, so whoever grows the
+            // map after this point sees us in flight
+            slot.writers.fetch_add(1, Ordering::SeqCst);
+            if self.generation.load(Ordering::SeqCst) != generation {
+                slot.writers.fetch_sub(1, Ordering::Release);
+                continue;
+            }
+
+            // The older segments take no more inserts, once the ones in
+            // flight are done they are final
+            for level in (0..generation - 1).rev() {
+                let old = self.slot(level);
+                while old.writers.load(Ordering::Acquire) != 0 {
+                    hint::spin_loop();
+                }
+
+                if let Some(val) = old.segment.lookup(key) {
+                    slot.writers.fetch_sub(1, Ordering::Release);
+                    return Err(HashMapErr::ExistentEntry(val));
+                }
+            }
+
+            let res = slot.segment.insert_staged(key, &mut staged);
+            slot.writers.fetch_sub(1, Ordering::Release);
+
+            match res {
+                Err(HashMapErr::HashMapFull) => self.grow(generation),
+                res => return res,
+            }
+        }
+    }
+
+    /// The value of `key`, inserting `value` if it's not there. The reference
+    /// stays valid however much the map grows afterwards, see the module docs.
+    pub fn get_or_insert(&self, key: usize, value: V) -> &V {
+        match self.insert(key, value) {
+            Ok(val) | Err(HashMapErr::ExistentEntry(val)) => val,
+            Err(HashMapErr::HashMapFull) => unreachable!("the last segment has no cap"),
+        }
+    }
+
+    /// Publish the segment after the first `generation` ones, unless another
+    /// thread already did
+    fn grow(&self, generation: usize) {
+        let next = &self.segments[generation];
+
+        if next.load(Ordering::Acquire).is_null() {
+            let new_ptr = Box::into_raw(Box::new(SegmentSlot {
+                segment: Segment::new(generation, self.hasher.clone()),
+                writers: AtomicUsize::new(0),
+            }));
+
+            if next.compare_exchange(ptr::null_mut(), new_ptr,
+                Ordering::AcqRel,
+                Ordering::Acquire).is_err() {
+                // Lost the race, the winner's segment is there
+                drop(unsafe { Box::from_raw(new_ptr) });
+            }
+        }
+
+        let _ = self.generation.compare_exchange(generation, generation + 1,
+            Ordering::SeqCst,
+            Ordering::SeqCst);
+    }
+
+    /// Live entries of every segment, from the oldest
+    pub fn iter(&self) -> impl Iterator<Item = (&usize, &V)> + '_ {
+        (0..self.segments()).flat_map(|level| self.slot(level).segment.iter())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn test_growable() {
+        let map = GrowableHashMap::<u64>::new();
+        assert!(map.is_empty());
+        assert_eq!(map.segments(), 1);
+
+        for key in 0..100_000 {
+            assert!(map.insert(key, key as u64).is_ok());
+        }
+
+        // 64 + 256 + ... + 65536 entries fill the first 6 segments
+        assert_eq!(map.segments(), 7);
+        assert_eq!(map.entries(), 100_000);
+        assert_eq!(map.iter().count(), 100_000);
+
+        for key in 0..100_000 {
+            assert_eq!(*map.lookup(key).unwrap(), key as u64);
+        }
+        assert!(!map.contains_key(100_000));
+
+        // Keys in old segments are still found by inserts
+        match map.insert(3, 0) {
+            Err(HashMapErr::ExistentEntry(v)) => assert_eq!(*v, 3),
+            _ => panic!()
+        }
+        assert_eq!(map.entries(), 100_000);
+    }
+
+    /// References taken before a growth keep pointing at the same value
+    #[test]
+    fn test_growable_stable_refs() {
+        let map = GrowableHashMap::<String>::new();
+
+        let early: Vec<&String> = (0..10).map(|key| map.get_or_insert(key, format!("id {}", key))).collect();
+        assert_eq!(map.segments(), 1);
+
+        for key in 10..2000 {
+            map.get_or_insert(key, format!("id {}", key));
+        }
+        assert!(map.segments() > 3);
+
+        for (key, val) in early.iter().enumerate() {
+            assert_eq!(**val, format!("id {}", key));
+            // Same entry, nothing got moved
+            assert!(core::ptr::eq(*val, map.get_or_insert(key, String::new())));
+            assert!(core::ptr::eq(*val, map.lookup(key).unwrap()));
+        }
+    }
+
+    /// Threads racing on the same keys while the map grows under them
+    #[test]
+    fn test_growable_threads() {
+        let map = Arc::new(GrowableHashMap::<u64>::new());
+
+        let handles: Vec<_> = (0..8u64).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                (0..2000).map(|key| *map_tx.insert(key, x).unwrap_or_else(|err| match err {
+                    HashMapErr::ExistentEntry(v) => v,
+                    HashMapErr::HashMapFull => panic!("HashMap is full"),
+                })).collect::<Vec<_>>()
+            })
+        }).collect();
+
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        // Everybody agrees on the value of every key
+        for r in &results[1..] {
+            assert_eq!(r, &results[0]);
+        }
+
+        assert_eq!(map.entries(), 2000);
+        assert_eq!(map.iter().count(), 2000);
+        for (key, v) in results[0].iter().enumerate() {
+            assert_eq!(map.lookup(key).unwrap(), v);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Strategies to turn a key into a bucket index
+
+use xorshift::Rng;
+
+/// Maps a key into one of `n` buckets. `index` must return a value below `n`.
+pub trait IndexHasher {
+    fn index(&self, key: usize, n: usize) -> usize;
+}
+
+/// Uses the key bits as they are. Fast, but keys clustering on the low bits
+/// (e.g. aligned pointers) pile up in a few buckets, see
+/// `HashMap::collision_estimate`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdentityHasher;
+
+impl IndexHasher for IdentityHasher {
+    #[inline]
+    fn index(&self, key: usize, n: usize) -> usize {
+        if n.is_power_of_two() {
+            key & (n - 1)
+        } else {
+            // Masking would leave some buckets unreachable
+            key % n
+        }
+    }
+}
+
+/// Fibonacci hashing: multiply by 2^64 / golden ratio and keep the high bits,
+/// so every key bit influences the bucket.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FibonacciHasher;
+
+impl FibonacciHasher {
+    const GOLDEN: u64 = 0x9E37_79B9_7F4A_7C15;
+}
+
+impl IndexHasher for FibonacciHasher {
+    #[inline]
+    fn index(&self, key: usize, n: usize) -> usize {
+        let mixed = (key as u64).wrapping_mul(Self::GOLDEN);
+        // Scale the high bits into [0, n) without a division
+        ((mixed as u128 * n as u128) >> 64) as usize
+    }
+}
+
+/// Fibonacci hashing over the key mixed with secret words drawn from a seed.
+/// Without the seed the bucket of a key can't be predicted, so an adversary
+/// can't pick keys that all pile up in one bucket.
+#[derive(Debug, Clone, Copy)]
+pub struct SeededHasher {
+    k0: u64,
+    k1: u64,
+}
+
+impl SeededHasher {
+    pub fn new(seed: usize) -> Self {
+        // Spread nearby seeds apart before drawing the words
+        let mut rng = Rng::new(seed).split();
+        SeededHasher {
+            k0: rng.rand_u64(),
+            // Odd, so the multiplication is a bijection
+            k1: rng.rand_u64() | 1,
+        }
+    }
+}
+
+impl IndexHasher for SeededHasher {
+    #[inline]
+    fn index(&self, key: usize, n: usize) -> usize {
+        let mut mixed = ((key as u64) ^ self.k0).wrapping_mul(self.k1);
+        mixed ^= mixed >> 32;
+        mixed = mixed.wrapping_mul(FibonacciHasher::GOLDEN);
+        ((mixed as u128 * n as u128) >> 64) as usize
+    }
+}
@@ -0,0 +1,539 @@
+//! A map for small `Copy` values stored right in the bucket array.
+//!
+//! A `HashMap` lookup reads the bucket, then the boxed entry holding the key
+//! and the value. `InlineHashMap` keeps the key and the value side by side in
+//! the array, resolving collisions by linear probing like `AtomicHashSet`, so
+//! a lookup without collision touches a single cache line and inserts never
+//! allocate. Values must fit in 32 bits, the value word has a presence bit
+//! above them, and lookups hand out copies instead of references.
+//!
+//! Like the set, it holds at most `N` keys besides 0 and probe runs get
+//! longer as it fills up. The `ProbeStrategy` is linear unless picked on
+//! construction.
+//!
+//! A slot is never emptied, that would cut the probe runs going through it.
+//! Removing a key leaves a tombstone: the key stays in the slot, so lookups
+//! probe past it, and only an insert of that same key revives it. Letting
+//! any key take a tombstone would race with an insert of the same key
+//! further along the run. Removed keys keep their slot, so removal makes no
+//! room for new keys.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::{hint, marker::PhantomData};
+use alloc::boxed::Box;
+
+use crate::{alloc_zeroed_array, FibonacciHasher, IndexHasher, ProbeStrategy};
+
+/// A value that round-trips through 32 bits
+pub trait InlineValue: Copy {
+    fn into_bits(self) -> u32;
+    fn from_bits(bits: u32) -> Self;
+}
+
+macro_rules! inline_int {
+    ($($t:ty => $u:ty),*) => {
+        $(impl InlineValue for $t {
+            #[inline]
+            fn into_bits(self) -> u32 { self as $u as u32 }
+            #[inline]
+            fn from_bits(bits: u32) -> Self { bits as $u as $t }
+        })*
+    };
+}
+
+inline_int!(u8 => u8, u16 => u16, u32 => u32, i8 => u8, i16 => u16, i32 => u32);
+
+impl InlineValue for f32 {
+    #[inline]
+    fn into_bits(self) -> u32 { self.to_bits() }
+    #[inline]
+    fn from_bits(bits: u32) -> Self { f32::from_bits(bits) }
+}
+
+impl InlineValue for bool {
+    #[inline]
+    fn into_bits(self) -> u32 { self as u32 }
+    #[inline]
+    fn from_bits(bits: u32) -> Self { bits != 0 }
+}
+
+impl InlineValue for char {
+    #[inline]
+    fn into_bits(self) -> u32 { self as u32 }
+    #[inline]
+    fn from_bits(bits: u32) -> Self {
+        char::from_u32(bits).expect("only valid chars are stored")
+    }
+}
+
+/// A key nobody took yet
+const EMPTY: usize = 0;
+
+/// Set in a value word once the value is written
+const PRESENT: u64 = 1 << 32;
+
+/// The value word of a removed key
+const TOMBSTONE: u64 = 1 << 33;
+
+#[derive(Default)]
+struct Slot {
+    key         : AtomicUsize,
+    /// `PRESENT | bits` once the key's value is in, 0 before, `TOMBSTONE`
+    /// once removed
+    val         : AtomicU64,
+}
+
+#[inline]
+fn word_of<V: InlineValue>(val: V) -> u64 {
+    PRESENT | val.into_bits() as u64
+}
+
+impl Slot {
+
+    /// The value word of a taken slot. The key gets published first, so wait
+    /// for the inserting thread to write the value.
+    fn word(&self) -> u64 {
+        loop {
+            let word = self.val.load(Ordering::Acquire);
+            if word != 0 {
+                return word;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// The value of a taken slot, `None` for a tombstone
+    fn value<V: InlineValue>(&self) -> Option<V> {
+        let word = self.word();
+        (word & PRESENT != 0).then(|| V::from_bits(word as u32))
+    }
+
+    fn set_value<V: InlineValue>(&self, val: V) {
+        self.val.store(word_of(val), Ordering::Release);
+    }
+
+    /// Put `val` in unless a value is there, returning whether ours got in
+    /// along with the value now there. Takes tombstones, and a word never
+    /// written if `unused_is_empty`, as it is for the key 0 which has no
+    /// key to publish first.
+    fn fill<V: InlineValue>(&self, unused_is_empty: bool, val: V) -> (bool, V) {
+        let mut word = self.val.load(Ordering::Acquire);
+        loop {
+            if word & PRESENT != 0 {
+                return (false, V::from_bits(word as u32));
+            }
+            if word != TOMBSTONE && !(word == 0 && unused_is_empty) {
+                // Still being written by its inserter
+                hint::spin_loop();
+                word = self.val.load(Ordering::Acquire);
+                continue;
+            }
+
+            match self.val.compare_exchange(word, word_of(val),
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => return (true, val),
+                Err(actual) => word = actual,
+            }
+        }
+    }
+
+    /// Turn the value into a tombstone, returning it
+    fn take<V: InlineValue>(&self) -> Option<V> {
+        let mut word = self.val.load(Ordering::Acquire);
+        while word & PRESENT != 0 {
+            match self.val.compare_exchange(word, TOMBSTONE,
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => return Some(V::from_bits(word as u32)),
+                Err(actual) => word = actual,
+            }
+        }
+        None
+    }
+}
+
+pub struct InlineHashMap<V, const N: usize, H = FibonacciHasher> {
+
+    /// Number of keys in the map
+    entries         : AtomicUsize,
+
+    /// Value of the key 0, which can't be stored in a slot
+    zero            : Slot,
+
+    /// Keys and values, `EMPTY` keys for free slots
+    slots           : Box<[Slot; N]>,
+
+    /// Picks the first slot probed for a key
+    hasher          : H,
+
+    /// Picks the slots probed after it
+    probe           : ProbeStrategy,
+
+    _marker         : PhantomData<V>,
+}
+
+impl<V: InlineValue, const N: usize> InlineHashMap<V, N> {
+
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::with_hasher(FibonacciHasher)
+    }
+}
+
+impl<V: InlineValue, const N: usize, H: IndexHasher> InlineHashMap<V, N, H> {
+
+    /// Evaluated on construction, a map without slots fails to compile
+    const NON_EMPTY: () = assert!(N > 0, "InlineHashMap needs at least one slot");
+
+    /// Build a map probing from the slot picked by `hasher`
+    pub fn with_hasher(hasher: H) -> Self {
+        let () = Self::NON_EMPTY;
+
+        InlineHashMap {
+            entries:       AtomicUsize::new(0),
+            zero:          Slot::default(),
+            // All zeroes are empty slots
+            slots:         unsafe { alloc_zeroed_array() },
+            hasher,
+            probe:         ProbeStrategy::Linear,
+            _marker:       PhantomData,
+        }
+    }
+
+    /// Probe with `strategy` instead of linearly
+    ///
+    /// # Panics
+    ///
+    /// If `strategy` can't reach every slot, only linear probing works with
+    /// a non power of two `N`.
+    pub fn with_probe(mut self, strategy: ProbeStrategy) -> Self {
+        assert!(strategy.covers(N), "{strategy:?} probing needs a power of two size");
+        self.probe = strategy;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of slots, the most keys the map can hold besides 0
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Slots probed for `key`, starting from its hashed one
+    #[inline]
+    fn probe(&self, key: usize) -> impl Iterator<Item = &Slot> + '_ {
+        let start = self.hasher.index(key, N);
+        self.probe.sequence(key, start, N).map(|idx| &self.slots[idx])
+    }
+
+    /// Longest probe run a lookup of a key in the map takes, in slots
+    pub fn max_probe(&self) -> usize {
+        self.slots.iter()
+            .map(|slot| slot.key.load(Ordering::Acquire))
+            .filter(|&key| key != EMPTY)
+            .map(|key| 1 + self.probe(key).take_while(|slot| slot.key.load(Ordering::Acquire) != key).count())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Slot taken by `key`, if it ever was, tombstones included
+    #[inline]
+    fn find(&self, key: usize) -> Option<&Slot> {
+        self.find_counting(key, &mut 0)
+    }
+
+    /// `find`, counting the slots looked at in `steps`
+    fn find_counting(&self, key: usize, steps: &mut usize) -> Option<&Slot> {
+        if key == EMPTY {
+            return Some(&self.zero);
+        }
+
+        for slot in self.probe(key) {
+            *steps += 1;
+            match slot.key.load(Ordering::Acquire) {
+                // Inserts fill the first empty slot of the run
+                EMPTY => return None,
+                cur if cur == key => return Some(slot),
+                // Tombstones of other keys included, keep probing
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Copy of the value of `key`
+    pub fn lookup(&self, key: usize) -> Option<V> {
+        if key == EMPTY {
+            // Never waits, 0 is the never used word there
+            let word = self.zero.val.load(Ordering::Acquire);
+            return (word & PRESENT != 0).then(|| V::from_bits(word as u32));
+        }
+        self.find(key).and_then(Slot::value)
+    }
+
+    /// `lookup` also returning the number of slots probed, 1 for a key in
+    /// its home slot. A missing key probes up to the first empty slot.
+    pub fn lookup_profiled(&self, key: usize) -> (Option<V>, usize) {
+        if key == EMPTY {
+            return (self.lookup(key), 1);
+        }
+
+        let mut steps = 0;
+        let val = self.find_counting(key, &mut steps).and_then(Slot::value);
+        (val, steps)
+    }
+
+    pub fn contains_key(&self, key: usize) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    /// Insert `value` unless the key is there, returning whether ours got in
+    /// along with the value now in the map, like `HashMap::try_insert`.
+    ///
+    /// # Panics
+    ///
+    /// If the key is not there and every slot is taken.
+    pub fn try_insert(&self, key: usize, value: V) -> (bool, V) {
+        if key == EMPTY {
+            let res = self.zero.fill(true, value);
+            if res.0 {
+                self.entries.fetch_add(1, Ordering::AcqRel);
+            }
+            return res;
+        }
+
+        for slot in self.probe(key) {
+            let mut cur = slot.key.load(Ordering::Acquire);
+
+            if cur == EMPTY {
+                match slot.key.compare_exchange(EMPTY, key,
+                    Ordering::AcqRel,
+                    Ordering::Acquire) {
+                    Ok(_) => {
+                        slot.set_value(value);
+                        self.entries.fetch_add(1, Ordering::AcqRel);
+                        return (true, value);
+                    }
+                    // Someone took it first, maybe with our key
+                    Err(actual) => cur = actual,
+                }
+            }
+
+            // Slots are never emptied, so the key can't be further along.
+            // A tombstone of ours is revived.
+            if cur == key {
+                let res = slot.fill(false, value);
+                if res.0 {
+                    self.entries.fetch_add(1, Ordering::AcqRel);
+                }
+                return res;
+            }
+        }
+
+        // The probe sequence ends after visiting each slot once, so this is only
+        // reached with every slot taken by another key
+        panic!("InlineHashMap is full");
+    }
+
+    /// Remove `key`, returning its value. Its slot stays taken by a
+    /// tombstone, see the module docs.
+    pub fn remove(&self, key: usize) -> Option<V> {
+        let val = self.find(key)?.take();
+        if val.is_some() {
+            self.entries.fetch_sub(1, Ordering::AcqRel);
+        }
+        val
+    }
+
+    /// Keys and copies of their values, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, V)> + '_ {
+        let zero = self.lookup(EMPTY).map(|val| (EMPTY, val));
+
+        zero.into_iter().chain(self.slots.iter().filter_map(|slot| {
+            match slot.key.load(Ordering::Acquire) {
+                EMPTY => None,
+                key => slot.value().map(|val| (key, val)),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::IdentityHasher;
+
+    #[test]
+    fn test_inline_u32() {
+        let map = InlineHashMap::<u32, 2048>::new();
+        assert!(map.is_empty());
+
+        for key in 0..1000 {
+            assert_eq!(map.try_insert(key * 3, key as u32 + 7), (true, key as u32 + 7));
+        }
+        // First wins
+        for key in 0..1000 {
+            assert_eq!(map.try_insert(key * 3, 0), (false, key as u32 + 7));
+        }
+        // The full range round-trips
+        assert!(map.try_insert(1, u32::MAX).0);
+
+        assert_eq!(map.len(), 1001);
+        assert_eq!(map.lookup(0), Some(7));
+        assert_eq!(map.lookup(1), Some(u32::MAX));
+        assert_eq!(map.lookup(2), None);
+        for key in 0..1000 {
+            assert_eq!(map.lookup(key * 3), Some(key as u32 + 7));
+        }
+
+        let mut pairs: Vec<_> = map.iter().collect();
+        pairs.sort();
+        assert_eq!(pairs.len(), 1001);
+        assert_eq!(pairs[1], (1, u32::MAX));
+    }
+
+    #[test]
+    fn test_inline_values() {
+        let map = InlineHashMap::<i16, 8>::new();
+        map.try_insert(3, -2);
+        assert_eq!(map.lookup(3), Some(-2));
+
+        let map = InlineHashMap::<char, 8>::new();
+        map.try_insert(0, 'é');
+        assert_eq!(map.lookup(0), Some('é'));
+
+        let map = InlineHashMap::<f32, 8>::new();
+        map.try_insert(5, -1.5);
+        assert_eq!(map.lookup(5), Some(-1.5));
+    }
+
+    #[test]
+    fn test_inline_probe_strategies() {
+        for strategy in [ProbeStrategy::Linear, ProbeStrategy::Quadratic, ProbeStrategy::DoubleHash] {
+            let map = InlineHashMap::<u32, 64, IdentityHasher>::with_hasher(IdentityHasher).with_probe(strategy);
+
+            // Every slot taken by keys hashed to the same one
+            for key in 0..64 {
+                assert!(map.try_insert(key * 64 + 5, key as u32).0);
+            }
+            for key in 0..64 {
+                assert_eq!(map.lookup(key * 64 + 5), Some(key as u32));
+            }
+            assert_eq!(map.lookup(5 + 64 * 64), None);
+            assert!(map.max_probe() <= 64);
+        }
+    }
+
+    #[test]
+    fn test_inline_lookup_profiled() {
+        let map = InlineHashMap::<u32, 16, IdentityHasher>::with_hasher(IdentityHasher);
+        for key in [5, 21, 37] {
+            assert!(map.try_insert(key, key as u32).0);
+        }
+        assert!(map.try_insert(0, 1).0);
+
+        // Home slot, then the run of keys hashed to slot 5
+        assert_eq!(map.lookup_profiled(5), (Some(5), 1));
+        assert_eq!(map.lookup_profiled(37), (Some(37), 3));
+        assert_eq!(map.lookup_profiled(53), (None, 4));
+        assert_eq!(map.lookup_profiled(0), (Some(1), 1));
+    }
+
+    #[test]
+    fn test_inline_remove() {
+        let map = InlineHashMap::<u32, 8, IdentityHasher>::with_hasher(IdentityHasher);
+
+        // Colliding on slot 1, B probes past A
+        let (a, b) = (1, 9);
+        assert!(map.try_insert(a, 10).0);
+        assert!(map.try_insert(b, 90).0);
+
+        assert_eq!(map.remove(a), Some(10));
+        assert_eq!(map.remove(a), None);
+        assert_eq!(map.len(), 1);
+        // A tombstone doesn't end the run
+        assert_eq!(map.lookup(a), None);
+        assert_eq!(map.lookup(b), Some(90));
+        assert!(!map.contains_key(a));
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(b, 90)]);
+
+        // Nor does it let B in twice, and A gets its slot back
+        assert_eq!(map.try_insert(b, 91), (false, 90));
+        assert_eq!(map.try_insert(a, 11), (true, 11));
+        assert_eq!(map.try_insert(a, 12), (false, 11));
+        assert_eq!(map.max_probe(), 2);
+        assert_eq!(map.len(), 2);
+
+        // The key 0 goes through the same states
+        assert_eq!(map.remove(0), None);
+        assert!(map.try_insert(0, 5).0);
+        assert_eq!(map.remove(0), Some(5));
+        assert_eq!(map.lookup(0), None);
+        assert_eq!(map.try_insert(0, 6), (true, 6));
+        assert_eq!(map.len(), 3);
+    }
+
+    /// Threads inserting and removing the same keys
+    #[test]
+    fn test_inline_remove_threads() {
+        let map = Arc::new(InlineHashMap::<u32, 64>::new());
+
+        let handles: Vec<_> = (0..4u32).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                let mut balance = 0i64;
+                for i in 0..2000 {
+                    let key = (i * 7 + x as usize) % 32;
+                    if i % 2 == 0 {
+                        balance += map_tx.try_insert(key, x).0 as i64;
+                    } else {
+                        balance -= map_tx.remove(key).is_some() as i64;
+                    }
+                }
+                balance
+            })
+        }).collect();
+
+        let balance: i64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+        // Every key kept its slot, and the count adds up
+        assert_eq!(map.len() as i64, balance);
+        assert_eq!(map.iter().count(), map.len());
+        assert!(map.max_probe() <= 32);
+    }
+
+    #[test]
+    fn test_inline_threads() {
+        let map = Arc::new(InlineHashMap::<u32, 1024>::new());
+
+        let handles: Vec<_> = (0..8u32).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                (0..512).map(|key| map_tx.try_insert(key * 2, x)).collect::<Vec<_>>()
+            })
+        }).collect();
+
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        // One winner per key, and everybody agrees on its value
+        for key in 0..512 {
+            let won = results.iter().filter(|r| r[key].0).count();
+            assert_eq!(won, 1);
+            assert!(results.iter().all(|r| r[key].1 == results[0][key].1));
+            assert_eq!(map.lookup(key * 2), Some(results[0][key].1));
+        }
+        assert_eq!(map.len(), 512);
+    }
+}
@@ -0,0 +1,249 @@
+//! A map for arbitrary `Hash + Eq` keys on top of the `usize` one.
+//!
+//! The key is hashed into a `usize` which indexes the inner `HashMap`, whose
+//! value is the first node holding the full key. Distinct keys sharing the
+//! same hash are chained behind it and told apart by comparing the full key.
+//!
+//! Compared to the `usize` map every entry also stores the original `K`
+//! and a `next` pointer for hash collisions, and a key that collides on its
+//! full hash costs an extra allocation. Use `HashMap` when keys are `usize`.
+
+use core::{borrow::Borrow, hash::{BuildHasher, Hash}, ptr};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::collections::hash_map::RandomState;
+use alloc::boxed::Box;
+
+use crate::{HashMap, HashMapErr};
+
+/// One key of the map, chained with the keys sharing its hash
+struct KeyNode<K, V> {
+    key         : K,
+    val         : V,
+    next        : AtomicPtr<KeyNode<K, V>>
+}
+
+impl<K, V> KeyNode<K, V> {
+    fn new(key: K, val: V) -> Self {
+        KeyNode { key, val, next: AtomicPtr::new(ptr::null_mut()) }
+    }
+}
+
+impl<K, V> Drop for KeyNode<K, V> {
+    fn drop(&mut self) {
+        // Free the chain iteratively, recursing could blow the stack
+        let mut ptr = core::mem::replace(self.next.get_mut(), ptr::null_mut());
+        while !ptr.is_null() {
+            let mut boxed_ptr = unsafe { Box::from_raw(ptr) };
+            ptr = core::mem::replace(boxed_ptr.next.get_mut(), ptr::null_mut());
+            drop(boxed_ptr);
+        }
+    }
+}
+
+pub struct KeyedHashMap<K, V, const N: usize, S = RandomState> {
+
+    /// Number of keys in the map
+    entries         : AtomicUsize,
+
+    /// First node of each hash
+    map             : HashMap<KeyNode<K, V>, N>,
+
+    hash_builder    : S,
+}
+
+impl<K: Hash + Eq, V, const N: usize> KeyedHashMap<K, V, N> {
+
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, const N: usize, S: BuildHasher> KeyedHashMap<K, V, N, S> {
+
+    pub fn with_hasher(hash_builder: S) -> Self {
+        KeyedHashMap {
+            entries:       AtomicUsize::new(0),
+            map:           HashMap::new(),
+            hash_builder,
+        }
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries.load(Ordering::Acquire)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    fn hash<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        self.hash_builder.hash_one(key) as usize
+    }
+
+    fn find<Q>(&self, key: &Q) -> Option<&KeyNode<K, V>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+
+        let mut node = self.map.lookup(self.hash(key))?;
+
+        loop {
+            if node.key.borrow() == key {
+                return Some(node);
+            }
+
+            let next_ptr = node.next.load(Ordering::Acquire);
+            if next_ptr.is_null() {
+                return None;
+            }
+            node = unsafe { &*next_ptr };
+        }
+    }
+
+    pub fn lookup<Q>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.find(key).map(|node| &node.val)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.find(key).is_some()
+    }
+
+    /// Insert a entry into the table
+    pub fn insert(&self, key: K, value: V) -> Result<&V, HashMapErr<'_, V>> {
+
+        let hash = self.hash(&key);
+
+        let (head, node) = match self.map.insert_or_return(hash, KeyNode::new(key, value)) {
+            Ok(node) => {
+                self.entries.fetch_add(1, Ordering::AcqRel);
+                return Ok(&node.val);
+            }
+            Err(existent) => existent,
+        };
+
+        if head.key == node.key {
+            return Err(HashMapErr::ExistentEntry(&head.val));
+        }
+
+        // Different keys with the same hash, walk the keys chain
+        let new_node_ptr = Box::into_raw(Box::new(node));
+        let new_node = unsafe { &*new_node_ptr };
+
+        let mut cur_node = head;
+        loop {
+            let mut next_ptr = cur_node.next.load(Ordering::Acquire);
+
+            if next_ptr.is_null() {
+                match cur_node.next.compare_exchange(ptr::null_mut(), new_node_ptr,
+                    Ordering::AcqRel,
+                    Ordering::Acquire) {
+
+                    Ok(_) => {
+                        self.entries.fetch_add(1, Ordering::AcqRel);
+                        return Ok(&new_node.val);
+                    }
+
+                    // Somebody appended first, check what it was
+                    Err(actual) => next_ptr = actual,
+                }
+            }
+
+            cur_node = unsafe { &*next_ptr };
+            if cur_node.key == new_node.key {
+                drop(unsafe { Box::from_raw(new_node_ptr) });
+                return Err(HashMapErr::ExistentEntry(&cur_node.val));
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use std::sync::Arc;
+    use core::hash::{BuildHasherDefault, Hasher};
+
+    use super::*;
+
+    /// Every key hashes to the same value
+    #[derive(Default)]
+    struct ConstHasher;
+
+    impl Hasher for ConstHasher {
+        fn finish(&self) -> u64 { 42 }
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    #[test]
+    fn test_keyed_1() {
+        let map = KeyedHashMap::<String, u64, 64>::new();
+
+        let _ = map.insert("first".into(), 1);
+        let _ = map.insert("second".into(), 2);
+
+        assert_eq!(*map.lookup("first").unwrap(), 1);
+        assert_eq!(*map.lookup("second").unwrap(), 2);
+        assert!(map.lookup("third").is_none());
+        assert!(map.contains_key("second"));
+
+        match map.insert("first".into(), 3) {
+            Err(HashMapErr::ExistentEntry(v)) => assert_eq!(*v, 1),
+            _ => panic!()
+        }
+
+        assert_eq!(map.entries(), 2);
+
+        let map = KeyedHashMap::<[u8; 16], u64, 64>::new();
+        let _ = map.insert([7u8; 16], 7);
+        assert_eq!(*map.lookup(&[7u8; 16]).unwrap(), 7);
+    }
+
+    /// Keys colliding on the full hash are told apart by the key
+    #[test]
+    fn test_keyed_hash_collisions() {
+        let map = KeyedHashMap::<String, u64, 8, BuildHasherDefault<ConstHasher>>::with_hasher(
+            BuildHasherDefault::default());
+
+        for i in 0..10 {
+            assert!(map.insert(format!("key {}", i), i).is_ok());
+        }
+        assert!(map.insert("key 5".into(), 55).is_err());
+
+        for i in 0..10 {
+            assert_eq!(*map.lookup(&format!("key {}", i)).unwrap(), i);
+        }
+
+        assert_eq!(map.entries(), 10);
+    }
+
+    #[test]
+    fn test_keyed_threads() {
+        let map = Arc::new(
+            KeyedHashMap::<String, u64, 8, BuildHasherDefault<ConstHasher>>::with_hasher(
+                BuildHasherDefault::default()));
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for i in 0..64 {
+                    let _ = map_tx.insert(format!("key {}", i), i);
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            let _ = h.join();
+        }
+
+        assert_eq!(map.entries(), 64);
+        for i in 0..64 {
+            assert_eq!(*map.lookup(&format!("key {}", i)).unwrap(), i);
+        }
+    }
+}
@@ -1,654 +1,5058 @@
-//#![no_std]
+#![cfg_attr(not(any(feature = "std", test, loom)), no_std)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 
-/// A Concurrent HashMap with the following constraints:
-/// - Only usize keys
-/// - Only Insertions (No updates or deletes)
+//! A Concurrent HashMap with the following constraints:
+//! - Only usize keys
+//! - Insertions and logical removals (no in-place updates)
+//!
+//! Without the default `std` feature the crate only needs `alloc`, which
+//! leaves out `KeyedHashMap` and the debug printing.
 
 
 extern crate alloc;
-use core::{sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
-use std::{alloc::{Layout, alloc_zeroed}};
-use alloc::{boxed::Box};
+use core::{cell::UnsafeCell, fmt, iter::FusedIterator, mem::ManuallyDrop, ptr::{self, NonNull}};
+use alloc::{alloc::{Layout, alloc_zeroed, handle_alloc_error}};
+use alloc::{boxed::Box, vec::Vec};
+
+mod sync;
+use sync::{spin_loop, AtomicPtr, AtomicUsize, Ordering, UnsyncAccess};
+
+mod allocator;
+pub use allocator::{AllocError, Allocator, Global};
+use allocator::{alloc_in, dealloc_in, free_in, try_alloc_zeroed_in};
+
+mod chain_index;
+use chain_index::ChainIndex;
+
+mod freelist;
+use freelist::FreeList;
+
+mod counter;
+use counter::Counter;
+
+mod pool;
+use pool::EntryPool;
+
+#[cfg(feature = "deterministic")]
+pub mod deterministic;
+
+/// Where a thread may lose a race, see `deterministic`
+#[inline(always)]
+fn seam() {
+    #[cfg(feature = "deterministic")]
+    deterministic::seam();
+}
+
+mod hasher;
+pub use hasher::{IndexHasher, IdentityHasher, FibonacciHasher, SeededHasher};
+
+#[cfg(feature = "std")]
+mod keyed;
+#[cfg(feature = "std")]
+pub use keyed::KeyedHashMap;
+
+mod epoch;
+pub use epoch::{EpochHashMap, Guard};
+
+mod growable;
+pub use growable::GrowableHashMap;
+
+mod set;
+pub use set::AtomicHashSet;
+
+mod inline;
+pub use inline::{InlineHashMap, InlineValue};
+
+mod probe;
+pub use probe::ProbeStrategy;
+
+mod local;
+pub use local::LocalHashMap;
+
+mod cache;
+pub use cache::CacheHashMap;
+
+mod static_map;
+pub use static_map::StaticHashMap;
+
+#[cfg(feature = "serde")]
+mod serde_impl;
+
+#[cfg(feature = "rayon")]
+mod rayon_impl;
+
+#[cfg(all(test, not(loom)))]
+mod test_util;
 
 extern crate xorshift;
+#[cfg(all(test, not(loom)))]
 use xorshift::Rng;
 
 
-#[derive(Debug)]
-pub struct Entry<V> {
-    key         : usize,
-    val         : V,
-    next        : AtomicPtr<Entry<V>>
+/// Heap array of `N` zeroed `T`, for bucket arrays. The caller must make
+/// sure all zeroes is a valid `T`, like a null `AtomicPtr`.
+unsafe fn alloc_zeroed_array<T, const N: usize>() -> Box<[T; N]> {
+    let layout = Layout::array::<T>(N)
+        .expect("unable to allocate memory for buckets");
+
+    // Straight to the heap, building the array first would blow the
+    // stack for large N
+    let raw_array = alloc_zeroed(layout) as *mut [T; N];
+
+    if raw_array.is_null() {
+        handle_alloc_error(layout);
+    }
+
+    Box::from_raw(raw_array)
 }
 
-pub enum HashMapErr<'a, V> {
-    HashMapFull,
-    ExistentEntry(&'a V)
+/// Hint the cache line at `ptr` into L1 ahead of a load, for `lookup_many`
+/// and the chain walk of lookups. Only with the `prefetch` feature, and a no-op on targets without a prefetch instruction
+/// we know of. Never faults, any address is fine.
+#[inline(always)]
+#[allow(unused_variables)]
+fn prefetch<T>(ptr: *const T) {
+    #[cfg(all(feature = "prefetch", target_arch = "x86_64"))]
+    unsafe {
+        use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch::<_MM_HINT_T0>(ptr.cast());
+    }
+
+    #[cfg(all(feature = "prefetch", target_arch = "aarch64"))]
+    unsafe {
+        core::arch::asm!("prfm pldl1keep, [{0}]", in(reg) ptr, options(nostack, readonly, preserves_flags));
+    }
 }
 
-pub type Bucket<V> = AtomicPtr<Entry<V>>;
+/// The low bits of `Entry::next` hold the state of the entry owning the
+/// pointer. Entries are at least `usize` aligned, so these bits are free.
+const STATE_MASK:      usize = 0b11;
 
-#[derive(Debug)]
-pub struct HashMap<V, const N: usize> {
+/// The entry holds a value visible to readers
+const STATE_LIVE:      usize = 0b00;
 
-    /// Number of entries in the Table
-    entries         : AtomicUsize,
+/// The entry was removed, its value slot is vacant and can be reused
+const STATE_TOMBSTONE: usize = 0b01;
 
-    /// Number of collisions
-    collisions      : AtomicUsize,
+/// A writer owns the value slot (removing or reusing the entry)
+const STATE_BUSY:      usize = 0b10;
 
-    /// The buckets in the table.
-    buckets         : Box<[Bucket<V>; N]>,
+/// Removed through `EpochHashMap`. The value stays until no pinned reader can
+/// see it and the entry is never revived, a newer entry for the key may follow.
+const STATE_RETIRED:   usize = 0b11;
+
+/// The only way to read a `next` pointer: a tail entry that got removed holds
+/// a tagged null, so null checks must always come after stripping the state.
+#[inline]
+fn untag<V>(ptr: *mut Entry<V>) -> *mut Entry<V> {
+    ptr.map_addr(|addr| addr & !STATE_MASK)
 }
 
-impl<V, const N: usize> Drop for HashMap<V, N> {
-    fn drop(&mut self) {
-        for idx in 0..N {
-            // Get the entry
-            let mut ptr = self.buckets[idx].load(Ordering::SeqCst);
+#[inline]
+fn state_of<V>(ptr: *mut Entry<V>) -> usize {
+    ptr.addr() & STATE_MASK
+}
 
-            // Remove all the chained list of items for that bucket
-            while !ptr.is_null() {
-                // Take ownership of the value to drop it
-                let boxed_ptr = unsafe { Box::from_raw(ptr) };
-                // Get the next item in the list
-                ptr = boxed_ptr.next.load(Ordering::SeqCst);
-                // drop the current
-                drop(boxed_ptr);
-            }
-        }
-    }
+/// A key, its value and the link to the next entry of the chain.
+///
+/// The layout is fixed: a chain walk reads `key` and `next` of every entry
+/// but `val` only of the one it stops at, so those two come first and share
+/// the first 16 bytes whatever the size of `V`. A walk past a big value
+/// touches one cache line per entry instead of two. The insertion order
+/// `seq` is only read by `iter_ordered`, it goes between them and the value.
+/// With a `u64` value the entry is 32 bytes, two to a cache line.
+///
+/// Packing key and value into a word for small `V` isn't worth it: the
+/// entry is rounded up to the pointer alignment anyway, and the free low
+/// bits of `next` already hold the state.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Entry<V> {
+    key         : usize,
+    next        : AtomicPtr<Entry<V>>,
+    /// Stamped from the map counter whenever the entry gets a value, a
+    /// revived tombstone included
+    seq         : AtomicUsize,
+    val         : UnsafeCell<ManuallyDrop<V>>,
 }
 
-impl<V, const N: usize> HashMap<V, N> {
+impl<V> Entry<V> {
 
-    pub fn entries(&self) -> usize {
-        self.entries.load(Ordering::Relaxed)
+    fn new(key: usize, val: V) -> Self {
+        Entry {
+            key,
+            next: AtomicPtr::new(ptr::null_mut()),
+            seq:  AtomicUsize::new(0),
+            val:  UnsafeCell::new(ManuallyDrop::new(val)),
+        }
     }
 
-    pub fn collisions(&self) -> usize {
-        self.collisions.load(Ordering::Relaxed)
+    /// Next entry in the chain, with the state bits stripped
+    #[inline]
+    fn next_ptr(&self) -> *mut Entry<V> {
+        untag(self.next.load(Ordering::Acquire))
     }
 
-    pub fn new() -> Self {
-        let layout = Layout::array::<Bucket<V>>(N)
-            .expect("unable to allocate memory for buckets");
-
-        let raw_buckets = unsafe { alloc_zeroed(layout) }
-             as *mut [AtomicPtr<Entry<V>>; N];
-
-        HashMap {
-            //permutation:   permutation_table.into_boxed_slice().try_into().unwrap(),   
-            entries:       AtomicUsize::new(0),        
-            collisions:    AtomicUsize::new(0),
-            buckets:       unsafe { Box::from_raw(raw_buckets) }
-        }       
+    #[inline]
+    fn state(&self) -> usize {
+        state_of(self.next.load(Ordering::Acquire))
     }
 
-    /// Returns a position inside the table 
-    /// based on the permutation table and the key
+    /// Reference to the value. The caller must have observed the entry
+    /// as `STATE_LIVE` or own it through `STATE_BUSY`, or be pinned since
+    /// before it got `STATE_RETIRED`.
     #[inline]
-    fn get_idx(&self, key: usize) -> usize {     
-        key & (N - 1)
+    unsafe fn value(&self) -> &V {
+        &*self.val.get()
     }
 
-    // debug method
-    fn print_map(&self) {
-        for idx in 0..N {
-            let bucket = &self.buckets[idx];
-            let entry_ptr = bucket.load(Ordering::Acquire);
-            if entry_ptr.is_null() {    
-                println!("Idx:[{:x}: NULL", idx);
-                continue;
+    /// CAS the state bits from `from` to `to`, preserving the next pointer
+    /// which may be concurrently appended to.
+    fn transition(&self, from: usize, to: usize) -> bool {
+        let mut cur = self.next.load(Ordering::Acquire);
+        loop {
+            if state_of(cur) != from {
+                return false;
             }
 
-            let cur_key = unsafe { (*entry_ptr).key  };
-            println!("Idx:[{:x}: {:x} -> {}", 
-                idx, entry_ptr as usize, cur_key );
+            let new = cur.map_addr(|addr| (addr & !STATE_MASK) | to);
+            match self.next.compare_exchange_weak(cur, new,
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => return true,
+                Err(actual) => cur = actual,
+            }
         }
     }
 
-    pub fn lookup(&self, key: usize) -> Option<&V> {
+    /// Append `new_entry_ptr` if this entry is the tail of the chain.
+    /// Otherwise returns the next entry to keep walking.
+    ///
+    /// The CAS needs no generation tag against ABA. A `next` pointer goes
+    /// from null to an entry once and never back, and chained entries are
+    /// only freed or recycled under `&mut` of the map, or by `EpochHashMap`
+    /// once no pinned thread can reach them, so no thread can hold a stale
+    /// pointer to an address that got reused. Only the state bits
+    /// can come back to an old value (a removal undone by an insert), and
+    /// the CAS keeps them as they are anyway.
+    fn try_append(&self, new_entry_ptr: *mut Entry<V>) -> Result<(), *mut Entry<V>> {
+        let mut cur = self.next.load(Ordering::Acquire);
+        loop {
+            let next_ptr = untag(cur);
+            if !next_ptr.is_null() {
+                return Err(next_ptr);
+            }
 
-        let idx     = self.get_idx(key);
+            let new = new_entry_ptr.map_addr(|addr| addr | state_of(cur));
+            match self.next.compare_exchange(cur, new,
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => return Ok(()),
+                // Either the state changed or someone appended first
+                Err(actual) => cur = actual,
+            }
+        }
+    }
 
-        let bucket  = &self.buckets[idx];
+    /// Free a staged entry that never got published, taking its value back
+    unsafe fn into_value<A: Allocator>(entry_ptr: *mut Entry<V>, alloc: &A, pool: Option<&EntryPool<V>>) -> V {
+        let val = ManuallyDrop::into_inner(ptr::read((*entry_ptr).val.get()));
+        // Nothing else to drop in the entry
+        match pool {
+            Some(pool) if pool.contains(entry_ptr) => pool.unclaim(entry_ptr),
+            _ => dealloc_in(alloc, entry_ptr),
+        }
+        val
+    }
 
-        let mut entry_ptr = bucket.load(Ordering::Acquire);
-        
-        if entry_ptr.is_null() {    
-            return None;
-        } 
+    /// Drop an unlinked entry and give it back to the pool slot it came
+    /// from, or to `alloc`
+    unsafe fn free<A: Allocator>(entry_ptr: *mut Entry<V>, alloc: &A, pool: Option<&EntryPool<V>>) {
+        match pool {
+            Some(pool) if pool.contains(entry_ptr) => {
+                ptr::drop_in_place(entry_ptr);
+                pool.unclaim(entry_ptr);
+            }
+            _ => free_in(alloc, entry_ptr),
+        }
+    }
+}
 
-        let mut cur_entry = unsafe { &*entry_ptr };
+impl<V> Drop for Entry<V> {
+    fn drop(&mut self) {
+        // Tombstones have already given their value away
+        if state_of(self.next.read_mut()) == STATE_LIVE {
+            unsafe { ManuallyDrop::drop(self.val.get_mut()) };
+        }
+    }
+}
 
-        if key == cur_entry.key {
-            return Some( &cur_entry.val );
-        } else {
-            // Collided keys,, walk the LL
-            
-            // A pointer to Null
-            let empty:    *mut Entry<V> =  0 as *mut Entry<V>;
+/// A value waiting to be inserted. The closure runs at most once and the
+/// entry is only boxed when we are about to CAS it in.
+///
+/// Freeing an unpublished entry on drop also covers unwinding: if the closure
+/// or the drop of a losing value panics, the entry goes with the insert frame.
+///
+/// The entry comes from `pool` or `alloc`, which must be the ones of the map
+/// it gets linked into.
+struct Staged<'a, V, F, A: Allocator = Global> {
+    init        : Option<F>,
+    val         : Option<V>,
+    entry_ptr   : *mut Entry<V>,
+    alloc       : &'a A,
+    pool        : Option<&'a EntryPool<V>>,
+}
 
-            loop {               
-                let mut next_entry_ptr = cur_entry.next.load(Ordering::Acquire);
+impl<'a, V, F: FnOnce() -> V, A: Allocator> Staged<'a, V, F, A> {
 
-                while next_entry_ptr != empty {
+    fn new(init: F, alloc: &'a A, pool: Option<&'a EntryPool<V>>) -> Self {
+        Staged { init: Some(init), val: None, entry_ptr: ptr::null_mut(), alloc, pool }
+    }
 
-                    entry_ptr  = next_entry_ptr;
+    fn ready(val: V, alloc: &'a A, pool: Option<&'a EntryPool<V>>) -> Self {
+        Staged { init: None, val: Some(val), entry_ptr: ptr::null_mut(), alloc, pool }
+    }
 
-                    cur_entry = unsafe { &*entry_ptr };
-                    if cur_entry.key == key {
-                        return Some( &cur_entry.val );
-                    }
+    /// Make sure the value is built
+    fn force(&mut self) {
+        if let Some(init) = self.init.take() {
+            self.val = Some(init());
+        }
+    }
 
-                    next_entry_ptr = cur_entry.next.load(Ordering::Acquire);
+    /// Pointer to the boxed entry, on the first call taking a pool slot of
+    /// bucket `idx`, else recycling one from `free` or allocating it. Every
+    /// call stamps it with the next `seq`, as it's about to be linked in.
+    fn entry_ptr(&mut self, idx: usize, key: usize, free: &FreeList<V>, seq: &AtomicUsize) -> *mut Entry<V> {
+        self.force();
+
+        if let Some(val) = self.val.take() {
+            self.entry_ptr = match self.pool.and_then(|pool| pool.claim(idx)).or_else(|| free.pop()) {
+                Some(entry_ptr) => {
+                    unsafe { ptr::write(entry_ptr, Entry::new(key, val)) };
+                    entry_ptr
                 }
+                None => alloc_in(self.alloc, Entry::new(key, val)),
+            };
+        }
+
+        debug_assert!(!self.entry_ptr.is_null(), "staged entry already published");
+        // Nobody sees the entry before the CAS publishing it
+        unsafe { (*self.entry_ptr).seq.store(seq.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed) };
+        self.entry_ptr
+    }
 
-                return None;
+    fn take_value(&mut self) -> V {
+        self.force();
+
+        match self.val.take() {
+            Some(val) => val,
+            None => {
+                let entry_ptr = core::mem::replace(&mut self.entry_ptr, ptr::null_mut());
+                unsafe { Entry::into_value(entry_ptr, self.alloc, self.pool) }
             }
         }
-        
     }
 
-    /// Insert a entry into the table
-    pub fn insert(&self, key: usize, value: V) -> Result<&V, HashMapErr<V>> {
-        
-        // A pointer to Null
-        let empty:    *mut Entry<V> =  0 as *mut Entry<V>;
+    /// The entry got linked in, the map owns it now
+    fn publish(&mut self) {
+        self.entry_ptr = ptr::null_mut();
+    }
+}
 
-        // Prepare the new entry ptr
-        let new_entry_ptr = 
-            Box::into_raw(
-                Box::new(Entry {
-                    key, val: value, next: AtomicPtr::new(core::ptr::null_mut())
-                }));  
+impl<V, F, A: Allocator> Drop for Staged<'_, V, F, A> {
+    fn drop(&mut self) {
+        // Lost to an existent entry, free what we allocated
+        if !self.entry_ptr.is_null() {
+            unsafe { Entry::free(self.entry_ptr, self.alloc, self.pool) };
+        }
+    }
+}
 
-        // Get index for the entry
-        let idx = self.get_idx(key);
+/// A slot taken in the entries counter by an insert in flight, so the cap
+/// holds under concurrency. Given back on drop unless committed, unwinding
+/// included, so a panicking insert leaves the counter as it found it.
+///
+/// A deferred reservation (batches on uncapped maps) doesn't touch the
+/// counter per insert, it adds all the committed slots at once on drop.
+struct Reservation<'a> {
+    entries     : &'a Counter,
+    max_entries : usize,
+    /// Where the held slot is counted
+    shard       : usize,
+    held        : bool,
+    deferred    : bool,
+    committed   : usize,
+}
 
-        let bucket = &self.buckets[idx];
-                
-        // We use CAS to place the entry if and only if the bucket is empty. Otherwise, we must
-        // handle the respective cases.
-        match bucket.compare_exchange(empty, new_entry_ptr, 
-            Ordering::Release,
-            Ordering::Acquire) {
+impl<'a> Reservation<'a> {
 
-            Ok(_) => {
+    fn new(entries: &'a Counter, max_entries: usize) -> Self {
+        Reservation { entries, max_entries, shard: 0, held: false, deferred: false, committed: 0 }
+    }
 
-                self.entries.fetch_add(1, Ordering::Relaxed);
-            
-                // CAS suceeded, return new inserted entry value reference;
-                return Ok( unsafe { &(*new_entry_ptr).val });
-            }
+    fn deferred(entries: &'a Counter) -> Self {
+        Reservation { entries, max_entries: usize::MAX, shard: 0, held: false, deferred: true, committed: 0 }
+    }
 
-            Err(mut cur_entry_ptr) => {
+    /// Take a slot for a key of bucket `idx`, false if the map is full
+    fn acquire(&mut self, idx: usize) -> bool {
+        if self.held {
+            return true;
+        }
 
-                // Hash Collision or Entry already existed
+        if self.deferred {
+            self.held = true;
+        } else if self.max_entries == usize::MAX {
+            // No cap, no need to look at the count. It can't wrap either,
+            // every live entry is an allocation of its own.
+            self.shard = Counter::shard(idx, false);
+            self.entries.add(self.shard, 1);
+            self.held = true;
+        } else {
+            self.shard = Counter::shard(idx, true);
+            self.held = self.entries.add_capped(self.max_entries);
+        }
 
-                let cur_entry = unsafe { &*cur_entry_ptr };
+        self.held
+    }
 
-                // Check if the key matches with ours, if so, return the existent
-                if cur_entry.key == key {
-                    drop(unsafe { Box::from_raw(new_entry_ptr) });
-                    return Err(HashMapErr::ExistentEntry(&cur_entry.val));
+    /// The entry got inserted, keep the slot
+    fn commit(&mut self) {
+        debug_assert!(self.held);
+        debug_assert!(self.entries.sum() <= self.max_entries,
+            "entries above the max_entries cap");
+        self.held = false;
+        if self.deferred {
+            self.committed += 1;
+        }
+    }
 
-                } else {
-                    // Keys were different, go test linked list                    
-                    loop {
-                        let cur_entry = unsafe { &*cur_entry_ptr };                        
-                        let mut next_entry_ptr = cur_entry.next.load(Ordering::Acquire);
-                        while next_entry_ptr != empty {
-
-                            cur_entry_ptr  = next_entry_ptr;
-
-                            let cur_entry = unsafe { &*cur_entry_ptr };
-                            if cur_entry.key == key {
-                                drop(unsafe { Box::from_raw(new_entry_ptr) });
-                                return Err(HashMapErr::ExistentEntry(&cur_entry.val));
-                            }
+    /// Give back a slot the insert didn't use
+    fn release(&mut self) {
+        if self.held {
+            self.held = false;
+            if !self.deferred {
+                self.entries.sub(self.shard, 1);
+            }
+        }
+    }
+}
 
-                            next_entry_ptr = cur_entry.next.load(Ordering::Acquire);
-                        }
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.release();
+        if self.committed > 0 {
+            self.entries.add(0, self.committed);
+        }
+    }
+}
 
-                        // Found empty slot in the linked list and keys haven't collided
-                        // up to this point
-                       
-                        if cur_entry.next.compare_exchange(empty, new_entry_ptr, 
-                            Ordering::Release,
-                            Ordering::Acquire).is_ok() {
-                
-                            self.entries.fetch_add(1, Ordering::Relaxed);
+#[derive(Debug)]
+pub enum HashMapErr<'a, V> {
+    HashMapFull,
+    ExistentEntry(&'a V)
+}
 
-                            self.collisions.fetch_add(1, Ordering::Relaxed);
-                            
-                            // CAS suceeded, return new inserted entry value reference;
-                            return Ok( unsafe { &(*new_entry_ptr).val });
-                        } 
+impl<V> fmt::Display for HashMapErr<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashMapErr::HashMapFull      => f.write_str("HashMap is full"),
+            HashMapErr::ExistentEntry(_) => f.write_str("key already in the HashMap"),
+        }
+    }
+}
 
-                        // Failed Race,, re-start the loop from the point where 
-                        // we were going to insert this
+#[cfg(feature = "std")]
+impl<V: fmt::Debug> std::error::Error for HashMapErr<'_, V> {}
 
-                    }                    
-                }
-            }
+/// `HashMapErr` without the borrow of the map, holding a clone of the
+/// existent value, so it can be boxed or returned past the map's scope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertError<V> {
+    HashMapFull,
+    ExistentEntry(V)
+}
 
-        } 
+impl<V: Clone> From<HashMapErr<'_, V>> for InsertError<V> {
+    fn from(err: HashMapErr<'_, V>) -> Self {
+        match err {
+            HashMapErr::HashMapFull        => InsertError::HashMapFull,
+            HashMapErr::ExistentEntry(val) => InsertError::ExistentEntry(val.clone()),
+        }
+    }
+}
 
+impl<V> fmt::Display for InsertError<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertError::HashMapFull      => f.write_str("HashMap is full"),
+            InsertError::ExistentEntry(_) => f.write_str("key already in the HashMap"),
+        }
     }
+}
 
+#[cfg(feature = "std")]
+impl<V: fmt::Debug> std::error::Error for InsertError<V> {}
+
+/// What an insert did, for callers that treat a duplicate as a normal outcome
+/// rather than an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub enum Insertion<'a, V> {
+    /// Our value got in
+    Inserted(&'a V),
+    /// The key was there, ours got dropped
+    Existing(&'a V),
 }
 
+impl<'a, V> Insertion<'a, V> {
 
-pub struct Iter<'a, V> {
-    buckets: &'a [Bucket<V>],
-    current_bucket: usize,
-    current_entry: Option<&'a Entry<V>>,
-}
+    pub fn is_inserted(&self) -> bool {
+        matches!(self, Insertion::Inserted(_))
+    }
 
-impl<'a, V, const N: usize> HashMap<V, N> {
-    pub fn iter(&'a self) -> Iter<'a, V> {
-        Iter {
-            buckets: &self.buckets[..],
-            current_bucket: 0,
-            current_entry: None,
+    /// The value now in the map, whoever put it there
+    pub fn value(&self) -> &'a V {
+        match *self {
+            Insertion::Inserted(val) | Insertion::Existing(val) => val,
         }
     }
 }
 
-impl<'a, V> Iterator for Iter<'a, V> {
-    type Item = (&'a usize, &'a V);
+/// A key of a map and whatever value it has, from `HashMap::entry`.
+///
+/// Unlike the std one, it doesn't tell an occupied entry from a vacant one:
+/// another thread can insert the key at any time, so every method looks
+/// again.
+pub struct EntryApi<'a, V, const N: usize, H = FibonacciHasher, A: Allocator = Global> {
+    map         : &'a HashMap<V, N, H, A>,
+    key         : usize,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.current_bucket < self.buckets.len() {
-            if let Some(entry) = self.current_entry {
-                // Traverse the linked list
-                let next_ptr = entry.next.load(Ordering::Acquire);
-                if !next_ptr.is_null() {
-                    self.current_entry = Some(unsafe { &*next_ptr });
-                    if let Some(entry) = self.current_entry {
-                        return Some((&entry.key, &entry.val));
-                    }
-                } else {
-                    // End of linked list
-                    self.current_entry = None; 
-                }
-            }
+impl<'a, V, const N: usize, H: IndexHasher, A: Allocator> EntryApi<'a, V, N, H, A> {
 
-            // Move to the next bucket
-            let bucket_ptr = self.buckets[self.current_bucket].load(Ordering::Acquire);
-            self.current_bucket += 1;
-            if !bucket_ptr.is_null() {
-                self.current_entry = Some(unsafe { &*bucket_ptr });
-                if let Some(entry) = self.current_entry {
-                    return Some((&entry.key, &entry.val));
-                }
-            }
-        }
+    pub fn key(&self) -> usize {
+        self.key
+    }
 
-        None
+    /// The value of the key, inserting `default` if it's not there. A
+    /// racing insert of the key may win, `default` is dropped then.
+    ///
+    /// # Panics
+    ///
+    /// If the key is not there and the map reached its `max_entries` cap.
+    pub fn or_insert(self, default: V) -> &'a V {
+        self.map.get_or_insert_with(self.key, || default)
+    }
+
+    /// `or_insert` building the value only if it's needed, see
+    /// `HashMap::get_or_insert_with`
+    ///
+    /// # Panics
+    ///
+    /// If the key is not there and the map reached its `max_entries` cap.
+    pub fn or_insert_with(self, f: impl FnOnce() -> V) -> &'a V {
+        self.map.get_or_insert_with(self.key, f)
+    }
+
+    /// Run `f` on the value if the key is there.
+    ///
+    /// The map never updates a value in place, so `f` gets a `&V` and only
+    /// values with interior mutability (atomics, locks) can be modified,
+    /// like with `HashMap::with_value_mut`. The check and the insert of a
+    /// following `or_insert` are two steps: a value inserted by another
+    /// thread in between gets neither `f` nor replaced. Counters that must
+    /// not lose increments do `or_insert_with(..)` and then the increment.
+    pub fn and_modify(self, f: impl FnOnce(&V)) -> Self {
+        self.map.with_value_mut(self.key, f);
+        self
     }
 }
 
+/// `lookup_bounded` gave up before reaching the key or the end of its chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyProbes;
 
+impl fmt::Display for TooManyProbes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("lookup exceeded its probe limit")
+    }
+}
 
-#[cfg(test)]
-mod tests {
+#[cfg(feature = "std")]
+impl std::error::Error for TooManyProbes {}
 
-    use std::sync::Arc;
+pub type Bucket<V> = AtomicPtr<Entry<V>>;
 
-    use super::*;
+/// Make a zeroed bucket array valid. All zeroes are null heads, except for
+/// loom's atomics which hold more than the pointer.
+#[inline]
+unsafe fn init_buckets<V, const N: usize>(table_ptr: *mut [Bucket<V>; N]) -> *mut [Bucket<V>; N] {
+    #[cfg(loom)]
+    for idx in 0..N {
+        ptr::write(table_ptr.cast::<Bucket<V>>().add(idx), Bucket::new(ptr::null_mut()));
+    }
+    table_ptr
+}
 
-    #[test]
-    fn test_1() {
+/// A live entry handed out by `insert_handle`
+#[derive(Clone, Copy)]
+pub struct EntryRef<'a, V> {
+    entry       : &'a Entry<V>,
+    bucket      : usize,
+}
 
-        let map = HashMap::<u64, 1024>::new();
-  
-        let _ = map.insert(1337,41);
-        let _ = map.insert(1338,0);
-        let _ = map.insert(1,1337);
-        let _ = map.insert(1012312,55);
-        let _ = map.insert(11111,999999);
+impl<'a, V> EntryRef<'a, V> {
 
-        assert_eq!(41, *map.lookup(1337).unwrap());
-        assert_eq!(0, *map.lookup(1338).unwrap());
-        assert_eq!(1337, *map.lookup(1).unwrap());
-        assert_eq!(55, *map.lookup(1012312).unwrap());
-        assert_eq!(999999, *map.lookup(11111).unwrap());
+    pub fn key(&self) -> usize {
+        self.entry.key
+    }
 
-        assert_eq!(map.entries(), 5);
+    /// Same lifetime as the `&V` returned by `insert`
+    pub fn value(&self) -> &'a V {
+        unsafe { self.entry.value() }
     }
 
-    /// Test insertions that end up with the same index in the HashMap
-    /// This ends in linear probing internally
-    #[test]
-    fn test_2() {
+    /// Index of the bucket whose chain holds the entry
+    pub fn bucket(&self) -> usize {
+        self.bucket
+    }
+}
 
-        let map = HashMap::<String, 8>::new();       
-        
-        let s1 = "first string".into();
-        let s2 = "second string".into();
-        let s3 = "third string".into();
-        let s4 = "foasodfjiosdg".into();
-        let s5 = "collision please?".into();
-        let s6 = "fasdafzxvng".into();
-        let s7 = "fixccccing".into();
-        let s8 = "fi1231211111ng".into();
-        
-        let _  = map.insert(0, s1);
-        let _  = map.insert(1, s2);
-        let _  = map.insert(16, s3);
-        let _  = map.insert(24, s4);
-        let _  = map.insert(32, s5);
-        let _  = map.insert(40, s6);
-        let _  = map.insert(48, s7);
-        let _  = map.insert(56, s8);
+/// Load factor past which `should_grow` advises a bigger map
+const DEFAULT_GROW_THRESHOLD: f64 = 1.0;
 
-        assert_eq!(map.lookup(0).unwrap(), "first string");
-        assert_eq!(map.lookup(32).unwrap(), "collision please?");
+/// Entries printed by `Debug` before eliding the rest
+const DEBUG_MAX_ENTRIES: usize = 64;
 
-        assert_eq!(map.entries(), 8);
-    }
+/// Keys `lookup_many` prefetches at once: enough misses in flight, and the
+/// lines still cached by the last pass
+const LOOKUP_BATCH: usize = 16;
 
+/// `A` allocates the bucket array and the entries, the chain index runs
+/// always come from the global allocator.
+pub struct HashMap<V, const N: usize, H = FibonacciHasher, A: Allocator = Global> {
 
-    /// Tests that an insertion with the same key returns the
-    /// first value inserted instead in a HashMapErr::ExistentEntry
-    /// Lookup returns the first value as well
-    /// Meaning the second value just doesn't get accepted in the HashMap
-    #[test]
-    fn test_3() {
+    /// Number of entries in the Table, sharded to spread the inserts. Like
+    /// `collisions`, updated with `AcqRel` and read with `Acquire`, see
+    /// `entries()`.
+    entries         : Counter,
 
-        let map = HashMap::<String, 8>::new();       
-        
-        let s1 = "first string".into();
-        let s2 = "second string".into();
-        
-        let _  = map.insert(0, s1);
-        let v = map.insert(0, s2).err().unwrap();
-        match v {
-            HashMapErr::HashMapFull => assert!(false),
-            HashMapErr::ExistentEntry(x) => {
-                assert_eq!(x, "first string");        
-            },
-        }
-        
-        assert_eq!(map.lookup(0).unwrap(), "first string");
+    /// Number of collisions, see `collisions()`
+    collisions      : AtomicUsize,
 
-        assert_eq!(map.entries(), 1);
-    }
+    /// The buckets in the table, from `alloc`. Go through `buckets()`.
+    table           : NonNull<[Bucket<V>; N]>,
 
-    /// Test map iteration
-    #[test]
-    fn test_4() {
+    /// Picks the bucket for a key
+    hasher          : H,
+
+    /// Cap on the live entries, `usize::MAX` if unbounded
+    max_entries     : usize,
+
+    /// Sorted runs over the long chains, see `with_chain_index`
+    index           : Option<ChainIndex<V>>,
+
+    /// Load factor past which `should_grow` says so
+    grow_threshold  : f64,
+
+    /// Spread neighbouring buckets over cache lines, see
+    /// `with_interleaved_buckets`
+    interleave      : bool,
+
+    /// Entry slots reserved for every bucket, see `with_capacity_hint`
+    pool            : Option<EntryPool<V>>,
+
+    /// Entries freed under `&mut self`, reused by inserts before allocating
+    free            : FreeList<V>,
+
+    /// Stamp of the next entry to get a value, see `iter_ordered`
+    seq             : AtomicUsize,
+
+    /// Where the buckets and entries come from
+    alloc           : A,
+}
+
+// Written out because the table pointer makes the map neither `Send` nor
+// `Sync`, while the `AtomicPtr`s would make it both even for an `Rc` value.
+
+/// Safety: the map owns its values, entries and bucket array, moving it moves
+/// all of them along with the hasher and the allocator that frees them.
+unsafe impl<V: Send, const N: usize, H: Send, A: Allocator + Send> Send for HashMap<V, N, H, A> {}
+
+/// Safety: through `&HashMap` a thread moves its values in, which another
+/// thread may drop when `EpochHashMap` reclaims them, so `V: Send`, and hands out
+/// `&V` to every thread reading, so `V: Sync`. The bucket array and the
+/// `next` links are only touched through atomics, and entries are fully
+/// built before the CAS publishing them. Every thread hashes and allocates
+/// through `&H` and `&A`.
+unsafe impl<V: Send + Sync, const N: usize, H: Sync, A: Allocator + Sync> Sync for HashMap<V, N, H, A> {}
+
+impl<V, const N: usize, H, A: Allocator> HashMap<V, N, H, A> {
+
+    #[inline]
+    fn buckets(&self) -> &[Bucket<V>; N] {
+        unsafe { self.table.as_ref() }
+    }
+
+    fn buckets_mut(&mut self) -> &mut [Bucket<V>; N] {
+        unsafe { self.table.as_mut() }
+    }
+
+    /// Take back an unlinked entry no thread can reach anymore, dropping its
+    /// value if it's still live. Up to `N` are kept for later inserts to
+    /// reuse, pool slots go back to their bucket rather than the free list.
+    unsafe fn recycle(&self, entry_ptr: *mut Entry<V>) {
+        let pooled = self.pool.as_ref().is_some_and(|pool| pool.contains(entry_ptr));
+        if self.free.len() < N && !pooled {
+            ptr::drop_in_place(entry_ptr);
+            self.free.push(entry_ptr);
+        } else {
+            Entry::free(entry_ptr, &self.alloc, self.pool.as_ref());
+        }
+    }
+}
+
+impl<V, const N: usize, H, A: Allocator> Drop for HashMap<V, N, H, A> {
+    fn drop(&mut self) {
+        self.free.release(&self.alloc);
+
+        let table_ptr = self.table.as_ptr();
+        let buckets = unsafe { &*table_ptr };
+
+        for bucket in buckets {
+            // Get the entry
+            let mut ptr = bucket.load(Ordering::SeqCst);
+
+            // Remove all the chained list of items for that bucket
+            while !ptr.is_null() {
+                // Get the next item in the list
+                let next_ptr = unsafe { (*ptr).next_ptr() };
+                // drop the current
+                unsafe { Entry::free(ptr, &self.alloc, self.pool.as_ref()) };
+                ptr = next_ptr;
+            }
+        }
+
+        unsafe { dealloc_in(&self.alloc, table_ptr) };
+        if let Some(pool) = &mut self.pool {
+            unsafe { pool.release(&self.alloc) };
+        }
+    }
+}
+
+/// Prints the live entries like std maps do, `{key: val, ..}`. Only the first
+/// `DEBUG_MAX_ENTRIES` are printed so huge maps don't flood the output.
+impl<V: fmt::Debug, const N: usize, H, A: Allocator> fmt::Debug for HashMap<V, N, H, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut iter = self.iter();
+        let mut map = f.debug_map();
+        map.entries(iter.by_ref().take(DEBUG_MAX_ENTRIES));
+
+        if iter.next().is_some() {
+            map.finish_non_exhaustive()
+        } else {
+            map.finish()
+        }
+    }
+}
+
+/// Point-in-time copy, re-inserting every entry into a fresh map in insertion
+/// order, so `iter_ordered` walks the copy the same. Entries inserted or
+/// removed by other threads while cloning may or may not show up.
+impl<V: Clone, const N: usize, H: IndexHasher + Clone> Clone for HashMap<V, N, H> {
+    fn clone(&self) -> Self {
+        let mut map = Self::with_hasher(self.hasher.clone())
+            .with_max_entries(self.max_entries)
+            .with_grow_threshold(self.grow_threshold);
+        if let Some(index) = &self.index {
+            map = map.with_chain_index(index.threshold);
+        }
+        if self.interleave {
+            map = map.with_interleaved_buckets();
+        }
+        if let Some(pool) = &self.pool {
+            map = map.with_capacity_hint(pool.per_bucket());
+        }
+
+        for (key, val) in self.iter_ordered() {
+            let _ = map.insert(*key, val.clone());
+        }
+
+        map
+    }
+}
+
+/// Duplicate keys keep the first value, like `insert`
+impl<V, const N: usize, H: IndexHasher + Default> FromIterator<(usize, V)> for HashMap<V, N, H> {
+    fn from_iter<I: IntoIterator<Item = (usize, V)>>(iter: I) -> Self {
+        let mut map = Self::with_hasher(H::default());
+        map.extend(iter);
+        map
+    }
+}
+
+/// Duplicate keys keep the value already in the map, like `insert`, and so
+/// do the pairs past the `max_entries` cap.
+impl<V, const N: usize, H: IndexHasher, A: Allocator> Extend<(usize, V)> for HashMap<V, N, H, A> {
+    fn extend<I: IntoIterator<Item = (usize, V)>>(&mut self, iter: I) {
+        for (key, val) in iter {
+            let _ = self.insert(key, val);
+        }
+    }
+}
+
+impl<V, const N: usize> Default for HashMap<V, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V, const N: usize> HashMap<V, N> {
+
+    /// Map placing keys with the multiply-shift of `FibonacciHasher`, so
+    /// keys differing only in their high bits, like `0, N, 2N, ..`, still
+    /// spread over the buckets. `with_hasher(IdentityHasher)` keeps the key
+    /// bits as they are.
+    pub fn new() -> Self {
+        Self::with_hasher(FibonacciHasher)
+    }
+
+    /// `new` returning `AllocError` when the bucket array can't be
+    /// allocated, for callers that must survive running out of memory
+    pub fn try_new() -> Result<Self, AllocError> {
+        Self::try_with_hasher_in(FibonacciHasher, Global)
+    }
+
+    /// A map usable in a `static` or `const`, see `StaticHashMap`
+    pub const fn new_const() -> StaticHashMap<V, N> {
+        StaticHashMap::new()
+    }
+
+    /// Adopt a bucket array built outside of a map, e.g. loaded from a
+    /// persisted image, holding `entries` live entries. The map frees the
+    /// array and the entries from then on.
+    ///
+    /// # Safety
+    ///
+    /// - The entries are separate `Entry<V>` allocations of the global
+    ///   allocator, each reachable from a single bucket and owned by nothing
+    ///   else.
+    /// - Every chain ends with a null `next` and only holds keys the
+    ///   `FibonacciHasher` puts in its bucket. Of the entries of a key all but
+    ///   the last are retired, and none is busy.
+    /// - `entries` is the number of live entries.
+    pub unsafe fn from_raw_parts(buckets: Box<[Bucket<V>; N]>, entries: usize) -> Self {
+        let () = Self::NON_EMPTY;
+
+        let table_ptr = Box::into_raw(buckets);
+
+        // Counted like inserts would have, and stamps past the adopted ones
+        let mut collisions = 0;
+        let mut seq = 0;
+        for bucket in &*table_ptr {
+            let mut entry_ptr = bucket.load(Ordering::Acquire);
+            let mut depth = 0;
+            while !entry_ptr.is_null() {
+                if depth > 0 {
+                    collisions += 1;
+                }
+                seq = seq.max((*entry_ptr).seq.load(Ordering::Relaxed) + 1);
+                entry_ptr = (*entry_ptr).next_ptr();
+                depth += 1;
+            }
+        }
+
+        let mut counter = Counter::new();
+        counter.set(entries);
+
+        HashMap {
+            entries:       counter,
+            collisions:    AtomicUsize::new(collisions),
+            table:         NonNull::new_unchecked(table_ptr),
+            hasher:        FibonacciHasher,
+            max_entries:   usize::MAX,
+            index:         None,
+            grow_threshold: DEFAULT_GROW_THRESHOLD,
+            interleave:    false,
+            pool:          None,
+            free:          FreeList::new(),
+            seq:           AtomicUsize::new(seq),
+            alloc:         Global,
+        }
+    }
+
+    /// Map placing keys with a `SeededHasher`, so the bucket of a key depends
+    /// on `seed`. Use it when keys may come from an adversary.
+    pub fn new_with_seed(seed: usize) -> HashMap<V, N, SeededHasher> {
+        HashMap::with_hasher(SeededHasher::new(seed))
+    }
+}
+
+impl<V, const N: usize, A: Allocator> HashMap<V, N, FibonacciHasher, A> {
+
+    /// `new` with the buckets and entries allocated by `alloc`
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_hasher_in(FibonacciHasher, alloc)
+    }
+
+    /// `try_new` with the buckets and entries allocated by `alloc`
+    pub fn try_new_in(alloc: A) -> Result<Self, AllocError> {
+        Self::try_with_hasher_in(FibonacciHasher, alloc)
+    }
+}
+
+impl<V, const N: usize, H: IndexHasher> HashMap<V, N, H> {
+
+    /// Build a map placing keys with `hasher`
+    pub fn with_hasher(hasher: H) -> Self {
+        Self::with_hasher_in(hasher, Global)
+    }
+}
+
+impl<V, const N: usize, H: IndexHasher, A: Allocator> HashMap<V, N, H, A> {
+
+    /// Evaluated on construction, a map without buckets fails to compile
+    const NON_EMPTY: () = assert!(N > 0, "HashMap needs at least one bucket");
+
+    /// Number of live entries, same as `len`.
+    ///
+    /// A slot is counted before its entry gets linked, so while inserts are
+    /// in flight the count may run ahead of what lookups find, and a batch of
+    /// `insert_many` is only counted when it returns. Once the inserting
+    /// threads are joined the count is exact.
+    pub fn entries(&self) -> usize {
+        self.entries.sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of entries chained behind a bucket head, i.e. keys that landed
+    /// in an already occupied bucket. Only a successful append to a chain
+    /// counts, so lost races and duplicate keys never do. Removed entries stay
+    /// in their chain and reusing them doesn't count either.
+    pub fn collisions(&self) -> usize {
+        self.collisions.load(Ordering::Acquire)
+    }
+
+    /// Number of buckets
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Approximate heap footprint in bytes: the bucket array, the entry
+    /// slots of `with_capacity_hint`, the live entries outside of them and the
+    /// ones kept for reuse by `compact`. Tombstones, the chain index and the
+    /// allocator's own overhead are left out.
+    pub fn memory_usage(&self) -> usize {
+        let table = core::mem::size_of::<[Bucket<V>; N]>();
+        let (pool, pooled) = self.pool.as_ref().map_or((0, 0), |pool| (pool.size(), pool.claimed()));
+
+        table + pool
+            + (self.entries().saturating_sub(pooled) + self.free.len()) * core::mem::size_of::<Entry<V>>()
+    }
+
+    /// Entries per bucket. Chains keep working above 1.0, but lookups
+    /// get slower as they grow.
+    pub fn load_factor(&self) -> f64 {
+        self.entries() as f64 / N as f64
+    }
+
+    /// Whether the load factor crossed the `with_grow_threshold` one.
+    ///
+    /// `N` is fixed, so the map never grows by itself. This is the hint for
+    /// callers growing it their own way, e.g. building a map with more
+    /// buckets and moving the entries there with `migrate_into`.
+    pub fn should_grow(&self) -> bool {
+        self.load_factor() > self.grow_threshold
+    }
+
+    /// Insert a clone of every entry into `dst`, usually a map with more
+    /// buckets, returning how many got in.
+    ///
+    /// Keys already in `dst` keep their value there, and so do the entries
+    /// past its `max_entries` cap. Like `clone`, entries inserted or removed
+    /// concurrently may or may not be migrated, so stop the writers first or
+    /// have them write to both maps while this runs.
+    pub fn migrate_into<const M: usize, H2: IndexHasher, A2: Allocator>(&self, dst: &HashMap<V, M, H2, A2>) -> usize
+        where V: Clone {
+
+        self.iter()
+            .filter(|(key, val)| dst.insert(**key, (*val).clone()).is_ok())
+            .count()
+    }
+
+    /// Fold the entries of `other` into this map, for maps built apart by
+    /// workers. Keys only in `other` get a clone of its value, keys in both
+    /// get `combine(ours, theirs)`. Returns how many keys were merged, the
+    /// new keys past the `max_entries` cap are left out.
+    ///
+    /// Combining replaces values in place, which readers holding a `&V`
+    /// can't allow, hence `&mut self`. `other` is only read, but should be
+    /// quiescent: like `iter`, entries inserted into it concurrently may or
+    /// may not be merged.
+    pub fn merge<const M: usize, H2, A2: Allocator>(&mut self, other: &HashMap<V, M, H2, A2>,
+        combine: impl Fn(&V, &V) -> V) -> usize where V: Clone {
+
+        let mut merged = 0;
+        for (&key, theirs) in other.iter() {
+            if let Some(ours) = self.get_mut(key) {
+                *ours = combine(ours, theirs);
+                merged += 1;
+            } else if self.insert(key, theirs.clone()).is_ok() {
+                merged += 1;
+            }
+        }
+        merged
+    }
+
+    /// Build a map placing keys with `hasher`, allocating from `alloc`
+    pub fn with_hasher_in(hasher: H, alloc: A) -> Self {
+        match Self::try_with_hasher_in(hasher, alloc) {
+            Ok(map) => map,
+            Err(_)  => handle_alloc_error(Layout::new::<[Bucket<V>; N]>()),
+        }
+    }
+
+    /// `with_hasher_in` returning `AllocError` if the bucket array can't be
+    /// allocated, instead of aborting. Entries are still allocated by the
+    /// inserts, which abort when that fails.
+    pub fn try_with_hasher_in(hasher: H, alloc: A) -> Result<Self, AllocError> {
+        let () = Self::NON_EMPTY;
+
+        let table_ptr = unsafe { init_buckets(try_alloc_zeroed_in(&alloc)?) };
+
+        Ok(HashMap {
+            entries:       Counter::new(),
+            collisions:    AtomicUsize::new(0),
+            table:         unsafe { NonNull::new_unchecked(table_ptr) },
+            hasher,
+            max_entries:   usize::MAX,
+            index:         None,
+            grow_threshold: DEFAULT_GROW_THRESHOLD,
+            interleave:    false,
+            pool:          None,
+            free:          FreeList::new(),
+            seq:           AtomicUsize::new(0),
+            alloc,
+        })
+    }
+
+    /// Cap the number of live entries. Past it `insert` fails with
+    /// `HashMapErr::HashMapFull` instead of growing the chains, which bounds
+    /// the memory used by entries. Tombstones left by `remove` keep their
+    /// allocation but don't count against the cap.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        // Counted in shard 0 from now on
+        let entries = self.entries();
+        self.entries.set(entries);
+        self
+    }
+
+    /// Load factor past which `should_grow` returns true, 1.0 by default
+    pub fn with_grow_threshold(mut self, load_factor: f64) -> Self {
+        self.grow_threshold = load_factor;
+        self
+    }
+
+    /// Index the chain entries from position `threshold` on (at least 1) with
+    /// a sorted array per bucket, so a lookup in a bucket with `n` entries
+    /// walks `threshold` entries, binary searches about `log n` keys and
+    /// walks at most `threshold` entries more instead of `n` entries.
+    ///
+    /// It's for buckets that can't be kept short by the hasher, e.g. keys
+    /// chosen by an adversary, and costs three words per bucket plus two per
+    /// indexed entry. Inserts still walk the whole chain to append to it, and
+    /// every `threshold` of them past the threshold copy the bucket's array.
+    /// The arrays only shrink when `compact`, `retain` or `clear` rebuild them.
+    pub fn with_chain_index(mut self, threshold: usize) -> Self {
+        self.index = Some(ChainIndex::new(N, threshold));
+        self
+    }
+
+    /// Lay the buckets out so that the ones the hasher gives to neighbouring
+    /// keys are on different cache lines, for a new map.
+    ///
+    /// With the `IdentityHasher`, threads inserting nearby keys, e.g. a range
+    /// split round robin, otherwise CAS heads sharing a cache line, which
+    /// bounces between their cores. Bucket `i` of the `R` rows of full lines
+    /// moves to line `i % R`, the buckets of a partial last line stay put.
+    /// It costs a division by a constant per operation, and a scan of
+    /// consecutive keys touches a line per key instead of one per line.
+    /// Random keys or a `SeededHasher` gain nothing from it.
+    pub fn with_interleaved_buckets(mut self) -> Self {
+        debug_assert!(self.is_empty(), "interleaving a map with entries");
+        self.interleave = true;
+        self
+    }
+
+    /// Reserve `per_bucket` entry slots for every bucket, in one allocation
+    /// from the map's allocator, for a new map. The first `per_bucket`
+    /// entries linked into a bucket take its slots instead of being
+    /// allocated one by one, later ones come from the free list or the
+    /// allocator as usual. Slots freed by `compact` or `retain` go back to
+    /// their bucket.
+    ///
+    /// It trades memory for fewer allocations: the slots take
+    /// `N * per_bucket` entries whether the buckets fill up or not, so pick
+    /// `per_bucket` around the expected load factor.
+    pub fn with_capacity_hint(mut self, per_bucket: usize) -> Self {
+        debug_assert!(self.is_empty(), "reserving entries for a map with entries");
+        self.pool = (per_bucket > 0).then(|| EntryPool::new_in(N, per_bucket, &self.alloc));
+        self
+    }
+
+    /// Buckets sharing a cache line
+    const BUCKETS_PER_LINE: usize = 64 / core::mem::size_of::<Bucket<V>>();
+
+    /// Returns a position inside the table 
+    /// based on the hasher and the key
+    #[inline]
+    fn get_idx(&self, key: usize) -> usize {     
+        let idx = self.hasher.index(key, N);
+        if !self.interleave {
+            return idx;
+        }
+
+        let rows = N / Self::BUCKETS_PER_LINE;
+        if idx >= rows * Self::BUCKETS_PER_LINE {
+            return idx;
+        }
+        (idx % rows) * Self::BUCKETS_PER_LINE + idx / rows
+    }
+
+    /// Bucket `key` lands in, without inserting it. Threads inserting keys
+    /// of disjoint bucket ranges never contend on a chain.
+    pub fn bucket_index(&self, key: usize) -> usize {
+        self.get_idx(key)
+    }
+
+    /// How many of `keys` would land in a bucket already taken by another,
+    /// i.e. `collisions()` after inserting them into an empty map, without
+    /// inserting anything. Duplicate keys count once.
+    ///
+    /// To pick `N` and the hasher before filling a map: the `IdentityHasher`
+    /// puts keys `0, N, 2N, ..` all in bucket 0, where the multiply-shift of
+    /// the default `FibonacciHasher` spreads them.
+    pub fn collision_estimate(&self, keys: &[usize]) -> usize {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut idxs: Vec<usize> = keys.iter().map(|&key| self.get_idx(key)).collect();
+        idxs.sort_unstable();
+        idxs.dedup();
+        keys.len() - idxs.len()
+    }
+
+    /// Debug dump of the buckets into `w`, a line per bucket with its head
+    /// entry and key
+    #[cfg(all(test, not(loom)))]
+    fn dump(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        for idx in 0..N {
+            let bucket = &self.buckets()[idx];
+            let entry_ptr = bucket.load(Ordering::Acquire);
+            if entry_ptr.is_null() {
+                writeln!(w, "Idx:[{:x}: NULL", idx)?;
+                continue;
+            }
+
+            let cur_key = unsafe { (*entry_ptr).key };
+            writeln!(w, "Idx:[{:x}: {:p} -> {}", idx, entry_ptr, cur_key)?;
+        }
+        Ok(())
+    }
+
+    /// Check the structural invariants, for tests once the threads are joined:
+    /// every chain ends, its entries belong to its bucket, all but the last
+    /// entry of a key are retired, and `entries` matches the live ones.
+    #[cfg(test)]
+    fn validate(&self) -> Result<(), std::string::String> {
+        use std::format;
+
+        let mut live = 0;
+
+        for (idx, bucket) in self.buckets().iter().enumerate() {
+            let head_ptr = bucket.load(Ordering::Acquire);
+
+            // Floyd's, the hare moves two entries for every one of the tortoise
+            let (mut slow_ptr, mut fast_ptr) = (head_ptr, head_ptr);
+            while !fast_ptr.is_null() {
+                fast_ptr = unsafe { (*fast_ptr).next_ptr() };
+                if fast_ptr.is_null() {
+                    break;
+                }
+                fast_ptr = unsafe { (*fast_ptr).next_ptr() };
+                slow_ptr = unsafe { (*slow_ptr).next_ptr() };
+                if ptr::eq(slow_ptr, fast_ptr) {
+                    return Err(format!("bucket {idx}: the chain loops"));
+                }
+            }
+
+            // Finite now, walk it for the keys
+            let mut keys = std::collections::HashMap::<usize, usize>::new();
+            let mut entry_ptr = head_ptr;
+            while !entry_ptr.is_null() {
+                let entry = unsafe { &*entry_ptr };
+
+                if self.get_idx(entry.key) != idx {
+                    return Err(format!("bucket {idx}: key {} belongs to bucket {}", entry.key, self.get_idx(entry.key)));
+                }
+
+                let state = entry.state();
+                if state == STATE_LIVE {
+                    live += 1;
+                }
+                if let Some(prev_state) = keys.insert(entry.key, state) {
+                    if prev_state != STATE_RETIRED {
+                        return Err(format!("bucket {idx}: key {} chained twice", entry.key));
+                    }
+                }
+
+                entry_ptr = entry.next_ptr();
+            }
+        }
+
+        if live != self.entries() {
+            return Err(format!("{} live entries counted as {}", live, self.entries()));
+        }
+
+        Ok(())
+    }
+
+    /// Fault in the pages of the bucket array before entering a hot path.
+    ///
+    /// `alloc_zeroed` hands out lazily mapped pages, so the first insert into
+    /// each page pays a page fault. That only matters for huge `N`, like the
+    /// 30M buckets perf map. A plain load isn't enough on systems mapping a
+    /// shared zero page for reads, so this does a CAS from null to null on a
+    /// bucket of every page, which is a no-op for concurrent inserts.
+    pub fn prefault(&self) {
+        const PAGE_SIZE: usize = 4096;
+        let stride = (PAGE_SIZE / core::mem::size_of::<Bucket<V>>()).max(1);
+
+        for bucket in self.buckets().iter().step_by(stride) {
+            let _ = bucket.compare_exchange(ptr::null_mut(), ptr::null_mut(),
+                Ordering::Relaxed,
+                Ordering::Relaxed);
+        }
+    }
+
+    /// Chain length of every bucket, to spot hot buckets. Tombstones count
+    /// too since lookups still walk them. O(N + entries) like `max_probe`.
+    pub fn bucket_histogram(&self) -> Vec<usize> {
+        self.buckets().iter().map(|bucket| {
+            let mut len = 0;
+            let mut entry_ptr = bucket.load(Ordering::Acquire);
+            while !entry_ptr.is_null() {
+                len += 1;
+                entry_ptr = unsafe { (*entry_ptr).next_ptr() };
+            }
+            len
+        }).collect()
+    }
+
+    /// Number of buckets with a chain, removed entries included. Without
+    /// collisions it's the number of entries.
+    pub fn occupied_buckets(&self) -> usize {
+        self.buckets().iter()
+            .filter(|bucket| !bucket.load(Ordering::Acquire).is_null())
+            .count()
+    }
+
+    /// First bucket without a chain from `idx` on, wrapping around the end,
+    /// or `None` if every bucket has one. A key placed there won't collide.
+    pub fn next_free_from(&self, idx: usize) -> Option<usize> {
+        let start = idx % N;
+        (start..N).chain(0..start)
+            .find(|&idx| self.buckets()[idx].load(Ordering::Acquire).is_null())
+    }
+
+    /// Position of every live entry in its chain, 0 for a bucket head. Unless
+    /// the chains are indexed, it's how many entries a lookup walks past.
+    fn probe_distances(&self) -> impl Iterator<Item = usize> + '_ {
+        self.buckets().iter().flat_map(|bucket| {
+            let mut entry_ptr = bucket.load(Ordering::Acquire);
+            let mut dist = 0;
+            core::iter::from_fn(move || {
+                while !entry_ptr.is_null() {
+                    let cur_entry = unsafe { &*entry_ptr };
+                    let cur_dist = dist;
+                    entry_ptr = cur_entry.next_ptr();
+                    dist += 1;
+                    if cur_entry.state() == STATE_LIVE {
+                        return Some(cur_dist);
+                    }
+                }
+                None
+            })
+        })
+    }
+
+    /// Longest walk down a chain to reach a live entry.
+    ///
+    /// Every call walks all `N` buckets and every chained entry, removed ones
+    /// included, so it's O(N + entries): sample it, don't poll it from a hot
+    /// path. Inserts don't pay for keeping it up to date.
+    pub fn max_probe(&self) -> usize {
+        self.probe_distances().max().unwrap_or(0)
+    }
+
+    /// Average walk down a chain to reach a live entry, 0.0 when empty.
+    /// Growing well past `load_factor() / 2` hints at hot buckets.
+    ///
+    /// O(N + entries) per call like `max_probe`.
+    pub fn avg_probe(&self) -> f64 {
+        let (count, sum) = self.probe_distances()
+            .fold((0usize, 0usize), |(count, sum), dist| (count + 1, sum + dist));
+
+        if count == 0 { 0.0 } else { sum as f64 / count as f64 }
+    }
+
+    /// Walk the chain of `key` looking for its live entry
+    #[inline]
+    fn find_entry(&self, key: usize) -> Option<&Entry<V>> {
+        self.find_entry_counting(key, &mut 0)
+    }
+
+    /// `find_entry`, counting the entries and index nodes visited in `probes`
+    #[inline]
+    fn find_entry_counting(&self, key: usize, probes: &mut usize) -> Option<&Entry<V>> {
+        self.find_entry_bounded(key, probes, usize::MAX).unwrap_or(None)
+    }
+
+    /// `find_entry_counting`, giving up once `probes` goes past `limit`
+    #[inline]
+    fn find_entry_bounded(&self, key: usize, probes: &mut usize, limit: usize)
+        -> Result<Option<&Entry<V>>, TooManyProbes> {
+        self.find_entry_at(self.get_idx(key), key, probes, limit)
+    }
+
+    /// `find_entry_bounded` in the chain of bucket `idx`
+    #[inline]
+    fn find_entry_at(&self, idx: usize, key: usize, probes: &mut usize, limit: usize)
+        -> Result<Option<&Entry<V>>, TooManyProbes> {
+
+        let bucket  = &self.buckets()[idx];
+
+        let mut entry_ptr = bucket.load(Ordering::Acquire);
+
+        let mut depth = 0;
+
+        // Walk the LL, keys are unique within a chain
+        while !entry_ptr.is_null() {
+
+            if let Some(index) = &self.index {
+                if depth == index.threshold {
+                    // Resume from the key if the run has it, else past the run
+                    if let Some(resume_ptr) = index.find(idx, key, probes) {
+                        if *probes > limit {
+                            return Err(TooManyProbes);
+                        }
+                        if resume_ptr.is_null() {
+                            return Ok(None);
+                        }
+                        entry_ptr = resume_ptr;
+                    }
+                }
+            }
+            depth += 1;
+            *probes += 1;
+
+            if *probes > limit {
+                return Err(TooManyProbes);
+            }
+
+            let cur_entry = unsafe { &*entry_ptr };
+
+            // Start fetching the next entry while we check this one. Only a
+            // hint, the pointer to follow is loaded again below.
+            if cfg!(feature = "prefetch") {
+                prefetch(untag(cur_entry.next.load(Ordering::Relaxed)));
+            }
+
+            if cur_entry.key == key {
+                match cur_entry.state() {
+                    STATE_LIVE    => return Ok(Some(cur_entry)),
+                    // A newer entry for the key may follow
+                    STATE_RETIRED => {},
+                    // A removed key is just not there
+                    _             => return Ok(None),
+                }
+            }
+
+            entry_ptr = cur_entry.next_ptr();
+        }
+
+        Ok(None)
+    }
+
+    pub fn lookup(&self, key: usize) -> Option<&V> {
+        self.find_entry(key)
+            .map(|entry| unsafe { entry.value() })
+    }
+
+    /// `lookup` also returning the number of chain entries walked, index
+    /// nodes included, 1 for a key at the head of its bucket. A missing key
+    /// walks its whole chain, 0 for an empty bucket.
+    pub fn lookup_profiled(&self, key: usize) -> (Option<&V>, usize) {
+        let mut probes = 0;
+        let val = self.find_entry_counting(key, &mut probes)
+            .map(|entry| unsafe { entry.value() });
+        (val, probes)
+    }
+
+    /// `lookup` of every key in `keys`, the result for `keys[i]` going in
+    /// `out[i]`.
+    ///
+    /// Batches of keys go through three passes: prefetch their buckets, then
+    /// prefetch the heads of their chains, then look them up. The cache
+    /// misses of a batch overlap instead of stalling one lookup after the
+    /// other. Without the `prefetch` feature it's a plain loop of `lookup`.
+    ///
+    /// # Panics
+    ///
+    /// If `out` is not as long as `keys`.
+    pub fn lookup_many<'a>(&'a self, keys: &[usize], out: &mut [Option<&'a V>]) {
+        assert_eq!(keys.len(), out.len(), "one output slot per key");
+
+        let buckets = self.buckets();
+
+        for (keys, out) in keys.chunks(LOOKUP_BATCH).zip(out.chunks_mut(LOOKUP_BATCH)) {
+            if cfg!(feature = "prefetch") {
+                for &key in keys {
+                    prefetch(&buckets[self.get_idx(key)]);
+                }
+                for &key in keys {
+                    prefetch(buckets[self.get_idx(key)].load(Ordering::Relaxed));
+                }
+            }
+
+            for (&key, slot) in keys.iter().zip(out) {
+                *slot = self.lookup(key);
+            }
+        }
+    }
+
+    /// The keys of `keys` in the map with their values, in the order of
+    /// `keys`, for intersections where most keys miss. Batched like
+    /// `lookup_many`.
+    pub fn lookup_present<'a>(&'a self, keys: &[usize]) -> Vec<(usize, &'a V)> {
+        let mut present = Vec::new();
+        let mut found = [None; LOOKUP_BATCH];
+
+        for keys in keys.chunks(LOOKUP_BATCH) {
+            let found = &mut found[..keys.len()];
+            self.lookup_many(keys, found);
+            present.extend(keys.iter().zip(found.iter())
+                .filter_map(|(&key, val)| val.map(|val| (key, val))));
+        }
+        present
+    }
+
+    /// `lookup` in bucket `idx`, from `bucket_index(key)`, for a lookup and
+    /// an `insert_at` of the same key hashing it once. Any other `idx` is a
+    /// miss, unless the key got inserted there with the same wrong `idx`.
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is out of the bucket array.
+    pub fn lookup_at(&self, idx: usize, key: usize) -> Option<&V> {
+        self.find_entry_at(idx, key, &mut 0, usize::MAX)
+            .unwrap_or(None)
+            .map(|entry| unsafe { entry.value() })
+    }
+
+    /// `lookup` visiting at most `max_steps` entries and index nodes, for
+    /// callers that can't afford walking a pathological chain. Gives up with
+    /// `TooManyProbes` rather than answering late.
+    pub fn lookup_bounded(&self, key: usize, max_steps: usize) -> Result<Option<&V>, TooManyProbes> {
+        self.find_entry_bounded(key, &mut 0, max_steps)
+            .map(|found| found.map(|entry| unsafe { entry.value() }))
+    }
+
+    /// Run `f` on the value of `key`, for values updated in place through
+    /// interior mutability (atomics, locks), the map itself never updates a
+    /// value. `None` if the key is not there.
+    pub fn with_value_mut<R>(&self, key: usize, f: impl FnOnce(&V) -> R) -> Option<R> {
+        self.lookup(key).map(f)
+    }
+
+    /// Mutable access to the value of `key`, exclusive access to the map
+    /// rules out any reader
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut V> {
+        self.find_entry(key)
+            .map(|entry| unsafe { &mut **entry.val.get() })
+    }
+
+    /// Clone of the value, not tied to the map borrow
+    pub fn get_cloned(&self, key: usize) -> Option<V> where V: Clone {
+        self.lookup(key).cloned()
+    }
+
+    /// Presence check, walks the chain like `lookup` without borrowing the value
+    pub fn contains_key(&self, key: usize) -> bool {
+        self.find_entry(key).is_some()
+    }
+
+    /// Insert a entry into the table. The entry is only allocated once the
+    /// walk reaches the end of the chain, a duplicate key costs no allocation.
+    #[must_use = "the insert may have been rejected, see `insert_outcome`"]
+    pub fn insert(&self, key: usize, value: V) -> Result<&V, HashMapErr<'_, V>> {
+        self.insert_with(key, || value)
+    }
+
+    /// `insert` only if `precondition` holds, e.g. `|map| map.contains_key(a)`
+    /// to insert a key only once the one it depends on is there. `None` if
+    /// the precondition failed, `value` is dropped then.
+    ///
+    /// A convenience, not a transaction: the precondition is checked right
+    /// before inserting, and nothing stops another thread from changing what
+    /// it saw in between. Only inserts are atomic, so a precondition on the
+    /// presence of keys that are never removed stays true.
+    pub fn insert_if(&self, key: usize, value: V, precondition: impl Fn(&Self) -> bool)
+        -> Option<Result<&V, HashMapErr<'_, V>>> {
+        precondition(self).then(|| self.insert(key, value))
+    }
+
+    /// `insert` into bucket `idx`, which must be `bucket_index(key)`. The
+    /// index isn't checked outside of debug builds: a key inserted anywhere
+    /// else is only found by `lookup_at` with the same `idx`, and `insert`
+    /// would add it a second time.
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is out of the bucket array, or not the bucket of `key` in a
+    /// debug build.
+    #[must_use = "the insert may have been rejected, see `insert_outcome`"]
+    pub fn insert_at(&self, idx: usize, key: usize, value: V) -> Result<&V, HashMapErr<'_, V>> {
+        debug_assert_eq!(idx, self.get_idx(key), "key inserted out of its bucket");
+
+        let mut staged = Staged::<V, fn() -> V, A>::ready(value, &self.alloc, self.pool.as_ref());
+        let mut slot = Reservation::new(&self.entries, self.max_entries);
+
+        self.insert_reserved_at(idx, key, &mut staged, &mut slot)
+            .map(|entry| unsafe { entry.value() })
+    }
+
+    /// `insert` telling a duplicate apart from a fresh insert without an
+    /// error. Only fails with `HashMapErr::HashMapFull`.
+    pub fn insert_outcome(&self, key: usize, value: V) -> Result<Insertion<'_, V>, HashMapErr<'_, V>> {
+        match self.insert(key, value) {
+            Ok(val) => Ok(Insertion::Inserted(val)),
+            Err(HashMapErr::ExistentEntry(val)) => Ok(Insertion::Existing(val)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// `insert` returning a handle on the new entry, which also tells the
+    /// bucket it landed in, instead of just the value
+    pub fn insert_handle(&self, key: usize, value: V) -> Result<EntryRef<'_, V>, HashMapErr<'_, V>> {
+        let mut staged = Staged::<V, fn() -> V, A>::ready(value, &self.alloc, self.pool.as_ref());
+        let mut slot = Reservation::new(&self.entries, self.max_entries);
+
+        self.insert_reserved(key, &mut staged, &mut slot)
+            .map(|entry| EntryRef { entry, bucket: self.get_idx(key) })
+    }
+
+    /// `insert` with an owned error, cloning the existent value
+    pub fn try_insert_owned(&self, key: usize, value: V) -> Result<&V, InsertError<V>>
+        where V: Clone {
+        self.insert(key, value).map_err(InsertError::from)
+    }
+
+    /// Returns the value for `key`, inserting the result of `f` if it's not there.
+    ///
+    /// `f` only runs when a new value is actually needed, and at most once even
+    /// if we lose a race and have to retry further down the chain. A racing
+    /// insert of the same key can still win, in which case the value built by
+    /// `f` is dropped and the winner's is returned.
+    ///
+    /// # Panics
+    ///
+    /// If the key is not there and the map reached its `max_entries` cap.
+    pub fn get_or_insert_with(&self, key: usize, f: impl FnOnce() -> V) -> &V {
+        match self.insert_with(key, f) {
+            Ok(val) | Err(HashMapErr::ExistentEntry(val)) => val,
+            Err(HashMapErr::HashMapFull) => panic!("HashMap is full"),
+        }
+    }
+
+    /// The entry of `key`, for `map.entry(key).or_insert(value)` in the style
+    /// of the std maps
+    pub fn entry(&self, key: usize) -> EntryApi<'_, V, N, H, A> {
+        EntryApi { map: self, key }
+    }
+
+    /// Insert `value` unless the key is there, returning whether ours got in
+    /// along with the value now in the map, for interning.
+    ///
+    /// # Panics
+    ///
+    /// If the key is not there and the map reached its `max_entries` cap.
+    pub fn try_insert(&self, key: usize, value: V) -> (bool, &V) {
+        match self.insert(key, value) {
+            Ok(val) => (true, val),
+            Err(HashMapErr::ExistentEntry(val)) => (false, val),
+            Err(HashMapErr::HashMapFull) => panic!("HashMap is full"),
+        }
+    }
+
+    fn insert_with<F: FnOnce() -> V>(&self, key: usize, f: F) -> Result<&V, HashMapErr<'_, V>> {
+        // The new entry is only built and boxed once we know we need it
+        self.insert_staged(key, &mut Staged::new(f, &self.alloc, self.pool.as_ref()))
+    }
+
+    /// Insert `value`, handing it back along with the existent one if the key
+    /// is already there
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn insert_or_return(&self, key: usize, value: V) -> Result<&V, (&V, V)> {
+        let mut staged = Staged::<V, fn() -> V, A>::ready(value, &self.alloc, self.pool.as_ref());
+
+        match self.insert_staged(key, &mut staged) {
+            Ok(val) => Ok(val),
+            Err(HashMapErr::ExistentEntry(val)) => Err((val, staged.take_value())),
+            Err(HashMapErr::HashMapFull) => unreachable!("only capped maps fill up"),
+        }
+    }
+
+    /// Insert every pair, returning one result per pair in order.
+    ///
+    /// On a map without `max_entries` cap the entries counter is updated once
+    /// at the end instead of once per insert, which is what makes this cheaper
+    /// than calling `insert` in a loop under contention. `entries()` lags
+    /// behind until the batch returns.
+    pub fn insert_many(&self, items: impl IntoIterator<Item = (usize, V)>)
+        -> Vec<Result<(), HashMapErr<'_, V>>> {
+
+        let mut slot = if self.max_entries == usize::MAX {
+            Reservation::deferred(&self.entries)
+        } else {
+            Reservation::new(&self.entries, self.max_entries)
+        };
+
+        items.into_iter().map(|(key, val)| {
+            let mut staged = Staged::<V, fn() -> V, A>::ready(val, &self.alloc, self.pool.as_ref());
+            let res = self.insert_reserved(key, &mut staged, &mut slot);
+            slot.release();
+            res.map(|_| ())
+        }).collect()
+    }
+
+    /// Insert the pairs of a precomputed table, returning how many got in.
+    /// Duplicate keys keep the first value, like `insert`.
+    ///
+    /// With `assume_unique` the caller vouches that no two pairs share a key
+    /// and that none is in the map yet. Entries then go at the head of their
+    /// chain without walking it for the key. Breaking that promise doesn't
+    /// break memory safety, but the key ends up twice in the map and lookups
+    /// only see its last value. Maps with a chain index take the usual path,
+    /// the index relies on entries keeping their chain position.
+    ///
+    /// It takes `&mut self` so no other thread can insert a key behind the
+    /// head while it's prepended, use `insert_many` to load concurrently.
+    pub fn load_copy(&mut self, pairs: &[(usize, V)], assume_unique: bool) -> usize where V: Copy {
+        if !assume_unique || self.index.is_some() {
+            return self.insert_many(pairs.iter().copied())
+                .iter()
+                .filter(|res| res.is_ok())
+                .count();
+        }
+
+        let mut slot = if self.max_entries == usize::MAX {
+            Reservation::deferred(&self.entries)
+        } else {
+            Reservation::new(&self.entries, self.max_entries)
+        };
+
+        let mut loaded = 0;
+        for &(key, val) in pairs {
+            let idx = self.get_idx(key);
+            if !slot.acquire(idx) {
+                break;
+            }
+
+            let mut staged = Staged::<V, fn() -> V, A>::ready(val, &self.alloc, self.pool.as_ref());
+            let new_entry_ptr = staged.entry_ptr(idx, key, &self.free, &self.seq);
+            let bucket = &self.buckets()[idx];
+
+            // Nobody else can see the map, no CAS needed
+            let head_ptr = bucket.load(Ordering::Relaxed);
+            unsafe { (*new_entry_ptr).next.store(head_ptr, Ordering::Relaxed) };
+            bucket.store(new_entry_ptr, Ordering::Relaxed);
+            staged.publish();
+            slot.commit();
+
+            if !head_ptr.is_null() {
+                self.collisions.fetch_add(1, Ordering::AcqRel);
+            }
+            loaded += 1;
+        }
+
+        loaded
+    }
+
+    fn insert_staged<F: FnOnce() -> V>(&self, key: usize, staged: &mut Staged<'_, V, F, A>)
+        -> Result<&V, HashMapErr<'_, V>> {
+
+        // Counted before publishing so the cap can't be overshot by racing inserts
+        let mut slot = Reservation::new(&self.entries, self.max_entries);
+
+        self.insert_reserved(key, staged, &mut slot)
+            .map(|entry| unsafe { entry.value() })
+    }
+
+    /// Insert and return the entry now holding our value
+    fn insert_reserved<'a, F: FnOnce() -> V>(&'a self, key: usize, staged: &mut Staged<'_, V, F, A>,
+        slot: &mut Reservation<'_>) -> Result<&'a Entry<V>, HashMapErr<'a, V>> {
+        self.insert_reserved_at(self.get_idx(key), key, staged, slot)
+    }
+
+    /// `insert_reserved` into the chain of bucket `idx`
+    fn insert_reserved_at<'a, F: FnOnce() -> V>(&'a self, idx: usize, key: usize,
+        staged: &mut Staged<'_, V, F, A>, slot: &mut Reservation<'_>)
+        -> Result<&'a Entry<V>, HashMapErr<'a, V>> {
+
+        let bucket = &self.buckets()[idx];
+
+        let mut cur_entry_ptr = bucket.load(Ordering::Acquire);
+
+        // Position of cur_entry in the chain
+        let mut depth = 0;
+
+        if cur_entry_ptr.is_null() {
+            if !slot.acquire(idx) {
+                return Err(HashMapErr::HashMapFull);
+            }
+
+            let new_entry_ptr = staged.entry_ptr(idx, key, &self.free, &self.seq);
+
+            seam();
+
+            // We use CAS to place the entry if and only if the bucket is empty. Otherwise, we must
+            // handle the respective cases.
+            match bucket.compare_exchange(ptr::null_mut(), new_entry_ptr,
+                Ordering::Release,
+                Ordering::Acquire) {
+
+                Ok(_) => {
+                    staged.publish();
+
+                    slot.commit();
+
+                    // CAS suceeded, return new inserted entry reference;
+                    return Ok( unsafe { &*new_entry_ptr });
+                }
+
+                Err(head_ptr) => cur_entry_ptr = head_ptr,
+            }
+        }
+
+        // Hash Collision or Entry already existed, walk the LL
+        loop {
+            let cur_entry = unsafe { &*cur_entry_ptr };
+
+            // Check if the key matches with ours
+            if cur_entry.key == key {
+                if let Some(res) = self.insert_existing(idx, cur_entry, staged, slot) {
+                    return res;
+                }
+            }
+
+            let next_entry_ptr = cur_entry.next_ptr();
+            if !next_entry_ptr.is_null() {
+                cur_entry_ptr = next_entry_ptr;
+                depth += 1;
+                continue;
+            }
+
+            // Keys were different up to the tail, try to take it. If we lose the race
+            // we just keep walking from the entry that beat us
+            if !slot.acquire(idx) {
+                return Err(HashMapErr::HashMapFull);
+            }
+
+            let new_entry_ptr = staged.entry_ptr(idx, key, &self.free, &self.seq);
+
+            seam();
+
+            match cur_entry.try_append(new_entry_ptr) {
+                Ok(()) => {
+                    staged.publish();
+
+                    self.index_entry(idx, depth + 1, new_entry_ptr);
+
+                    slot.commit();
+
+                    self.collisions.fetch_add(1, Ordering::AcqRel);
+
+                    // CAS suceeded, return new inserted entry reference;
+                    return Ok( unsafe { &*new_entry_ptr });
+                }
+                Err(next_entry_ptr) => {
+                    cur_entry_ptr = next_entry_ptr;
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    /// Sort the entries appended past the run of the bucket into a new one,
+    /// if `entry_ptr`, just appended at chain position `depth`, closes a batch
+    #[inline]
+    fn index_entry(&self, idx: usize, depth: usize, entry_ptr: *mut Entry<V>) {
+        if let Some(index) = &self.index {
+            if index.due(depth) {
+                index.extend(&self.buckets()[idx], idx, depth, entry_ptr);
+            }
+        }
+    }
+
+    /// The key is already chained. Either return the existent value or, if the
+    /// entry was removed, move our staged value into the tombstone. `None` if
+    /// the entry is retired and the walk must go on.
+    fn insert_existing<'a, F: FnOnce() -> V>(&'a self, idx: usize, cur_entry: &'a Entry<V>,
+        staged: &mut Staged<'_, V, F, A>, slot: &mut Reservation<'_>)
+        -> Option<Result<&'a Entry<V>, HashMapErr<'a, V>>> {
+
+        loop {
+            match cur_entry.state() {
+                STATE_LIVE => {
+                    return Some(Err(HashMapErr::ExistentEntry(unsafe { cur_entry.value() })));
+                }
+
+                STATE_RETIRED => return None,
+
+                STATE_TOMBSTONE => {
+                    // Build the value before owning the slot so nobody spins on us
+                    // while user code runs
+                    staged.force();
+
+                    if !slot.acquire(idx) {
+                        return Some(Err(HashMapErr::HashMapFull));
+                    }
+
+                    seam();
+
+                    if !cur_entry.transition(STATE_TOMBSTONE, STATE_BUSY) {
+                        continue;
+                    }
+
+                    // We own the slot now
+                    let value = staged.take_value();
+                    unsafe { ptr::write(cur_entry.val.get(), ManuallyDrop::new(value)) };
+                    cur_entry.seq.store(self.seq.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+                    cur_entry.transition(STATE_BUSY, STATE_LIVE);
+
+                    slot.commit();
+
+                    return Some(Ok(cur_entry));
+                }
+
+                // Someone else is removing or reusing it, it won't take long
+                _ => spin_loop(),
+            }
+        }
+    }
+
+    /// Remove a entry from the table, returning its value.
+    ///
+    /// The entry is only marked as a tombstone, it stays in the chain and a
+    /// later `insert` of the same key reuses it. Entries are freed by
+    /// `compact` or when the map is dropped.
+    ///
+    /// The value is moved out of the entry, so it takes `&mut self`: no `&V`
+    /// handed out by `lookup`, `insert` or `iter` can outlive it. To remove
+    /// while other threads read, use `EpochHashMap`.
+    pub fn remove(&mut self, key: usize) -> Option<V> {
+
+        let cur_entry = unsafe { &*self.take_live(key, STATE_BUSY)? };
+
+        let value = unsafe { ManuallyDrop::into_inner(ptr::read(cur_entry.val.get())) };
+        cur_entry.transition(STATE_BUSY, STATE_TOMBSTONE);
+
+        Some(value)
+    }
+
+    /// Replace the value of `key`, returning the old one, or insert `value`
+    /// and return `None` if the key is not there.
+    ///
+    /// It takes `&mut self` as the old value is moved out, see `remove`.
+    /// `EpochHashMap::insert_or_replace` replaces while other threads read.
+    ///
+    /// # Panics
+    ///
+    /// If the key is not there and the map reached its `max_entries` cap.
+    pub fn insert_or_replace(&mut self, key: usize, value: V) -> Option<V> {
+        if let Some(val) = self.get_mut(key) {
+            return Some(core::mem::replace(val, value));
+        }
+
+        match self.insert(key, value) {
+            Err(HashMapErr::HashMapFull) => panic!("HashMap is full"),
+            _ => None,
+        }
+    }
+
+    /// Append an entry holding `value` for `key`, then retire the live one
+    /// it replaces and return it, or insert `value` if the key is not there.
+    ///
+    /// A concurrent `lookup` finds either the old entry or the new one, both
+    /// fully built, never a mix of the two nor a moment without the key. An
+    /// `iter` racing with it may yield the key twice. The caller is in charge
+    /// of the retired value, like with `retire`.
+    ///
+    /// Replacing a key from two threads at once leaves both new entries live,
+    /// lookups only see the first one. Nothing worse: it's memory safe.
+    ///
+    /// # Panics
+    ///
+    /// If the key is not there and the map reached its `max_entries` cap.
+    pub(crate) fn replace(&self, key: usize, value: V) -> Option<*mut Entry<V>> {
+
+        let mut staged = Staged::<V, fn() -> V, A>::ready(value, &self.alloc, self.pool.as_ref());
+
+        let old_entry = loop {
+            match self.find_entry(key) {
+                Some(old_entry) => break old_entry,
+                None => match self.insert_staged(key, &mut staged) {
+                    Ok(_) => return None,
+                    Err(HashMapErr::ExistentEntry(_)) => continue,
+                    Err(HashMapErr::HashMapFull) => panic!("HashMap is full"),
+                }
+            }
+        };
+
+        // Both entries are live for a moment, walkers stop at the old one
+        // since it comes first. Walk from the head to know the position, and
+        // to get the old entry as the chain links it, for the caller to free.
+        let idx = self.get_idx(key);
+        let new_entry_ptr = staged.entry_ptr(idx, key, &self.free, &self.seq);
+        let mut cur_entry_ptr = self.buckets()[idx].load(Ordering::Acquire);
+        let mut old_entry_ptr = ptr::null_mut();
+        let mut depth = 0;
+        loop {
+            if ptr::eq(cur_entry_ptr, old_entry) {
+                old_entry_ptr = cur_entry_ptr;
+            }
+            match unsafe { (*cur_entry_ptr).try_append(new_entry_ptr) } {
+                Ok(()) => break,
+                Err(next_entry_ptr) => cur_entry_ptr = next_entry_ptr,
+            }
+            depth += 1;
+        }
+        staged.publish();
+        self.index_entry(idx, depth + 1, new_entry_ptr);
+        self.collisions.fetch_add(1, Ordering::AcqRel);
+
+        // Removed behind our back, nothing to hand back
+        if !old_entry.transition(STATE_LIVE, STATE_RETIRED) {
+            return None;
+        }
+        Some(old_entry_ptr)
+    }
+
+    /// Free the entries left chained by `remove`, returning how many were
+    /// freed.
+    ///
+    /// Removed entries only stay around so concurrent walkers never touch
+    /// freed memory. Holding `&mut self` there are none, so the chains can be
+    /// rebuilt with just the live entries.
+    ///
+    /// Up to `N` freed entries are kept for later inserts to reuse instead of
+    /// allocating, the rest go back to the allocator.
+    pub fn compact(&mut self) -> usize {
+        self.rebuild(|_, _| true)
+    }
+
+    /// Keep only the entries for which `f` returns true, dropping the others
+    /// along with their entry. Like `compact`, removed entries are freed too.
+    ///
+    /// This is the cleanup once a concurrent fill phase is over, with no
+    /// `unsafe` needed since `&mut self` rules out any outstanding `&V`.
+    pub fn retain(&mut self, mut f: impl FnMut(usize, &V) -> bool) {
+        let before = self.entries();
+
+        let mut dropped = 0;
+        self.rebuild(|key, val| {
+            let keep = f(key, val);
+            if !keep {
+                dropped += 1;
+            }
+            keep
+        });
+
+        self.entries.set(before - dropped);
+    }
+
+    /// Drop every entry, leaving the map empty for reuse. The buckets and
+    /// the `with_*` settings are kept.
+    ///
+    /// It takes `&mut self` so no reader can be walking the freed chains. A
+    /// `&self` version could swap the bucket heads out atomically, but would
+    /// have to hold the detached chains until every reader is done, which is
+    /// what `EpochHashMap` does for removed values.
+    pub fn clear(&mut self) {
+        self.retain(|_, _| false);
+    }
+
+    /// Relink every chain with the live entries `keep` accepts, freeing the
+    /// rest. Returns how many entries were freed.
+    fn rebuild(&mut self, mut keep: impl FnMut(usize, &V) -> bool) -> usize {
+        let mut freed = 0;
+        let mut collisions = 0;
+
+        let threshold = self.index.as_ref().map(|index| index.threshold);
+        if let Some(threshold) = threshold {
+            // Rebuilt along the chains, the old one points to freed entries
+            self.index = Some(ChainIndex::new(N, threshold));
+        }
+
+        // Not tied to the borrow of self, recycle needs it
+        let buckets: *mut [Bucket<V>; N] = self.buckets_mut();
+
+        for idx in 0..N {
+            let mut tail: *mut Bucket<V> = unsafe { &mut (*buckets)[idx] };
+            let mut entry_ptr = unsafe { (*tail).read_mut() };
+            unsafe { (*tail).write_mut(ptr::null_mut()) };
+            let mut depth = 0;
+            let mut last_ptr = ptr::null_mut();
+
+            while !entry_ptr.is_null() {
+                let next_ptr = unsafe { (*entry_ptr).next_ptr() };
+
+                let live = unsafe { (*entry_ptr).state() } == STATE_LIVE;
+                if live && keep(unsafe { (*entry_ptr).key }, unsafe { (*entry_ptr).value() }) {
+                    unsafe {
+                        (*entry_ptr).next.write_mut(ptr::null_mut());
+                        (*tail).write_mut(entry_ptr);
+                        tail = &mut (*entry_ptr).next;
+                    }
+
+                    last_ptr = entry_ptr;
+                    if depth > 0 {
+                        collisions += 1;
+                    }
+                    depth += 1;
+                } else {
+                    unsafe { self.recycle(entry_ptr) };
+                    freed += 1;
+                }
+
+                entry_ptr = next_ptr;
+            }
+
+            // A single run over the whole chain past the threshold
+            if let Some(index) = &self.index {
+                if depth > index.threshold {
+                    index.extend(unsafe { &(*buckets)[idx] }, idx, depth - 1, last_ptr);
+                }
+            }
+        }
+
+        self.collisions.write_mut(collisions);
+
+        freed
+    }
+
+    /// Mark the live entry of `key` as retired. Its value stays in place, the
+    /// caller is in charge of dropping it once no reader can see it.
+    pub(crate) fn retire(&self, key: usize) -> Option<*mut Entry<V>> {
+        self.take_live(key, STATE_RETIRED)
+    }
+
+    /// Unlink a retired entry from its chain, returning whether it did. A
+    /// tail is left alone: an insert may be appending to it.
+    ///
+    /// # Safety
+    ///
+    /// `entry_ptr` is a retired entry chained in this map and no other
+    /// thread unlinks meanwhile. Walkers may still stand on it, it can only
+    /// be recycled once none can.
+    pub(crate) unsafe fn unlink(&self, entry_ptr: *mut Entry<V>) -> bool {
+        // Not the tail, so its next pointer won't change anymore
+        let next_ptr = (*entry_ptr).next_ptr();
+        if next_ptr.is_null() {
+            return false;
+        }
+
+        // Appends only change tails and nobody else unlinks, the links up
+        // to ours stay put
+        let mut link = &self.buckets()[self.get_idx((*entry_ptr).key)];
+        loop {
+            let cur = untag(link.load(Ordering::Acquire));
+            if cur == entry_ptr {
+                break;
+            }
+            link = &(*cur).next;
+        }
+
+        // The state of the entry owning the link may change meanwhile
+        let mut cur = link.load(Ordering::Acquire);
+        loop {
+            let new = next_ptr.map_addr(|addr| addr | state_of(cur));
+            match link.compare_exchange_weak(cur, new,
+                Ordering::AcqRel,
+                Ordering::Acquire) {
+                Ok(_)       => return true,
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+
+    /// Walk the chain of `key` and move its live entry to the `to` state,
+    /// updating the entries counter
+    fn take_live(&self, key: usize, to: usize) -> Option<*mut Entry<V>> {
+
+        let idx     = self.get_idx(key);
+
+        let mut entry_ptr = self.buckets()[idx].load(Ordering::Acquire);
+
+        while !entry_ptr.is_null() {
+
+            let cur_entry = unsafe { &*entry_ptr };
+
+            if cur_entry.key == key {
+                loop {
+                    match cur_entry.state() {
+                        STATE_LIVE => {
+                            seam();
+
+                            if cur_entry.transition(STATE_LIVE, to) {
+                                self.entries.sub(Counter::shard(idx, self.max_entries != usize::MAX), 1);
+                                return Some(entry_ptr);
+                            }
+                        }
+
+                        STATE_TOMBSTONE => return None,
+
+                        // A newer entry for the key may follow
+                        STATE_RETIRED => break,
+
+                        _ => spin_loop(),
+                    }
+                }
+            }
+
+            entry_ptr = cur_entry.next_ptr();
+        }
+
+        None
+    }
+
+}
+
+
+/// Where the iterator is standing
+enum IterState<'a, V> {
+    /// Load the head of `current_bucket`
+    NextBucket,
+
+    /// Yield this entry (if live) and then move to its `next`
+    InChain(&'a Entry<V>),
+
+    /// Every bucket was walked
+    Done,
+}
+
+/// Iterator over the live entries, bucket by bucket.
+///
+/// It can run while other threads insert. Entries inserted before the
+/// iterator got created, and not removed since, are always yielded. Entries
+/// inserted concurrently may or may not be, depending on whether their bucket
+/// was already walked. Either way a yielded entry was fully built before it
+/// got linked in, and entries are never freed while the map is borrowed, so
+/// the iterator never sees a torn entry nor freed memory.
+pub struct Iter<'a, V> {
+    buckets: &'a [Bucket<V>],
+    current_bucket: usize,
+    state: IterState<'a, V>,
+}
+
+impl<'a, V, const N: usize, H, A: Allocator> HashMap<V, N, H, A> {
+    pub fn iter(&'a self) -> Iter<'a, V> {
+        Iter {
+            buckets: &self.buckets()[..],
+            current_bucket: 0,
+            state: IterState::NextBucket,
+        }
+    }
+
+    pub fn keys(&'a self) -> Keys<'a, V> {
+        Keys { inner: self.iter() }
+    }
+
+    pub fn values(&'a self) -> Values<'a, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Clones of the live pairs, not tied to the map borrow.
+    ///
+    /// Weakly consistent, like `iter`: entries inserted concurrently may be
+    /// missed, but every pair is a fully built entry, never a torn one. Only
+    /// matches `entries()` while the map is quiescent.
+    pub fn snapshot(&'a self) -> Vec<(usize, V)> where V: Clone {
+        self.iter().map(|(&key, val)| (key, val.clone())).collect()
+    }
+
+    /// Live pairs in the order they got their value, oldest first, for FIFO
+    /// walks and eviction. A key removed and inserted again counts from its
+    /// last insert.
+    ///
+    /// The entries are collected and sorted up front, so this costs a walk
+    /// of the map and a `Vec` of all the entries before yielding the first.
+    /// Weakly consistent like `iter` otherwise, concurrent inserts racing
+    /// for stamps may show up in either order.
+    pub fn iter_ordered(&'a self) -> impl Iterator<Item = (&'a usize, &'a V)> {
+        let mut iter = self.iter();
+        let mut entries: Vec<_> = core::iter::from_fn(|| iter.next_entry())
+            .map(|entry| (entry.seq.load(Ordering::Relaxed), entry))
+            .collect();
+        entries.sort_unstable_by_key(|&(seq, _)| seq);
+
+        entries.into_iter()
+            .map(|(_, entry)| (&entry.key, unsafe { entry.value() }))
+    }
+
+    /// Live pairs of this map whose key isn't in `other`, e.g. to reconcile
+    /// two maps built apart. A lookup in `other` per pair, weakly consistent
+    /// like `iter` on both maps.
+    pub fn difference<V2, const M: usize, H2: IndexHasher, A2: Allocator>(&'a self,
+        other: &'a HashMap<V2, M, H2, A2>) -> impl Iterator<Item = (&'a usize, &'a V)> {
+
+        self.iter().filter(|(&key, _)| !other.contains_key(key))
+    }
+
+    /// Live pairs of this map whose key is also in `other`, see `difference`
+    pub fn intersection<V2, const M: usize, H2: IndexHasher, A2: Allocator>(&'a self,
+        other: &'a HashMap<V2, M, H2, A2>) -> impl Iterator<Item = (&'a usize, &'a V)> {
+
+        self.iter().filter(|(&key, _)| other.contains_key(key))
+    }
+}
+
+impl<'a, V, const N: usize, H, A: Allocator> IntoIterator for &'a HashMap<V, N, H, A> {
+    type Item = (&'a usize, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, V> Iter<'a, V> {
+
+    /// The next live entry, the one `next` yields the pair of
+    fn next_entry(&mut self) -> Option<&'a Entry<V>> {
+        loop {
+            match self.state {
+                IterState::InChain(entry) => {
+                    // Step first, so the state is right whatever we return
+                    let next_ptr = entry.next_ptr();
+                    self.state = if next_ptr.is_null() {
+                        // End of linked list
+                        IterState::NextBucket
+                    } else {
+                        IterState::InChain(unsafe { &*next_ptr })
+                    };
+
+                    // Skip removed entries
+                    if entry.state() == STATE_LIVE {
+                        return Some(entry);
+                    }
+                }
+
+                IterState::NextBucket => {
+                    if self.current_bucket >= self.buckets.len() {
+                        self.state = IterState::Done;
+                        continue;
+                    }
+
+                    // Move to the next bucket
+                    let bucket_ptr = self.buckets[self.current_bucket].load(Ordering::Acquire);
+                    self.current_bucket += 1;
+                    if !bucket_ptr.is_null() {
+                        self.state = IterState::InChain(unsafe { &*bucket_ptr });
+                    }
+                }
+
+                // Never left, entries inserted after the walk stay unseen
+                IterState::Done => return None,
+            }
+        }
+    }
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a usize, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry()
+            .map(|entry| (&entry.key, unsafe { entry.value() }))
+    }
+}
+
+impl<V> FusedIterator for Iter<'_, V> {}
+
+pub struct Keys<'a, V> {
+    inner: Iter<'a, V>,
+}
+
+impl<'a, V> Iterator for Keys<'a, V> {
+    type Item = &'a usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, _)| key)
+    }
+}
+
+impl<V> FusedIterator for Keys<'_, V> {}
+
+pub struct Values<'a, V> {
+    inner: Iter<'a, V>,
+}
+
+impl<'a, V> Iterator for Values<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, val)| val)
+    }
+}
+
+impl<V> FusedIterator for Values<'_, V> {}
+
+
+
+// Under `--cfg loom` only the loom tests run
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_util::DropCounter;
+
+    /// Map using the key bits as bucket index, for tests chaining keys
+    /// `k, k + N, ..` into one bucket
+    fn identity_map<V, const N: usize>() -> HashMap<V, N, IdentityHasher> {
+        HashMap::with_hasher(IdentityHasher)
+    }
+
+    /// The global allocator, counting the calls
+    #[cfg(feature = "allocator_api")]
+    #[derive(Default)]
+    struct Counting {
+        allocs  : AtomicUsize,
+    }
+
+    #[cfg(feature = "allocator_api")]
+    unsafe impl Allocator for Counting {
+        fn allocate(&self, layout: Layout) -> Result<core::ptr::NonNull<[u8]>, AllocError> {
+            self.allocs.fetch_add(1, Ordering::Relaxed);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: Layout) {
+            Global.deallocate(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_1() {
+
+        let map = HashMap::<u64, 1024>::new();
+  
+        let _ = map.insert(1337,41);
+        let _ = map.insert(1338,0);
+        let _ = map.insert(1,1337);
+        let _ = map.insert(1012312,55);
+        let _ = map.insert(11111,999999);
+
+        assert_eq!(41, *map.lookup(1337).unwrap());
+        assert_eq!(0, *map.lookup(1338).unwrap());
+        assert_eq!(1337, *map.lookup(1).unwrap());
+        assert_eq!(55, *map.lookup(1012312).unwrap());
+        assert_eq!(999999, *map.lookup(11111).unwrap());
+
+        assert_eq!(map.entries(), 5);
+    }
+
+    /// Test insertions that end up with the same index in the HashMap
+    /// This ends in linear probing internally
+    #[test]
+    fn test_2() {
+
+        let map = identity_map::<String, 8>();       
+        
+        let s1 = "first string".into();
+        let s2 = "second string".into();
+        let s3 = "third string".into();
+        let s4 = "foasodfjiosdg".into();
+        let s5 = "collision please?".into();
+        let s6 = "fasdafzxvng".into();
+        let s7 = "fixccccing".into();
+        let s8 = "fi1231211111ng".into();
+        
+        let _  = map.insert(0, s1);
+        let _  = map.insert(1, s2);
+        let _  = map.insert(16, s3);
+        let _  = map.insert(24, s4);
+        let _  = map.insert(32, s5);
+        let _  = map.insert(40, s6);
+        let _  = map.insert(48, s7);
+        let _  = map.insert(56, s8);
+
+        assert_eq!(map.lookup(0).unwrap(), "first string");
+        assert_eq!(map.lookup(32).unwrap(), "collision please?");
+
+        assert_eq!(map.entries(), 8);
+    }
+
+
+    /// Tests that an insertion with the same key returns the
+    /// first value inserted instead in a HashMapErr::ExistentEntry
+    /// Lookup returns the first value as well
+    /// Meaning the second value just doesn't get accepted in the HashMap
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn test_3() {
+
+        let map = HashMap::<String, 8>::new();       
+        
+        let s1 = "first string".into();
+        let s2 = "second string".into();
+        
+        let _  = map.insert(0, s1);
+        let v = map.insert(0, s2).err().unwrap();
+        match v {
+            HashMapErr::HashMapFull => assert!(false),
+            HashMapErr::ExistentEntry(x) => {
+                assert_eq!(x, "first string");        
+            },
+        }
+        
+        assert_eq!(map.lookup(0).unwrap(), "first string");
+
+        assert_eq!(map.entries(), 1);
+    }
+
+    /// Test map iteration
+    #[test]
+    fn test_4() {
+
+        let map = HashMap::<u64, 8>::new();       
+        
+        let _  = map.insert(0, 1337);
+        let _  = map.insert(4, 2020);
+        let _  = map.insert(8, 2023);
+        let _  = map.insert(12, 1990);        
+
+    }
+
+
+    /// Test map iteration
+    #[test]
+    fn test_5() {
+
+        let map = identity_map::<u64, 8>();       
+        
+        let _  = map.insert(0, 1337);
+        let _  = map.insert(8, 2020);
+        let _  = map.insert(16, 2023);
+        let _  = map.insert(24, 1990);        
+
+        assert_eq!(*map.lookup(24).unwrap(), 1990);
+        assert_eq!(*map.lookup(0).unwrap(), 1337);
+        assert_eq!(*map.lookup(16).unwrap(), 2023);
+        assert_eq!(*map.lookup(8).unwrap(), 2020);
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn test_6_full() {
 
         let map = HashMap::<u64, 8>::new();       
         
         let _  = map.insert(0, 1337);
-        let _  = map.insert(4, 2020);
-        let _  = map.insert(8, 2023);
-        let _  = map.insert(12, 1990);        
-        
-        //map.print_map();
+        let _  = map.insert(8, 2020);
+        let _  = map.insert(16, 2023);
+        let _  = map.insert(24, 1990);   
+        let _  = map.insert(12123, 1990);
+        let _  = map.insert(212354, 1990);
+        let _  = map.insert(2354, 1990);
+        let _  = map.insert(66345, 1990);     
+        
+        assert_eq!(map.entries(), 8);
+
+        match map.insert(0, 2222) {            
+            Err(HashMapErr::ExistentEntry(v)) => {
+                assert_eq!(1337, *v);
+            },            
+            _ => assert!(false)
+        }
+    }
+
+    #[test]
+    fn test_thrads_1() {
+
+        let map = Arc::new(HashMap::<u64, 256>::new()); 
+
+        let map_t1 = map.clone(); 
+        let map_t2 = map.clone();
+
+        let t1 = std::thread::spawn(move || {
+            let mut rng = Rng::new(789678922);
+            for _ in 0..rng.get_random(200) {
+                let _ = map_t1.insert(rng.rand(), 
+                    (rng.get_random(100000000) as u64) + 1).ok();                
+
+            }
+
+            for _ in 0..rng.get_random(200) {
+                let v = map_t1.lookup(rng.rand());
+                match v {
+                    None => {},
+                    Some(x) => assert!(*x >= 1)
+                }                
+            }
+        });
+
+
+        let t2 = std::thread::spawn(move || {
+            let mut rng = Rng::new(789678922);
+            for _ in 0..rng.get_random(200) {
+                let v = map_t2.lookup(rng.rand());
+                match v {
+                    None => {},
+                    Some(x) => assert!(*x >= 1)
+                }                
+            }
+
+            for _ in 0..rng.get_random(50) {
+                let _ = map_t2.insert(rng.rand(), 
+                    (rng.get_random(100000000) as u64) + 1).ok();                
+
+            }
+        });
+
+        let _ = t1.join();
+        let _ = t2.join();
+        
+    }
+
+    /// Two threads attempting to insert the same keys
+    #[test]
+    fn test_threads_2() {
+
+        let map = Arc::new(HashMap::<u64, 256>::new()); 
+
+        let map_t1 = map.clone(); 
+        let map_t2 = map.clone();
+
+        let t1 = std::thread::spawn(move || {
+            let mut rng = Rng::new(789678922);
+            for key in rng.sample_distinct(128, usize::MAX).unwrap() {
+                let _ = map_t1.insert(key, 
+                    (rng.get_random(100000000) as u64) + 1).ok();                
+            }
+        });
+
+        let t2 = std::thread::spawn(move || {
+            let mut rng = Rng::new(789678922);
+
+            for key in rng.sample_distinct(128, usize::MAX).unwrap() {
+                let _ = map_t2.insert(key, 
+                    (rng.get_random(100000000) as u64) + 1).ok();               
+            }
+        });
+
+        let _ = t1.join();
+        let _ = t2.join();
+
+        assert_eq!(map.entries(), 128);
+        assert_eq!(map.validate(), Ok(()));
+
+    }
+
+
+   
+
+
+
+    /// 10 threads attempting to insert the same keys
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow under miri")]
+    #[allow(unused_variables)]
+    fn test_threads_3() {
+
+        let map = Arc::new(HashMap::<u64, 2048>::new()); 
+
+        let handles: Vec<_> = (0..10).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                let mut rng = Rng::new(789678922);
+                for key in rng.sample_distinct(1024, usize::MAX).unwrap() {
+                    let _ = map_tx.insert(key, 
+                        (rng.get_random(100000000) as u64) + 1).ok();                
+
+                }          
+            })
+        }).collect();
+
+        for h in handles {
+            let _ = h.join();
+        }
+
+        assert_eq!(map.entries(), 1024);
+        assert_eq!(map.validate(), Ok(()));
+
+    }
+
+
+        /// 10 threads attempting to insert the same keys
+        #[test]
+        #[cfg_attr(miri, ignore = "too slow under miri")]
+        #[allow(unused_variables)]
+        fn test_threads_3x() {
+    
+            let map = Arc::new(HashMap::<u64, 16384>::new()); 
+    
+            let handles: Vec<_> = (0..10).map(|x| {
+                let map_tx = map.clone();
+                std::thread::spawn(move || {
+                    let mut rng = Rng::new(789678922);
+                    for _ in 0..12000 {
+                        let _ = map_tx.insert(rng.rand(), 
+                            (rng.get_random(100000000) as u64) + 1).ok();                
+    
+                    }          
+                })
+            }).collect();
+    
+            for h in handles {
+                let _ = h.join();
+            }
+    
+            assert_eq!(map.entries(), 12000);
+        }
+
+
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn test_vector_values() {
+
+        let map = HashMap::<Vec<u8>, 8>::new();       
+        
+        let _  = map.insert(0, vec![0u8, 255]);
+        let _  = map.insert(8, Vec::new());
+        let _  = map.insert(16, vec![0u8, 1u8, 1u8, 1u8, 1u8]);
+
+        let _  = map.insert(16, Vec::new());
+        let _  = map.insert(16, Vec::new());
+        let _  = map.insert(16, Vec::new());
+        
+        assert_eq!(map.entries(), 3);
+
+        match map.insert(0, Vec::new()) {            
+            Err(HashMapErr::ExistentEntry(v)) => {
+                assert_eq!(255, v[1]);
+            },            
+            _ => assert!(false)
+        }
+
+        match map.insert(16, Vec::new()) {            
+            Err(HashMapErr::ExistentEntry(v)) => {
+                assert_eq!(0, v[0]);
+            },            
+            _ => assert!(false)
+        }
+    }
+
+    #[test]
+    fn test_collisions_1() {
+        let map = identity_map::<Vec<u8>, 8>();       
+        
+        let _  = map.insert(0, vec![0u8, 255]);
+        let _  = map.insert(8, Vec::new());
+        let _  = map.insert(16, vec![0u8, 1u8, 1u8, 1u8, 1u8]);
+
+        let _  = map.insert(24, Vec::new());
+        let _  = map.insert(32, Vec::new());
+        let _  = map.insert(2, Vec::new());
+        let _  = map.insert(24, Vec::new()); // key exists already
+        
+        assert_eq!(map.entries(), 6);
+        // the first entry is not a collision
+        // THe next 4 entries collide with the entry 0
+        assert_eq!(map.collisions(), 4); 
+    }
+
+    #[test]
+    fn test_iter1() {
+        let map = identity_map::<Vec<u8>, 8>();       
+        
+        let _  = map.insert(0, vec![0u8, 255]);
+        let _  = map.insert(8, Vec::new());
+        let _  = map.insert(16, vec![0u8, 1u8, 1u8, 1u8, 1u8]);
+
+        let _  = map.insert(24, Vec::new());
+        let _  = map.insert(32, vec![0u8, 2u8, 2u8, 2u8, 2u8]);        
+        let _  = map.insert(2, Vec::new());
+        let _  = map.insert(24, Vec::new()); // key exists already
+        
+        assert_eq!(map.collisions(), 4); 
+
+        assert_eq!(6, map.iter().count())
+            
+    
+    }
+
+    #[test]
+    fn test_remove_1() {
+        let mut map = identity_map::<String, 8>();
+
+        let _  = map.insert(0, "first string".into());
+        let _  = map.insert(8, "second string".into());
+        let _  = map.insert(16, "third string".into());
+        assert_eq!(map.bucket_histogram()[0], 3);
+
+        // Remove from the middle of the chain
+        assert_eq!(map.remove(8).unwrap(), "second string");
+        assert_eq!(map.entries(), 2);
+
+        assert!(map.lookup(8).is_none());
+        assert!(map.remove(8).is_none());
+        assert!(map.remove(24).is_none());
+
+        // Walkers skip the tombstone
+        assert_eq!(map.lookup(16).unwrap(), "third string");
+        assert_eq!(map.iter().count(), 2);
+    }
+
+    /// A removed key gets its tombstoned entry reused
+    #[test]
+    fn test_remove_reinsert() {
+        let mut map = identity_map::<u64, 8>();
+
+        let _  = map.insert(0, 1337);
+        let _  = map.insert(8, 2020);
+
+        assert_eq!(map.remove(0), Some(1337));
+        assert_eq!(map.entries(), 1);
+
+        assert_eq!(*map.insert(0, 1990).ok().unwrap(), 1990);
+        assert_eq!(*map.lookup(0).unwrap(), 1990);
+        assert_eq!(map.entries(), 2);
+        // No new entry got chained
+        assert_eq!(map.collisions(), 1);
+
+        match map.insert(0, 2222) {
+            Err(HashMapErr::ExistentEntry(v)) => assert_eq!(1990, *v),
+            _ => panic!()
+        }
+    }
+
+    /// Threads racing to re-insert the same colliding keys into the
+    /// tombstones of each round of removals
+    #[test]
+    fn test_remove_threads() {
+
+        let mut map = Arc::new(identity_map::<u64, 8>());
+
+        for key in 0..16 {
+            let _ = map.insert(key * 8, 1);
+        }
+
+        for _ in 0..20 {
+            let map_mut = Arc::get_mut(&mut map).unwrap();
+            for key in 0..16 {
+                assert!(map_mut.remove(key * 8).is_some());
+            }
+
+            let handles: Vec<_> = (0..4).map(|_| {
+                let map_tx = map.clone();
+                std::thread::spawn(move || {
+                    for key in 0..16 {
+                        let _ = map_tx.insert(key * 8, 1);
+                    }
+                })
+            }).collect();
+
+            for h in handles {
+                h.join().unwrap();
+            }
+        }
+
+        assert_eq!(map.entries(), 16);
+        assert_eq!(map.iter().count(), 16);
+        assert_eq!(map.collisions(), 15);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    /// Capacities that aren't a power of two still use every bucket
+    #[test]
+    fn test_non_pow2_capacity() {
+        let map = identity_map::<u64, 1000>();
+
+        for key in 0..1000 {
+            assert!(map.insert(key, key as u64).is_ok());
+        }
+
+        for key in 0..1000 {
+            assert_eq!(*map.lookup(key).unwrap(), key as u64);
+        }
+
+        assert_eq!(map.entries(), 1000);
+        assert_eq!(map.collisions(), 0);
+
+        let mut used = [false; 1000];
+        for key in 0..1000 {
+            used[map.get_idx(key)] = true;
+        }
+        assert!(used.iter().all(|&x| x));
+    }
+
+    #[test]
+    fn test_lookup_profiled() {
+        let mut map = identity_map::<u64, 8>();
+        for key in [3, 11, 19] {
+            assert!(map.insert(key, key as u64).is_ok());
+        }
+
+        assert_eq!(map.lookup_profiled(3), (Some(&3), 1));
+        assert_eq!(map.lookup_profiled(19), (Some(&19), 3));
+        // Missing keys walk the whole chain
+        assert_eq!(map.lookup_profiled(27), (None, 3));
+        assert_eq!(map.lookup_profiled(4), (None, 0));
+
+        // A removed key stops the walk at its tombstone
+        let _ = map.remove(11);
+        assert_eq!(map.lookup_profiled(11), (None, 2));
+        assert_eq!(map.lookup_profiled(19), (Some(&19), 3));
+    }
+
+    #[test]
+    fn test_interleaved_buckets() {
+        // 125 full lines of 8 buckets on 64-bit targets, and a partial one
+        let map = Arc::new(identity_map::<u64, 1003>().with_interleaved_buckets());
+
+        // Still a bucket each, neighbours on different lines
+        let mut used = [false; 1003];
+        for key in 0..1003 {
+            used[map.get_idx(key)] = true;
+        }
+        assert!(used.iter().all(|&x| x));
+        let line = |key| map.get_idx(key) * core::mem::size_of::<Bucket<u64>>() / 64;
+        assert!((0..999).all(|key| line(key) != line(key + 1)));
+
+        let handles: Vec<_> = (0..4).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for key in 0..1000 {
+                    assert!(map_tx.insert(key * 4 + x, key as u64).is_ok());
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        for key in 0..4000 {
+            assert_eq!(map.lookup(key), Some(&(key as u64 / 4)));
+            assert_eq!(map.lookup_at(map.bucket_index(key), key), Some(&(key as u64 / 4)));
+        }
+        assert_eq!(map.lookup(4000), None);
+        assert_eq!(map.entries(), 4000);
+        assert_eq!(map.validate(), Ok(()));
+
+        let map = Arc::try_unwrap(map).ok().unwrap();
+        let copy = map.clone();
+        assert!(copy.interleave);
+        assert_eq!(copy.iter().collect::<Vec<_>>(), map.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_capacity_hint() {
+        let mut map = identity_map::<String, 8>().with_capacity_hint(4);
+        let pooled = |map: &HashMap<String, 8, IdentityHasher>, key| {
+            let entry = map.find_entry(key).unwrap();
+            map.pool.as_ref().unwrap().contains(entry)
+        };
+
+        // Bucket 3 takes its 4 slots, then spills to the allocator
+        for key in (3..48).step_by(8) {
+            assert!(map.insert(key, key.to_string()).is_ok());
+        }
+        assert!((3..32).step_by(8).all(|key| pooled(&map, key)));
+        assert!(!pooled(&map, 35) && !pooled(&map, 43));
+        // A duplicate never takes a slot
+        assert!(map.insert_with(3, || "nope".into()).is_err());
+        assert_eq!(map.pool.as_ref().unwrap().claimed(), 4);
+
+        // Freed slots go back to the bucket, not to the free list
+        for key in [11, 19, 35] {
+            let _ = map.remove(key);
+        }
+        assert_eq!(map.compact(), 3);
+        assert_eq!(map.free.len(), 1);
+        assert_eq!(map.pool.as_ref().unwrap().claimed(), 2);
+        for key in [51, 59] {
+            assert!(map.insert(key, key.to_string()).is_ok());
+            assert!(pooled(&map, key));
+        }
+        assert_eq!(map.free.len(), 1);
+
+        assert_eq!(map.lookup(43).unwrap(), "43");
+        assert_eq!(map.validate(), Ok(()));
+        let copy = map.clone();
+        assert_eq!(copy.pool.as_ref().unwrap().per_bucket(), 4);
+        assert_eq!(copy.iter().collect::<Vec<_>>(), map.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_entry_or_insert() {
+        let map = HashMap::<String, 8>::new();
+
+        assert_eq!(map.entry(3).key(), 3);
+        assert_eq!(map.entry(3).or_insert("three".to_string()), "three");
+        // There already, the default is dropped
+        assert_eq!(map.entry(3).or_insert("again".to_string()), "three");
+        assert_eq!(map.len(), 1);
+
+        let calls = AtomicUsize::new(0);
+        let build = |s: &str| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            s.to_string()
+        };
+        assert_eq!(map.entry(11).or_insert_with(|| build("eleven")), "eleven");
+        assert_eq!(map.entry(11).or_insert_with(|| build("never")), "eleven");
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_entry_and_modify() {
+        use core::sync::atomic::AtomicU64;
+
+        let map = HashMap::<AtomicU64, 8>::new();
+
+        // Not there, nothing to modify
+        let mut modified = false;
+        map.entry(5).and_modify(|_| modified = true);
+        assert!(!modified && map.is_empty());
+
+        for _ in 0..3 {
+            map.entry(5)
+                .and_modify(|count| { count.fetch_add(1, Ordering::Relaxed); })
+                .or_insert(AtomicU64::new(1));
+        }
+        assert_eq!(map.lookup(5).unwrap().load(Ordering::Relaxed), 3);
+
+        // The increment after the insert loses no count to racing threads
+        let map = Arc::new(map);
+        let handles: Vec<_> = (0..4).map(|_| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for key in 0..100 {
+                    map_tx.entry(key).or_insert_with(|| AtomicU64::new(0)).fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(map.lookup(5).unwrap().load(Ordering::Relaxed), 7);
+        assert!((0..100).filter(|&key| key != 5)
+            .all(|key| map.lookup(key).unwrap().load(Ordering::Relaxed) == 4));
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let mut map = identity_map::<String, 8>();
+        let calls = AtomicUsize::new(0);
+
+        let build = |s: &str| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            String::from(s)
+        };
+
+        assert_eq!(map.get_or_insert_with(0, || build("first string")), "first string");
+        assert_eq!(map.get_or_insert_with(8, || build("second string")), "second string");
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // Existing keys, head and chained, don't build anything
+        assert_eq!(map.get_or_insert_with(0, || build("nope")), "first string");
+        assert_eq!(map.get_or_insert_with(8, || build("nope")), "second string");
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // Reusing a tombstone builds the value once
+        let _ = map.remove(8);
+        assert_eq!(map.get_or_insert_with(8, || build("third string")), "third string");
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        assert_eq!(map.entries(), 2);
+    }
+
+    /// Threads racing on the same colliding keys agree on a single value
+    #[test]
+    fn test_get_or_insert_with_threads() {
+
+        let map = Arc::new(identity_map::<u64, 8>());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..8).map(|x| {
+            let map_tx = map.clone();
+            let calls_tx = calls.clone();
+            std::thread::spawn(move || {
+                (0..64).map(|key| {
+                    let v = map_tx.get_or_insert_with(key * 8, || {
+                        calls_tx.fetch_add(1, Ordering::Relaxed);
+                        x
+                    });
+                    v as *const u64 as usize
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        for r in &results[1..] {
+            assert_eq!(r, &results[0]);
+        }
+
+        assert_eq!(map.entries(), 64);
+        assert_eq!(map.bucket_histogram()[0], 64);
+        // Each call builds at most one value
+        assert!(calls.load(Ordering::Relaxed) <= 8 * 64);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut map = HashMap::<u64, 8>::new();
+
+        let _  = map.insert(0, 1337);
+        let _  = map.insert(8, 2020);
+        let _  = map.insert(3, 2023);
+
+        assert!(map.contains_key(0));
+        assert!(map.contains_key(8));
+        assert!(map.contains_key(3));
+        assert!(!map.contains_key(16));
+        assert!(!map.contains_key(4));
+
+        let _ = map.remove(8);
+        assert!(!map.contains_key(8));
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let map = HashMap::<u64, 8>::new();
+
+        let _  = map.insert(0, 1337);
+        let _  = map.insert(8, 2020);
+        let _  = map.insert(3, 2023);
+
+        let mut sum = 0;
+        for (_, v) in &map {
+            sum += v;
+        }
+        assert_eq!(sum, 1337 + 2020 + 2023);
+    }
+
+    /// Chains are fully walked, including the one in the last bucket
+    #[test]
+    fn test_iter_chains() {
+        let map = identity_map::<u64, 8>();
+
+        // 4-deep chains in the first and the last bucket
+        let mut expected = Vec::new();
+        for key in [0, 8, 16, 24, 7, 15, 23, 31, 1, 3, 4, 6] {
+            let _ = map.insert(key, key as u64 * 10);
+            expected.push((key, key as u64 * 10));
+        }
+        assert_eq!(map.bucket_histogram(), [4, 1, 0, 1, 1, 0, 1, 4]);
+
+        assert_eq!(map.iter().count(), map.entries());
+
+        let mut seen: Vec<_> = map.iter().map(|(k, v)| (*k, *v)).collect();
+        seen.sort();
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let map = HashMap::<u64, 8>::new();
+
+        let _  = map.insert(0, 1337);
+        let _  = map.insert(8, 2020);
+        let _  = map.insert(3, 2023);
+
+        let mut keys: Vec<_> = map.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, [0, 3, 8]);
+
+        assert_eq!(map.values().sum::<u64>(), 1337 + 2020 + 2023);
+    }
+
+    #[test]
+    fn test_len() {
+        let mut map = HashMap::<u64, 8>::new();
+        assert!(map.is_empty());
+
+        let _  = map.insert(0, 1337);
+        let _  = map.insert(8, 2020);
+        assert_eq!(map.len(), 2);
+        assert!(!map.is_empty());
+
+        let _ = map.remove(0);
+        let _ = map.remove(8);
+        assert!(map.is_empty());
+    }
+
+    /// The buckets never live on the stack, even a small thread stack
+    /// can build a huge map
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow under miri")]
+    fn test_large_map() {
+        let t = std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(|| {
+                let map = HashMap::<u64, 1_048_576>::new();
+
+                let _ = map.insert(1_048_575, 1337);
+                assert_eq!(*map.lookup(1_048_575).unwrap(), 1337);
+                assert_eq!(map.entries(), 1);
+            }).unwrap();
+
+        t.join().unwrap();
+    }
+
+    /// Pointer-like keys only differ above the low bits
+    #[test]
+    fn test_fibonacci_hasher() {
+        let identity  = identity_map::<u64, 1024>();
+        let fibonacci = HashMap::<u64, 1024, FibonacciHasher>::with_hasher(FibonacciHasher);
+
+        for key in 0..1024 {
+            let _ = identity.insert(0x7f00_0000 + key * 64, key as u64);
+            let _ = fibonacci.insert(0x7f00_0000 + key * 64, key as u64);
+        }
+
+        // Only 16 buckets are reachable with the raw bits
+        assert_eq!(identity.collisions(), 1024 - 16);
+        assert!(fibonacci.collisions() < 1024 / 4);
+
+        for key in 0..1024 {
+            assert_eq!(*fibonacci.lookup(0x7f00_0000 + key * 64).unwrap(), key as u64);
+        }
+
+        // Non power of two capacities stay in range
+        let map = HashMap::<u64, 1000, FibonacciHasher>::with_hasher(FibonacciHasher);
+        for key in 0..5000 {
+            assert!(map.get_idx(key * 4096) < 1000);
+        }
+    }
+
+    #[test]
+    fn test_load_factor() {
+        let map = HashMap::<u64, 8>::new();
+        assert_eq!(map.capacity(), 8);
+        assert_eq!(map.load_factor(), 0.0);
+
+        for key in 0..6 {
+            let _ = map.insert(key, 1);
+        }
+        assert_eq!(map.load_factor(), 0.75);
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let mut map = identity_map::<u64, 8>().with_max_entries(10);
+
+        for key in (0..7).chain(8..11) {
+            assert!(map.insert(key, 1).is_ok());
+        }
+
+        // Head of an empty bucket and tail of a chain both refuse
+        assert_eq!(map.bucket_histogram()[7], 0);
+        assert!(matches!(map.insert(15, 1), Err(HashMapErr::HashMapFull)));
+        assert!(matches!(map.insert(16, 1), Err(HashMapErr::HashMapFull)));
+
+        // Existent keys are still reported as such
+        assert!(matches!(map.insert(3, 2), Err(HashMapErr::ExistentEntry(_))));
+        assert_eq!(map.entries(), 10);
+
+        // A removal frees a slot
+        let _ = map.remove(3);
+        assert!(map.insert(16, 1).is_ok());
+        assert!(matches!(map.insert(3, 1), Err(HashMapErr::HashMapFull)));
+    }
+
+    #[test]
+    fn test_max_entries_threads() {
+        let map = Arc::new(HashMap::<u64, 64>::new().with_max_entries(100));
+
+        let handles: Vec<_> = (0..8).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for key in 0..100 {
+                    let _ = map_tx.insert(key * 8 + x, 1);
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            let _ = h.join();
+        }
+
+        assert_eq!(map.entries(), 100);
+        assert_eq!(map.iter().count(), 100);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    /// The shards add up to the successful inserts, whichever shards they
+    /// counted the entries in
+    #[test]
+    fn test_sharded_entries() {
+        let map = Arc::new(HashMap::<u64, 64>::new());
+
+        // Overlapping keys, every thread returning the ones it got
+        let handles: Vec<_> = (0..4).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                (0..200).map(|key| key * 3 + x)
+                    .filter(|&key| map_tx.insert(key, 1).is_ok())
+                    .collect::<Vec<_>>()
+            })
+        }).collect();
+        let keys: Vec<_> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(map.entries(), keys.len());
+        assert_eq!(map.iter().count(), keys.len());
+        assert_eq!(map.validate(), Ok(()));
+
+        // Removed from the shards of the buckets, whichever counted them
+        let mut map = Arc::try_unwrap(map).ok().unwrap();
+        for &key in keys.iter().filter(|&&key| key % 5 == 0) {
+            assert!(map.remove(key).is_some());
+        }
+        let inserted = keys.iter().filter(|&&key| key % 5 != 0).count();
+        assert_eq!(map.entries(), inserted);
+        assert_eq!(map.iter().count(), inserted);
+
+        // Counted by a batch in shard 0, removed from the shard of the bucket
+        let pairs: Vec<_> = (1000..1100).map(|key| (key, 0)).collect();
+        assert_eq!(map.load_copy(&pairs, true), 100);
+        for key in 1000..1050 {
+            assert!(map.remove(key).is_some());
+        }
+        assert_eq!(map.entries(), inserted + 50);
+
+        // The cap sees every shard
+        let map = map.with_max_entries(inserted + 60);
+        for key in 2000..2020 {
+            assert_eq!(map.insert(key, 0).is_ok(), key < 2010);
+        }
+        assert_eq!(map.entries(), inserted + 60);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    /// Entries retired, like `EpochHashMap::remove` does, while the batch
+    /// that linked them hasn't counted them yet
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow under miri")]
+    fn test_entries_remove_in_flight_batch() {
+        let map = Arc::new(HashMap::<u64, 64>::new());
+        let done = Arc::new(AtomicUsize::new(0));
+
+        let map_tx = map.clone();
+        let done_tx = done.clone();
+        let loader = std::thread::spawn(move || {
+            for round in 0..200 {
+                let pairs: Vec<_> = (0..64).map(|key| (round * 64 + key, 0)).collect();
+                assert!(map_tx.insert_many(pairs).iter().all(|res| res.is_ok()));
+            }
+            done_tx.store(1, Ordering::Release);
+        });
+
+        let map_tx = map.clone();
+        let done_tx = done.clone();
+        let remover = std::thread::spawn(move || {
+            let mut removed = 0;
+            while done_tx.load(Ordering::Acquire) == 0 {
+                for key in 0..200 * 64 {
+                    removed += map_tx.retire(key).is_some() as usize;
+                }
+            }
+            removed
+        });
+
+        while done.load(Ordering::Acquire) == 0 {
+            assert!(map.entries() <= 200 * 64);
+            assert!(map.len() <= 200 * 64);
+        }
+
+        loader.join().unwrap();
+        let removed = remover.join().unwrap();
+        assert_eq!(map.entries(), 200 * 64 - removed);
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut map = HashMap::<String, 8>::new();
+
+        for key in [0, 8, 16, 3, 5] {
+            let _ = map.insert(key, format!("value {}", key));
+        }
+
+        let copy = map.clone();
+        assert_eq!(copy.entries(), 5);
+        assert_eq!(copy.collisions(), map.collisions());
+
+        for key in [0, 8, 16, 3, 5] {
+            assert_eq!(copy.lookup(key), map.lookup(key));
+            // Different allocations
+            assert!(!ptr::eq(copy.lookup(key).unwrap(), map.lookup(key).unwrap()));
+        }
+
+        let _ = copy.insert(24, "only in the copy".into());
+        assert_eq!(map.remove(8).unwrap(), "value 8");
+
+        assert!(map.lookup(24).is_none());
+        assert_eq!(copy.lookup(8).unwrap(), "value 8");
+        assert_eq!(map.entries(), 4);
+        assert_eq!(copy.entries(), 6);
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let map: HashMap<u64, 1024> = (0..100).map(|key| (key, key as u64 * 2)).collect();
+
+        assert_eq!(map.entries(), 100);
+        assert_eq!(*map.lookup(99).unwrap(), 198);
+
+        // First wins
+        let map: HashMap<u64, 8> = vec![(0, 1337), (8, 2020), (0, 2222)].into_iter().collect();
+        assert_eq!(map.entries(), 2);
+        assert_eq!(*map.lookup(0).unwrap(), 1337);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut map = HashMap::<u64, 8>::new();
+        let _ = map.insert(0, 1337);
+
+        map.extend(vec![(0, 2222), (8, 2020), (3, 2023), (8, 1)]);
+
+        assert_eq!(map.entries(), 3);
+        assert_eq!(*map.lookup(0).unwrap(), 1337);
+        assert_eq!(*map.lookup(8).unwrap(), 2020);
+        assert_eq!(*map.lookup(3).unwrap(), 2023);
+    }
+
+    #[test]
+    fn test_bucket_histogram() {
+        let mut map = identity_map::<u64, 8>();
+
+        for key in [0, 8, 16, 24, 32, 3, 5, 13] {
+            let _ = map.insert(key, 1);
+        }
+
+        assert_eq!(map.bucket_histogram(), [5, 0, 0, 1, 0, 2, 0, 0]);
+
+        let _ = map.remove(16);
+        assert_eq!(map.bucket_histogram()[0], 5);
+    }
+
+    /// Threads racing to insert the same colliding keys, in different orders
+    #[test]
+    fn test_collisions_threads() {
+
+        let map = Arc::new(identity_map::<u64, 8>());
+
+        // 64 keys in bucket 0, 32 in bucket 1, one in bucket 2
+        let mut keys: Vec<usize> = (0..64).map(|k| k * 8)
+            .chain((0..32).map(|k| k * 8 + 1))
+            .chain([2])
+            .collect();
+
+        let handles: Vec<_> = (0..8).map(|x| {
+            let map_tx = map.clone();
+            keys.rotate_left(x * 11);
+            let keys = keys.clone();
+            std::thread::spawn(move || {
+                for key in keys {
+                    let _ = map_tx.insert(key, 1);
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            let _ = h.join();
+        }
+
+        assert_eq!(map.entries(), 97);
+        assert_eq!(map.collisions(), 63 + 31);
+
+        let chained: usize = map.bucket_histogram().iter()
+            .map(|len| len.saturating_sub(1)).sum();
+        assert_eq!(map.collisions(), chained);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow under miri")]
+    fn test_prefault() {
+        let map = HashMap::<u64, 1_048_576>::new();
+        let _ = map.insert(0, 1337);
+
+        map.prefault();
+
+        assert_eq!(*map.lookup(0).unwrap(), 1337);
+        assert_eq!(map.entries(), 1);
+    }
+
+    #[test]
+    fn test_insert_many() {
+        let map = HashMap::<u64, 256>::new();
+
+        let res = map.insert_many((0..1000).map(|key| (key, key as u64)));
+        assert!(res.iter().all(|r| r.is_ok()));
+        assert_eq!(map.entries(), 1000);
+
+        // Duplicates are reported in order and not counted
+        let res = map.insert_many([(5, 0), (1000, 1000), (5, 0)]);
+        assert!(matches!(res[0], Err(HashMapErr::ExistentEntry(&5))));
+        assert!(res[1].is_ok());
+        assert!(matches!(res[2], Err(HashMapErr::ExistentEntry(&5))));
+        assert_eq!(map.entries(), 1001);
+
+        // Capped maps still count per insert
+        let map = HashMap::<u64, 256>::new().with_max_entries(10);
+        let res = map.insert_many((0..20).map(|key| (key, key as u64)));
+        assert_eq!(res.iter().filter(|r| r.is_ok()).count(), 10);
+        assert!(matches!(res[10], Err(HashMapErr::HashMapFull)));
+        assert_eq!(map.entries(), 10);
+    }
+
+    #[test]
+    fn test_get_cloned() {
+        let map = HashMap::<String, 8>::new();
+        let _ = map.insert(0, "first string".into());
+
+        let v = map.get_cloned(0).unwrap();
+        assert!(map.get_cloned(8).is_none());
+        drop(map);
+
+        assert_eq!(v, "first string");
+    }
+
+    #[test]
+    fn test_try_insert() {
+        let map = HashMap::<u64, 8>::new();
+
+        let (inserted, v) = map.try_insert(3, 30);
+        assert!(inserted);
+        assert_eq!(*v, 30);
+
+        let (inserted, v) = map.try_insert(3, 31);
+        assert!(!inserted);
+        assert_eq!(*v, 30);
+
+        assert_eq!(map.entries(), 1);
+    }
+
+    #[test]
+    fn test_try_insert_threads() {
+        let map = Arc::new(HashMap::<u64, 16>::new());
+
+        let handles: Vec<_> = (0..8u64).map(|t| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                (0..64).filter(|&key| map_tx.try_insert(key, t).0).count()
+            })
+        }).collect();
+
+        let won: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+        // Exactly one thread wins every key, and everybody agrees on its value
+        assert_eq!(won, 64);
+        for key in 0..64 {
+            let v = *map.lookup(key).unwrap();
+            assert_eq!(map.try_insert(key, 99), (false, &v));
+        }
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    /// Neither a zero key or value nor the tagged null left behind by
+    /// removing a tail entry may be mistaken for an empty slot
+    #[test]
+    fn test_null_handling() {
+        let mut map = identity_map::<u64, 8>();
+
+        let _ = map.insert(0, 0);
+        let _ = map.insert(8, 0);
+        assert_eq!(*map.lookup(0).unwrap(), 0);
+        assert_eq!(*map.lookup(8).unwrap(), 0);
+
+        // The tail now holds STATE_TOMBSTONE over a null next
+        assert_eq!(map.remove(8), Some(0));
+        assert!(map.lookup(8).is_none());
+
+        assert!(map.insert(16, 16).is_ok());
+        assert_eq!(*map.lookup(16).unwrap(), 16);
+        assert_eq!(*map.lookup(0).unwrap(), 0);
+        assert_eq!(map.bucket_histogram()[0], 3);
+        assert_eq!(map.collisions(), 2);
+    }
+
+    #[test]
+    fn test_insert_or_replace() {
+        let mut map = identity_map::<String, 8>();
+
+        assert!(map.insert_or_replace(0, "first".into()).is_none());
+        let _ = map.insert(8, "collision".into());
+
+        assert_eq!(map.insert_or_replace(0, "second".into()).unwrap(), "first");
+        assert_eq!(map.insert_or_replace(0, "third".into()).unwrap(), "second");
+
+        assert_eq!(map.lookup(0).unwrap(), "third");
+        assert_eq!(map.lookup(8).unwrap(), "collision");
+        assert_eq!(map.entries(), 2);
+        assert_eq!(map.iter().count(), 2);
+
+        // Replaced in place, removal and insertion see a single entry
+        assert_eq!(map.remove(0).unwrap(), "third");
+        assert!(map.insert(0, "fourth".into()).is_ok());
+        assert_eq!(map.lookup(0).unwrap(), "fourth");
+        assert_eq!(map.entries(), 2);
+    }
+
+    /// Every value is dropped exactly once, replaced ones by the caller
+    #[test]
+    fn test_insert_or_replace_drops() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut map = HashMap::<DropCounter, 8>::new();
+
+        for _ in 0..4 {
+            drop(map.insert_or_replace(3, DropCounter::new(&drops)));
+        }
+        assert_eq!(drops.load(Ordering::Relaxed), 3);
+
+        drop(map);
+        assert_eq!(drops.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_probe_stats() {
+        let mut map = identity_map::<u64, 8>();
+        assert_eq!(map.max_probe(), 0);
+        assert_eq!(map.avg_probe(), 0.0);
+
+        for key in 0..8 {
+            let _ = map.insert(key, key as u64);
+        }
+        assert_eq!(map.max_probe(), 0);
+
+        // Run of collisions on the first bucket
+        for i in 1..=4 {
+            let _ = map.insert(i * 8, 0);
+            assert_eq!(map.max_probe(), i);
+        }
+        assert_eq!(map.avg_probe(), 10.0 / 12.0);
+
+        // Removed entries are still walked past
+        let _ = map.remove(8);
+        assert_eq!(map.max_probe(), 4);
+        assert_eq!(map.avg_probe(), 9.0 / 11.0);
+    }
+
+    #[test]
+    fn test_chain_index() {
+        let plain   = identity_map::<u64, 8>();
+        let indexed = identity_map::<u64, 8>().with_chain_index(8);
+
+        // Every key lands in bucket 0
+        for i in 0..64 {
+            let _ = plain.insert(i * 8, i as u64);
+            let _ = indexed.insert(i * 8, i as u64);
+        }
+
+        for i in 0..64 {
+            assert_eq!(*indexed.lookup(i * 8).unwrap(), i as u64);
+        }
+        assert!(indexed.lookup(64 * 8).is_none());
+
+        let mut probes = 0;
+        assert!(plain.find_entry_counting(63 * 8, &mut probes).is_some());
+        assert_eq!(probes, 64);
+
+        // The threshold walk, a binary search of the 56 keys past it, the entry
+        let mut probes = 0;
+        assert!(indexed.find_entry_counting(63 * 8, &mut probes).is_some());
+        assert_eq!(probes, 8 + 6 + 1);
+
+        let worst = (0..64).map(|i| {
+            let mut probes = 0;
+            indexed.find_entry_counting(i * 8, &mut probes);
+            probes
+        }).max().unwrap();
+        assert_eq!(worst, 8 + 6 + 1);
+
+        // Short of a batch, the last keys are walked past the search
+        for i in 64..68 {
+            let _ = indexed.insert(i * 8, i as u64);
+        }
+        let mut probes = 0;
+        assert!(indexed.find_entry_counting(67 * 8, &mut probes).is_some());
+        assert!(probes <= 8 + 6 + 4, "{} probes", probes);
+        let mut probes = 0;
+        assert!(indexed.find_entry_counting(100 * 8, &mut probes).is_none());
+        assert!(probes <= 8 + 6 + 4, "{} probes", probes);
+
+        // The next batch goes into the array
+        for i in 68..72 {
+            let _ = indexed.insert(i * 8, i as u64);
+        }
+        let mut probes = 0;
+        assert!(indexed.find_entry_counting(67 * 8, &mut probes).is_some());
+        assert_eq!(probes, 8 + 6 + 1);
+        for i in 0..72 {
+            assert_eq!(*indexed.lookup(i * 8).unwrap(), i as u64);
+        }
+    }
+
+    /// Removal, reuse and replacement of indexed entries
+    #[test]
+    fn test_chain_index_updates() {
+        let mut map = identity_map::<u64, 8>().with_chain_index(2);
+
+        // All in bucket 0, most of them past the threshold
+        for i in 0..16 {
+            let _ = map.insert(i * 8, i as u64);
+        }
+        assert_eq!(map.bucket_histogram()[0], 16);
+
+        assert_eq!(map.remove(10 * 8), Some(10));
+        assert!(map.lookup(10 * 8).is_none());
+        assert!(map.insert(10 * 8, 100).is_ok());
+        assert_eq!(*map.lookup(10 * 8).unwrap(), 100);
+
+        assert_eq!(map.insert_or_replace(12 * 8, 120), Some(12));
+        assert_eq!(*map.lookup(12 * 8).unwrap(), 120);
+        assert_eq!(map.insert_or_replace(8, 10), Some(1));
+        assert_eq!(*map.lookup(8).unwrap(), 10);
+
+        assert_eq!(map.entries(), 16);
+        let copy = map.clone();
+        for i in 0..16 {
+            assert_eq!(map.lookup(i * 8), copy.lookup(i * 8));
+        }
+    }
+
+    #[test]
+    fn test_chain_index_threads() {
+        let map = Arc::new(HashMap::<u64, 4>::new().with_chain_index(4));
+
+        let handles: Vec<_> = (0..8).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                // Overlapping ranges, every key is raced by two threads
+                for i in (x * 32)..(x * 32 + 64) {
+                    let _ = map_tx.insert(i * 4, i as u64);
+                    assert_eq!(*map_tx.lookup(i * 4).unwrap(), i as u64);
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(map.entries(), 288);
+        for i in 0..288 {
+            assert_eq!(*map.lookup(i * 4).unwrap(), i as u64);
+        }
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_compact() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut map = identity_map::<DropCounter, 16>().with_chain_index(2);
+
+        for key in 0..100 {
+            let _ = map.insert(key, DropCounter::new(&drops));
+        }
+        for key in (0..100).step_by(2) {
+            drop(map.remove(key));
+        }
+        assert_eq!(drops.load(Ordering::Relaxed), 50);
+        assert_eq!(map.bucket_histogram().iter().sum::<usize>(), 100);
+
+        assert_eq!(map.compact(), 50);
+        assert_eq!(map.compact(), 0);
+
+        // Only the live entries are left, none of their values got dropped
+        assert_eq!(drops.load(Ordering::Relaxed), 50);
+        assert_eq!(map.bucket_histogram().iter().sum::<usize>(), 50);
+        assert_eq!(map.entries(), 50);
+        assert_eq!(map.collisions(), 50 - 8);
+        for key in 0..100 {
+            assert_eq!(map.contains_key(key), key % 2 == 1);
+        }
+
+        // The chains still take new entries
+        assert!(map.insert(0, DropCounter::new(&drops)).is_ok());
+        assert!(map.contains_key(0));
+
+        drop(map);
+        assert_eq!(drops.load(Ordering::Relaxed), 101);
+    }
+
+    #[test]
+    fn test_dump() {
+        let map = identity_map::<u64, 16>();
+        for key in [3, 10, 21] {
+            assert!(map.insert(key, 0).is_ok());
+        }
+
+        let mut out = String::new();
+        assert!(map.dump(&mut out).is_ok());
+        assert_eq!(out.lines().count(), 16);
+        assert_eq!(out.matches("NULL").count(), 13);
+        for (idx, key) in [(3, 3), (10, 10), (5, 21)] {
+            let line = out.lines().find(|line| line.starts_with(&format!("Idx:[{:x}:", idx))).unwrap();
+            assert!(line.ends_with(&format!(" -> {}", key)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut map = HashMap::<String, 8>::new();
+        assert_eq!(format!("{:?}", map), "{}");
+
+        let _ = map.insert(0, "first string".into());
+        let _ = map.insert(8, "second string".into());
+
+        let s = format!("{:?}", map);
+        assert!(s.contains("0: \"first string\""), "{}", s);
+        assert!(s.contains("8: \"second string\""), "{}", s);
+
+        // Removed entries don't show up
+        let _ = map.remove(8);
+        assert_eq!(format!("{:?}", map), "{0: \"first string\"}");
+
+        // Big maps are cut short
+        let map: HashMap<u64, 256> = (0..1000).map(|key| (key, key as u64)).collect();
+        let s = format!("{:?}", map);
+        assert!(s.ends_with(", ..}"), "{}", s);
+        assert_eq!(s.matches(':').count(), DEBUG_MAX_ENTRIES);
+    }
+
+    /// Iterating while another thread inserts only yields inserted keys,
+    /// each with its own value, and every key inserted beforehand
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow under miri")]
+    fn test_iter_concurrent_insert() {
+        let map = Arc::new(identity_map::<u64, 64>());
+
+        // Bucket 0 gets a long chain that keeps growing under the reader
+        for key in 0..256 {
+            let _ = map.insert(key * 64, key as u64 * 64 + 1);
+        }
+
+        let map_tx = map.clone();
+        let writer = std::thread::spawn(move || {
+            for key in 256..4096 {
+                let key = if key % 2 == 0 { key * 64 } else { key };
+                let _ = map_tx.insert(key, key as u64 + 1);
+            }
+        });
+
+        while !writer.is_finished() {
+            let mut before = 0;
+            for (key, val) in map.iter() {
+                assert_eq!(*val, *key as u64 + 1);
+                if key % 64 == 0 && key / 64 < 256 {
+                    before += 1;
+                }
+            }
+            assert_eq!(before, 256);
+        }
+
+        writer.join().unwrap();
+        assert_eq!(map.iter().count(), map.entries());
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_seeded_hasher() {
+        let map1 = HashMap::<u64, 1024>::new_with_seed(1337);
+        let map2 = HashMap::<u64, 1024>::new_with_seed(1338);
+
+        // Keys an adversary would pick against the identity placement
+        let keys: Vec<usize> = (0..1024).map(|key| key * 1024).collect();
+        for &key in &keys {
+            let _ = map1.insert(key, key as u64);
+            let _ = map2.insert(key, key as u64);
+        }
+
+        assert!(map1.collisions() < 1024 / 2);
+        assert!(map2.collisions() < 1024 / 2);
+        for &key in &keys {
+            assert_eq!(*map1.lookup(key).unwrap(), key as u64);
+        }
+
+        let idx1: Vec<_> = keys.iter().map(|&key| map1.get_idx(key)).collect();
+        let idx2: Vec<_> = keys.iter().map(|&key| map2.get_idx(key)).collect();
+        assert_ne!(idx1, idx2);
+        let same = idx1.iter().zip(&idx2).filter(|(a, b)| a == b).count();
+        assert!(same < 1024 / 16, "{} keys in the same bucket", same);
+
+        // Same seed, same placement
+        let map3 = HashMap::<u64, 1024>::new_with_seed(1337);
+        assert!(keys.iter().all(|&key| map3.get_idx(key) == map1.get_idx(key)));
+        assert!(map3.get_idx(usize::MAX) < 1024);
+    }
+
+    /// Small enough for miri: threads racing on a bucket head, on the tail
+    /// of a chain and on the reuse of the same tombstones
+    #[test]
+    fn test_concurrent_insert_paths() {
+        let mut map = HashMap::<u64, 4>::new().with_chain_index(2);
+
+        for key in 0..8 {
+            let _ = map.insert(key * 4, 0);
+        }
+        for key in 0..8 {
+            let _ = map.remove(key * 4);
+        }
+        let map = Arc::new(map);
+
+        let handles: Vec<_> = (0..4u64).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for key in 0..16 {
+                    let v = *map_tx.get_or_insert_with(key, || x);
+                    assert_eq!(*map_tx.lookup(key).unwrap(), v);
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(map.entries(), 16);
+        assert_eq!(map.iter().count(), 16);
+        // 0, 4, 8 and 12 reused their tombstones, the rest got appended
+        assert_eq!(map.bucket_histogram().iter().sum::<usize>(), 8 + 12);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_should_grow() {
+        let map = HashMap::<u64, 8>::new();
+        for key in 0..8 {
+            let _ = map.insert(key, key as u64);
+        }
+        assert!(!map.should_grow());
+        let _ = map.insert(8, 8);
+        assert!(map.should_grow());
+
+        let map = HashMap::<u64, 8>::new().with_grow_threshold(0.5);
+        for key in 0..4 {
+            let _ = map.insert(key, key as u64);
+        }
+        assert!(!map.should_grow());
+        let _ = map.insert(4, 4);
+        assert!(map.should_grow());
+        assert!(map.clone().should_grow());
+    }
+
+    #[test]
+    fn test_migrate_into() {
+        let mut small = HashMap::<String, 8>::new();
+        for key in 0..32 {
+            let _ = small.insert(key, format!("value {}", key));
+        }
+        let _ = small.remove(5);
+        assert!(small.should_grow());
+
+        let big = HashMap::<String, 64, FibonacciHasher>::with_hasher(FibonacciHasher);
+        let _ = big.insert(7, "already there".into());
+
+        assert_eq!(small.migrate_into(&big), 30);
+        assert_eq!(big.entries(), 31);
+        assert!(!big.should_grow());
+
+        assert!(big.lookup(5).is_none());
+        assert_eq!(big.lookup(7).unwrap(), "already there");
+        for key in (0..32).filter(|&key| key != 5 && key != 7) {
+            assert_eq!(big.lookup(key), small.lookup(key));
+        }
+    }
+
+    #[test]
+    fn test_retain() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut map = HashMap::<DropCounter, 16>::new().with_chain_index(2);
+
+        for key in 0..100 {
+            let _ = map.insert(key, DropCounter(drops.clone(), key * 10));
+        }
+        drop(map.remove(99));
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+
+        map.retain(|key, val| {
+            assert_eq!(val.1, key * 10);
+            key % 3 == 0
+        });
+
+        // 33 values dropped besides the one removed, 66 dropped by retain
+        assert_eq!(drops.load(Ordering::Relaxed), 1 + 66);
+        assert_eq!(map.entries(), 33);
+        assert_eq!(map.bucket_histogram().iter().sum::<usize>(), 33);
+        for key in 0..100 {
+            match map.lookup(key) {
+                Some(val) => assert_eq!(val.1, key * 10),
+                None      => assert!(key % 3 != 0 || key == 99),
+            }
+        }
+        assert_eq!(map.iter().count(), 33);
+
+        drop(map);
+        assert_eq!(drops.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn test_insert_handle() {
+        let map = identity_map::<String, 8>();
+
+        let handle = map.insert_handle(3, "first string".into()).ok().unwrap();
+        assert_eq!(handle.key(), 3);
+        assert_eq!(handle.value(), "first string");
+        assert_eq!(handle.bucket(), 3);
+        assert!(ptr::eq(handle.value(), map.lookup(3).unwrap()));
+
+        let handle = map.insert_handle(11, "second string".into()).ok().unwrap();
+        assert_eq!(handle.value(), "second string");
+        assert_eq!(handle.bucket(), 3);
+        assert_eq!(map.collisions(), 1);
+
+        match map.insert_handle(3, "nope".into()) {
+            Err(HashMapErr::ExistentEntry(v)) => assert_eq!(v, "first string"),
+            _ => panic!()
+        }
+        assert_eq!(map.entries(), 2);
+    }
+
+    /// After the threads are joined the counter matches both the inserts
+    /// that reported success and what iteration sees
+    #[test]
+    fn test_entries_threads() {
+        let map = Arc::new(HashMap::<u64, 64>::new());
+        let inserted = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..8).map(|x| {
+            let map_tx = map.clone();
+            let inserted_tx = inserted.clone();
+            std::thread::spawn(move || {
+                let mut rng = Rng::new(1337 + x);
+                for _ in 0..500 {
+                    if map_tx.insert(rng.get_random(2000), 1).is_ok() {
+                        inserted_tx.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                let batch = (0..100).map(|_| (rng.get_random(2000), 1));
+                let ok = map_tx.insert_many(batch).iter().filter(|r| r.is_ok()).count();
+                inserted_tx.fetch_add(ok, Ordering::Relaxed);
+            })
+        }).collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(map.entries(), inserted.load(Ordering::Relaxed));
+        assert_eq!(map.entries(), map.iter().count());
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow under miri")]
+    fn test_counters_after_join() {
+        let map = Arc::new(identity_map::<u64, 16>());
+
+        // Disjoint keys, 64 per thread, 4 threads per bucket
+        let handles: Vec<_> = (0..64).map(|x| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for i in 0..64 {
+                    assert!(map_tx.insert(i * 64 + x, 1).is_ok());
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(map.entries(), 64 * 64);
+        assert_eq!(map.collisions(), 64 * 64 - 16);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut map = HashMap::<String, 8>::new().with_max_entries(16);
+
+        for key in 0..16 {
+            let _ = map.insert(key, format!("value {}", key));
+        }
+        let _ = map.remove(3);
+
+        map.clear();
+        assert_eq!(map.entries(), 0);
+        assert_eq!(map.collisions(), 0);
+        assert_eq!(map.bucket_histogram(), [0; 8]);
+        assert_eq!(map.iter().count(), 0);
+        for key in 0..16 {
+            assert!(map.lookup(key).is_none());
+        }
+
+        // Reusable, with the cap still in place
+        for key in 0..16 {
+            assert!(map.insert(key, "again".into()).is_ok());
+        }
+        assert!(matches!(map.insert(16, "full".into()), Err(HashMapErr::HashMapFull)));
+        assert_eq!(map.lookup(3).unwrap(), "again");
+    }
+
+    #[test]
+    fn test_free_buckets() {
+        let map = identity_map::<u64, 8>();
+        assert_eq!(map.occupied_buckets(), 0);
+        assert_eq!(map.next_free_from(5), Some(5));
+
+        for key in [0, 1, 2, 5, 6, 7] {
+            let _ = map.insert(key, 1);
+        }
+        assert_eq!(map.occupied_buckets(), map.entries());
+        assert_eq!(map.next_free_from(0), Some(3));
+        assert_eq!(map.next_free_from(4), Some(4));
+        // Wraps around
+        assert_eq!(map.next_free_from(5), Some(3));
+        assert_eq!(map.next_free_from(13), Some(3));
+
+        // Chained keys don't take new buckets
+        let _ = map.insert(8, 1);
+        assert_eq!(map.occupied_buckets(), 6);
+
+        let _ = map.insert(3, 1);
+        let _ = map.insert(4, 1);
+        assert_eq!(map.occupied_buckets(), 8);
+        assert_eq!(map.next_free_from(0), None);
+    }
+
+    #[test]
+    fn test_new_const() {
+        static MAP: StaticHashMap<u64, 1024> = HashMap::new_const();
+
+        let handles: Vec<_> = (0..4).map(|x| {
+            std::thread::spawn(move || {
+                for key in 0..256 {
+                    let _ = MAP.insert(key * 4 + x, key as u64);
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(MAP.entries(), 1024);
+        assert_eq!(*MAP.lookup(1023).unwrap(), 255);
+        assert_eq!(MAP.collisions(), MAP.collision_estimate(&(0..1024).collect::<Vec<_>>()));
+
+        // Never touched, nothing to free
+        let map = HashMap::<u64, 8>::new_const();
+        assert!(map.is_empty());
+        drop(map);
+
+        let mut map = HashMap::<u64, 8>::new_const();
+        map.clear();
+        assert!(map.insert(3, 3).is_ok());
+        assert_eq!(map.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_with_value_mut() {
+        use core::sync::atomic::AtomicU64;
+
+        let map = Arc::new(HashMap::<AtomicU64, 8>::new());
+        for key in 0..4 {
+            let _ = map.insert(key * 8, AtomicU64::new(0));
+        }
+
+        let handles: Vec<_> = (0..4).map(|_| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for i in 0..1000 {
+                    map_tx.with_value_mut((i % 4) * 8, |v| v.fetch_add(1, Ordering::Relaxed));
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let total: u64 = map.values().map(|v| v.load(Ordering::Relaxed)).sum();
+        assert_eq!(total, 4000);
+        assert_eq!(map.lookup(8).unwrap().load(Ordering::Relaxed), 1000);
+        assert!(map.with_value_mut(1, |_| ()).is_none());
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut map = HashMap::<String, 8>::new();
+        let _ = map.insert(0, "first".into());
+        let _ = map.insert(8, "second".into());
+
+        map.get_mut(8).unwrap().push_str(" string");
+        assert_eq!(map.lookup(8).unwrap(), "second string");
+        assert!(map.get_mut(16).is_none());
+
+        let _ = map.remove(0);
+        assert!(map.get_mut(0).is_none());
+    }
+
+    /// `std::error::Error` is only implemented with `std`
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_insert_error() {
+        use std::error::Error;
+
+        fn fill(map: &HashMap<u64, 8>, keys: &[usize]) -> Result<(), Box<dyn Error>> {
+            for &key in keys {
+                map.try_insert_owned(key, key as u64 * 10)?;
+            }
+            Ok(())
+        }
+
+        let map = HashMap::<u64, 8>::new().with_max_entries(4);
+        assert!(fill(&map, &[0, 1, 2]).is_ok());
+
+        let err = fill(&map, &[3, 1]).err().unwrap();
+        assert_eq!(err.to_string(), "key already in the HashMap");
+        assert_eq!(err.downcast_ref::<InsertError<u64>>(), Some(&InsertError::ExistentEntry(10)));
+
+        let err = fill(&map, &[4]).err().unwrap();
+        assert_eq!(err.to_string(), "HashMap is full");
+
+        // The borrowing error displays the same
+        assert_eq!(map.insert(0, 1).err().unwrap().to_string(), "key already in the HashMap");
+    }
+
+    #[test]
+    fn test_default() {
+        #[derive(Default)]
+        struct Holder {
+            map: HashMap<u64, 8>,
+        }
+
+        let holder = Holder::default();
+        assert!(holder.map.is_empty());
+        assert!(holder.map.insert(3, 30).is_ok());
+        assert_eq!(holder.map.lookup(3), Some(&30));
+
+        let map: HashMap<u64, 8> = Default::default();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_lookup_bounded() {
+        let map = identity_map::<u64, 8>();
+
+        // Every key lands in bucket 0
+        for i in 0..64 {
+            let _ = map.insert(i * 8, i as u64);
+        }
+
+        assert_eq!(map.lookup_bounded(63 * 8, 64), Ok(Some(&63)));
+        assert_eq!(map.lookup_bounded(63 * 8, 63), Err(TooManyProbes));
+        assert_eq!(map.lookup(63 * 8), Some(&63));
+
+        assert_eq!(map.lookup_bounded(2 * 8, 3), Ok(Some(&2)));
+        assert_eq!(map.lookup_bounded(1, 1), Ok(None));
+        // Missing from a long chain, the whole chain has to be walked
+        assert_eq!(map.lookup_bounded(64 * 8, 63), Err(TooManyProbes));
+        assert_eq!(map.lookup_bounded(64 * 8, 64), Ok(None));
+
+        // The index cuts the walk short
+        let indexed = identity_map::<u64, 8>().with_chain_index(8);
+        for i in 0..64 {
+            let _ = indexed.insert(i * 8, i as u64);
+        }
+        assert_eq!(indexed.lookup_bounded(63 * 8, 8 + 6 + 1), Ok(Some(&63)));
+        assert_eq!(indexed.lookup_bounded(63 * 8, 8), Err(TooManyProbes));
+    }
+
+    /// Key and link first, see `Entry`
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_entry_layout() {
+        use core::mem::{offset_of, size_of};
+
+        assert_eq!(size_of::<Entry<u64>>(), 32);
+        assert_eq!(size_of::<Entry<u8>>(), 32);
+        assert_eq!(size_of::<Entry<()>>(), 24);
+        assert_eq!(size_of::<Entry<[u8; 64]>>(), 88);
+
+        assert_eq!(offset_of!(Entry<u64>, key), 0);
+        assert_eq!(offset_of!(Entry<u64>, next), 8);
+        assert_eq!(offset_of!(Entry<u64>, seq), 16);
+        assert_eq!(offset_of!(Entry<u64>, val), 24);
+        assert_eq!(offset_of!(Entry<[u8; 64]>, next), 8);
+    }
+
+    #[test]
+    fn test_iter_ordered() {
+        let mut map = HashMap::<u64, 16>::new();
+        let mut rng = Rng::new(789678922);
+
+        // Scattered over the buckets and chains, in a known order
+        let keys = rng.sample_distinct(100, 1000).unwrap();
+        for &key in &keys {
+            let _ = map.insert(key, key as u64);
+        }
+        let ordered: Vec<_> = map.iter_ordered().map(|(&key, _)| key).collect();
+        assert_eq!(ordered, keys);
+        assert!(map.iter_ordered().all(|(&key, &val)| val == key as u64));
+
+        // Back in the same tombstone, but last in the order
+        assert_eq!(map.remove(keys[0]), Some(keys[0] as u64));
+        assert!(map.insert(keys[0], 0).is_ok());
+        let ordered: Vec<_> = map.iter_ordered().map(|(&key, _)| key).collect();
+        assert_eq!(ordered[..99], keys[1..]);
+        assert_eq!(ordered[99], keys[0]);
+
+        // Compacting and cloning keep the order
+        let _ = map.remove(keys[1]);
+        map.compact();
+        let clone = map.clone();
+        assert!(map.iter_ordered().eq(clone.iter_ordered()));
+        assert_eq!(clone.iter_ordered().next(), Some((&keys[2], &(keys[2] as u64))));
+    }
+
+    #[test]
+    fn test_insert_if() {
+        let map = HashMap::<u64, 64>::new();
+
+        // B depends on A, which isn't there yet
+        assert!(map.insert_if(2, 20, |map| map.contains_key(1)).is_none());
+        assert!(!map.contains_key(2));
+        assert_eq!(map.entries(), 0);
+
+        assert!(map.insert(1, 10).is_ok());
+        assert!(matches!(map.insert_if(2, 20, |map| map.contains_key(1)), Some(Ok(&20))));
+        assert!(matches!(map.insert_if(2, 21, |map| map.contains_key(1)), Some(Err(HashMapErr::ExistentEntry(&20)))));
+        assert_eq!(map.entries(), 2);
+    }
+
+    #[test]
+    fn test_lookup_at() {
+        let map = HashMap::<u64, 64>::new_with_seed(1337);
+
+        // Hash once, look up and insert on a miss
+        for key in (0..300).chain(0..300) {
+            let idx = map.bucket_index(key);
+            if map.lookup_at(idx, key).is_none() {
+                assert!(map.insert_at(idx, key, key as u64).is_ok());
+            }
+        }
+        assert_eq!(map.entries(), 300);
+
+        for key in 0..600 {
+            assert_eq!(map.lookup_at(map.bucket_index(key), key), map.lookup(key));
+        }
+        assert!(matches!(map.insert_at(map.bucket_index(7), 7, 0), Err(HashMapErr::ExistentEntry(&7))));
+
+        // The wrong bucket is a miss
+        let idx = map.bucket_index(7);
+        assert_eq!(map.lookup_at((idx + 1) % 64, 7), None);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "key inserted out of its bucket")]
+    fn test_insert_at_wrong_bucket() {
+        let map = HashMap::<u64, 64>::new();
+        let _ = map.insert_at(3, 4, 0);
+    }
+
+    #[test]
+    fn test_lookup_many() {
+        let map = HashMap::<u64, 64>::new_with_seed(1337);
+        let mut rng = Rng::new(789678922);
+
+        for key in rng.sample_distinct(200, 1000).unwrap() {
+            let _ = map.insert(key, key as u64 * 3);
+        }
+
+        // Present and missing keys, more than a batch, with repeats
+        let keys: Vec<_> = (0..1000).chain(0..37).collect();
+        let mut out = std::vec![None; keys.len()];
+        map.lookup_many(&keys, &mut out);
+
+        for (&key, found) in keys.iter().zip(&out) {
+            assert_eq!(*found, map.lookup(key));
+        }
+        assert_eq!(out[..1000].iter().flatten().count(), 200);
+
+        // Lazily allocated table, nothing inserted yet
+        let empty = HashMap::<u64, 64>::new_const();
+        let mut out = [Some(&0); 3];
+        empty.lookup_many(&[1, 2, 3], &mut out);
+        assert_eq!(out, [None; 3]);
+    }
+
+    /// Long chains with removed entries, the walk prefetches ahead with the
+    /// `prefetch` feature and must find the same values as without
+    #[test]
+    fn test_lookup_prefetch() {
+        let mut map = HashMap::<u64, 16>::new();
+
+        for key in 0..512 {
+            let _ = map.insert(key, key as u64 + 1);
+        }
+        for key in (0..512).step_by(3) {
+            assert_eq!(map.remove(key), Some(key as u64 + 1));
+        }
+
+        for key in 0..1024 {
+            let expected = (key < 512 && key % 3 != 0).then_some(key as u64 + 1);
+            assert_eq!(map.lookup(key).copied(), expected);
+        }
+        assert_eq!(map.entries(), 512 - 171);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "one output slot per key")]
+    fn test_lookup_many_len() {
+        let map = HashMap::<u64, 64>::new();
+        map.lookup_many(&[1, 2], &mut [None]);
+    }
+
+    #[test]
+    fn test_lookup_present() {
+        let mut map = HashMap::<u64, 64>::new();
+        for key in (0..100).step_by(3) {
+            assert!(map.insert(key, key as u64 * 10).is_ok());
+        }
+        let _ = map.remove(9);
+
+        // Past a batch, unsorted, a duplicate and a removed key
+        let keys: Vec<usize> = (0..40).rev().chain([3, 1000, 9]).collect();
+        let expected: Vec<_> = keys.iter()
+            .filter(|&&key| key % 3 == 0 && key != 9)
+            .map(|&key| (key, key as u64 * 10))
+            .collect();
+
+        let present: Vec<_> = map.lookup_present(&keys).into_iter().map(|(key, val)| (key, *val)).collect();
+        assert_eq!(present, expected);
+        assert_eq!(present.last(), Some(&(3, 30)));
+        assert!(map.lookup_present(&[1, 2, 4]).is_empty());
+        assert!(map.lookup_present(&[]).is_empty());
+    }
+
+    /// Compiles only if `T` is not `Sync`: with a `Sync` type both impls
+    /// apply and `_` can't be inferred
+    trait AmbiguousIfSync<A> {
+        fn some_item() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSync<()> for T {}
+    impl<T: ?Sized + Sync> AmbiguousIfSync<u8> for T {}
+
+    /// The same for `Send`
+    trait AmbiguousIfSend<A> {
+        fn some_item() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSend<()> for T {}
+    impl<T: ?Sized + Send> AmbiguousIfSend<u8> for T {}
+
+    #[test]
+    fn test_send_sync() {
+        use std::{cell::Cell, rc::Rc};
+
+        fn send_sync<T: Send + Sync>() {}
+        fn send<T: Send>() {}
+
+        send_sync::<HashMap<u64, 8>>();
+        send_sync::<HashMap<Arc<String>, 8, SeededHasher>>();
+        send_sync::<EpochHashMap<u64, 8>>();
+        send_sync::<GrowableHashMap<u64>>();
+
+        // Can move to another thread but not be shared
+        send::<HashMap<Cell<u8>, 8>>();
+        <HashMap<Cell<u8>, 8> as AmbiguousIfSync<_>>::some_item();
+
+        // Neither, which the atomic fields alone would allow
+        <HashMap<Rc<u8>, 8> as AmbiguousIfSync<_>>::some_item();
+        <HashMap<Rc<u8>, 8> as AmbiguousIfSend<_>>::some_item();
+        <EpochHashMap<Rc<u8>, 8> as AmbiguousIfSync<_>>::some_item();
+        <GrowableHashMap<Rc<u8>> as AmbiguousIfSync<_>>::some_item();
+        <GrowableHashMap<Rc<u8>> as AmbiguousIfSend<_>>::some_item();
+        <LocalHashMap<u64, 8> as AmbiguousIfSync<_>>::some_item();
+
+        #[cfg(feature = "std")]
+        {
+            send_sync::<KeyedHashMap<String, u64, 8>>();
+            <KeyedHashMap<Rc<u8>, u64, 8> as AmbiguousIfSync<_>>::some_item();
+        }
+    }
+
+    /// Drops a counter and panics if armed
+    struct Bomb {
+        armed: bool,
+        drops: Arc<AtomicUsize>,
+    }
+
+    impl Drop for Bomb {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+            if self.armed {
+                panic!("bomb dropped");
+            }
+        }
+    }
+
+    #[test]
+    fn test_insert_panic_safety() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = HashMap::<Bomb, 8>::new().with_max_entries(2);
+        assert!(map.insert(1, Bomb { armed: false, drops: drops.clone() }).is_ok());
+
+        // The losing value blows up as the staged entry is freed
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let _ = map.insert(1, Bomb { armed: true, drops: drops.clone() });
+        }));
+        assert!(res.is_err());
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        assert_eq!(map.entries(), 1);
+        assert!(!map.lookup(1).unwrap().armed);
+
+        // The closure blows up, the reserved slot is given back
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            map.get_or_insert_with(2, || panic!("no value"));
+        }));
+        assert!(res.is_err());
+        assert_eq!(map.entries(), 1);
+        assert!(map.lookup(2).is_none());
+
+        // Still room for the cap's last entry
+        assert!(map.insert(2, Bomb { armed: false, drops: drops.clone() }).is_ok());
+        assert_eq!(map.entries(), 2);
 
+        drop(map);
+        assert_eq!(drops.load(Ordering::Relaxed), 3);
     }
 
+    #[test]
+    fn test_insert_outcome() {
+        let map = HashMap::<u64, 8>::new().with_max_entries(2);
+
+        match map.insert_outcome(1, 10) {
+            Ok(Insertion::Inserted(v)) => assert_eq!(*v, 10),
+            _ => panic!(),
+        }
+        match map.insert_outcome(1, 11) {
+            Ok(Insertion::Existing(v)) => assert_eq!(*v, 10),
+            _ => panic!(),
+        }
+
+        let fresh = map.insert_outcome(9, 90).unwrap();
+        assert!(fresh.is_inserted());
+        assert_eq!(fresh.value(), &90);
+        let dup = map.insert_outcome(9, 91).unwrap();
+        assert!(!dup.is_inserted());
+        assert_eq!(dup.value(), &90);
+
+        // A duplicate is fine on a full map, a new key is not
+        assert!(map.insert_outcome(1, 12).is_ok());
+        assert!(matches!(map.insert_outcome(2, 20), Err(HashMapErr::HashMapFull)));
+        assert_eq!(map.entries(), 2);
+    }
 
-    /// Test map iteration
     #[test]
-    fn test_5() {
+    fn test_snapshot() {
+        let mut map = HashMap::<String, 8>::new();
+        for key in 0..40 {
+            let _ = map.insert(key, format!("value {}", key));
+        }
+        let _ = map.remove(3);
 
-        let map = HashMap::<u64, 8>::new();       
-        
-        let _  = map.insert(0, 1337);
-        let _  = map.insert(8, 2020);
-        let _  = map.insert(16, 2023);
-        let _  = map.insert(24, 1990);        
-        
-        //map.print_map();
+        let mut pairs = map.snapshot();
+        assert_eq!(pairs.len(), map.entries());
 
-        assert_eq!(*map.lookup(24).unwrap(), 1990);
-        assert_eq!(*map.lookup(0).unwrap(), 1337);
-        assert_eq!(*map.lookup(16).unwrap(), 2023);
-        assert_eq!(*map.lookup(8).unwrap(), 2020);
+        // Owned, outlives the map
+        drop(map);
+        pairs.sort();
+        assert_eq!(pairs[3], (4, "value 4".to_string()));
     }
 
     #[test]
-    fn test_6_full() {
+    #[cfg(target_pointer_width = "64")]
+    #[cfg_attr(miri, ignore = "miri stops at an allocation it can't serve")]
+    fn test_try_new() {
+        let map = HashMap::<u64, 64>::try_new().unwrap();
+        assert!(map.insert(1, 1).is_ok());
+
+        // Just under 128 TiB of buckets, more than a 47 bit address space
+        // can map whatever the overcommit policy
+        assert!(matches!(HashMap::<u64, { (1 << 44) - 1 }>::try_new(), Err(AllocError)));
+    }
 
-        let map = HashMap::<u64, 8>::new();       
-        
-        let _  = map.insert(0, 1337);
-        let _  = map.insert(8, 2020);
-        let _  = map.insert(16, 2023);
-        let _  = map.insert(24, 1990);   
-        let _  = map.insert(12123, 1990);
-        let _  = map.insert(212354, 1990);
-        let _  = map.insert(2354, 1990);
-        let _  = map.insert(66345, 1990);     
-        
-        assert_eq!(map.entries(), 8);
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn test_try_new_in() {
+        use core::alloc::Layout;
+        use core::ptr::NonNull;
 
-        match map.insert(0, 2222) {            
-            Err(HashMapErr::ExistentEntry(v)) => {
-                assert_eq!(1337, *v);
-            },            
-            _ => assert!(false)
+        /// Out of memory from the start
+        struct Failing;
+
+        unsafe impl Allocator for Failing {
+            fn allocate(&self, _layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                Err(AllocError)
+            }
+
+            unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
+                unreachable!("nothing was allocated");
+            }
         }
+
+        assert!(matches!(HashMap::<u64, 64, FibonacciHasher, Failing>::try_new_in(Failing), Err(AllocError)));
+        assert!(HashMap::<u64, 64, FibonacciHasher, Global>::try_new_in(Global).is_ok());
     }
 
+    #[cfg(feature = "allocator_api")]
     #[test]
-    fn test_thrads_1() {
+    fn test_capacity_hint_allocations() {
+        let counting = Counting::default();
+        let map = HashMap::<String, 64, IdentityHasher, &Counting>::with_hasher_in(IdentityHasher, &counting).with_capacity_hint(2);
+        // The bucket array and the slots
+        assert_eq!(counting.allocs.load(Ordering::Relaxed), 2);
+
+        // Heads and first collisions fill the slots
+        for key in 0..128 {
+            assert!(map.insert(key, format!("value {}", key)).is_ok());
+        }
+        assert_eq!(counting.allocs.load(Ordering::Relaxed), 2);
 
-        let map = Arc::new(HashMap::<u64, 256>::new()); 
+        assert!(map.insert(128, "spilled".into()).is_ok());
+        assert_eq!(counting.allocs.load(Ordering::Relaxed), 3);
+        assert_eq!(map.lookup(64).unwrap(), "value 64");
+    }
 
-        let map_t1 = map.clone(); 
-        let map_t2 = map.clone();
+    /// Duplicates are found walking the chain, before their entry is boxed
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn test_duplicate_insert_allocations() {
+        let counting = Counting::default();
+        let map = HashMap::<String, 16, FibonacciHasher, &Counting>::new_in(&counting);
+        for key in 0..64 {
+            assert!(map.insert(key, format!("value {}", key)).is_ok());
+        }
+        // The bucket array and an entry per key
+        assert_eq!(counting.allocs.load(Ordering::Relaxed), 65);
+
+        for key in 0..64 {
+            assert!(map.insert(key, String::new()).is_err());
+            assert!(map.insert_with(key, || unreachable!()).is_err());
+            assert!(!map.try_insert(key, String::new()).0);
+            assert_eq!(map.get_or_insert_with(key, || unreachable!()), &format!("value {}", key));
+        }
+        assert!(map.insert_many((0..64).map(|key| (key, String::new()))).iter().all(Result::is_err));
+        assert_eq!(counting.allocs.load(Ordering::Relaxed), 65);
+        assert_eq!(map.entries(), 64);
+    }
 
-        let t1 = std::thread::spawn(move || {
-            let mut rng = Rng::new(789678922);
-            for _ in 0..rng.get_random(200) {
-                let _ = map_t1.insert(rng.rand(), 
-                    (rng.get_random(100000000) as u64) + 1).ok();                
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn test_allocator() {
+        use core::alloc::Layout;
+        use core::ptr::NonNull;
+
+        /// Hands out memory from a fixed arena, counting what's live
+        struct Bump {
+            arena   : *mut [u8],
+            used    : AtomicUsize,
+            allocs  : AtomicUsize,
+            live    : AtomicUsize,
+        }
 
+        unsafe impl Sync for Bump {}
+
+        unsafe impl Allocator for Bump {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                let base = self.arena as *mut u8;
+                let mut start = 0;
+                self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    start = (base.addr() + used).next_multiple_of(layout.align()) - base.addr();
+                    (start + layout.size() <= self.arena.len()).then_some(start + layout.size())
+                }).map_err(|_| AllocError)?;
+
+                self.allocs.fetch_add(1, Ordering::Relaxed);
+                self.live.fetch_add(1, Ordering::Relaxed);
+                let raw = unsafe { NonNull::new_unchecked(base.add(start)) };
+                Ok(NonNull::slice_from_raw_parts(raw, layout.size()))
             }
 
-            for _ in 0..rng.get_random(200) {
-                let v = map_t1.lookup(rng.rand());
-                match v {
-                    None => {},
-                    Some(x) => assert!(*x >= 1)
-                }                
+            unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
+                // Bump allocators only free everything at once
+                self.live.fetch_sub(1, Ordering::Relaxed);
             }
-        });
-
+        }
 
-        let t2 = std::thread::spawn(move || {
-            let mut rng = Rng::new(789678922);
-            for _ in 0..rng.get_random(200) {
-                let v = map_t2.lookup(rng.rand());
-                match v {
-                    None => {},
-                    Some(x) => assert!(*x >= 1)
-                }                
+        let arena = Box::into_raw(vec![0u8; 1 << 16].into_boxed_slice());
+        let bump = Bump {
+            arena,
+            used:   AtomicUsize::new(0),
+            allocs: AtomicUsize::new(0),
+            live:   AtomicUsize::new(0),
+        };
+
+        {
+            let mut map = HashMap::<String, 64, FibonacciHasher, &Bump>::new_in(&bump);
+            // The bucket array
+            assert_eq!(bump.allocs.load(Ordering::Relaxed), 1);
+
+            for key in 0..100 {
+                assert!(map.insert(key, format!("value {}", key)).is_ok());
             }
+            // A duplicate is found before its entry gets allocated
+            assert!(map.insert_with(1, || "nope".into()).is_err());
+            assert_eq!(bump.live.load(Ordering::Relaxed), 101);
 
-            for _ in 0..rng.get_random(50) {
-                let _ = map_t2.insert(rng.rand(), 
-                    (rng.get_random(100000000) as u64) + 1).ok();                
+            for key in 0..10 {
+                let _ = map.remove(key);
+            }
+            assert_eq!(map.compact(), 10);
+            assert_eq!(map.lookup(50).unwrap(), "value 50");
 
+            // Freed entries are kept for the next inserts
+            for key in 100..110 {
+                assert!(map.insert(key, format!("value {}", key)).is_ok());
             }
-        });
+            assert_eq!(bump.live.load(Ordering::Relaxed), 101);
+        }
 
-        let _ = t1.join();
-        let _ = t2.join();
+        // Every allocation went through the arena and came back
+        assert_eq!(bump.allocs.load(Ordering::Relaxed), 101);
+        assert_eq!(bump.live.load(Ordering::Relaxed), 0);
 
-        //map.print_map();
-        
+        drop(unsafe { Box::from_raw(arena) });
     }
 
-    /// Two threads attempting to insert the same keys
     #[test]
-    fn test_threads_2() {
+    fn test_bucket_index() {
+        let map = identity_map::<u64, 8>();
 
-        let map = Arc::new(HashMap::<u64, 256>::new()); 
+        // Same low bits, same bucket
+        assert_eq!(map.bucket_index(3), 3);
+        assert_eq!(map.bucket_index(3 + 8), map.bucket_index(3));
+        assert_ne!(map.bucket_index(4), map.bucket_index(3));
 
-        let map_t1 = map.clone(); 
-        let map_t2 = map.clone();
+        let handle = map.insert_handle(3 + 8 * 5, 0).unwrap();
+        assert_eq!(handle.bucket(), map.bucket_index(3));
 
-        let t1 = std::thread::spawn(move || {
-            let mut rng = Rng::new(789678922);
-            for _ in 0..128 {
-                let _ = map_t1.insert(rng.rand(), 
-                    (rng.get_random(100000000) as u64) + 1).ok();                
-            }
-        });
+        let seeded = HashMap::<u64, 8>::new_with_seed(7);
+        assert!((0..64).all(|key| seeded.bucket_index(key) < 8));
+    }
 
-        let t2 = std::thread::spawn(move || {
-            let mut rng = Rng::new(789678922);
+    #[test]
+    fn test_free_list() {
+        let mut map = HashMap::<String, 64>::new();
 
-            for _ in 0..128 {
-                let _ = map_t2.insert(rng.rand(), 
-                    (rng.get_random(100000000) as u64) + 1).ok();               
-            }
-        });
+        let addrs = |map: &HashMap<String, 64>, keys: core::ops::Range<usize>| -> Vec<usize> {
+            let mut addrs: Vec<_> = keys.map(|key| map.lookup(key).unwrap() as *const String as usize).collect();
+            addrs.sort();
+            addrs
+        };
 
-        let _ = t1.join();
-        let _ = t2.join();
+        for key in 0..40 {
+            let _ = map.insert(key, format!("value {}", key));
+        }
+        let first = addrs(&map, 0..40);
 
-        //map.print_map();
+        // Every cycle reuses the entries the previous one freed
+        for cycle in 1..5 {
+            map.clear();
+            assert!(map.is_empty());
+            assert_eq!(map.free.len(), 40);
 
-        assert_eq!(map.entries(), 128);
+            for key in 0..40 {
+                let key = key + cycle * 100;
+                assert!(map.insert(key, format!("value {}", key)).is_ok());
+            }
+            assert_eq!(map.free.len(), 0);
+            assert_eq!(addrs(&map, cycle * 100..cycle * 100 + 40), first);
+            assert_eq!(map.lookup(cycle * 100 + 7).unwrap(), &format!("value {}", cycle * 100 + 7));
+        }
 
-    }
+        // Threads racing for the freed entries
+        map.clear();
+        let map = Arc::new(map);
+        let handles: Vec<_> = (0..4).map(|t| {
+            let map_tx = map.clone();
+            std::thread::spawn(move || {
+                for key in 0..20 {
+                    let key = 500 + t * 20 + key;
+                    assert!(map_tx.insert(key, format!("value {}", key)).is_ok());
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!((500..580).all(|key| map.lookup(key).unwrap() == &format!("value {}", key)));
+        assert_eq!(map.free.len(), 0);
+        let mut map = Arc::into_inner(map).unwrap();
 
+        // Only a bucket's worth is kept
+        for key in 0..100 {
+            let _ = map.insert(1000 + key, String::new());
+        }
+        map.clear();
+        assert_eq!(map.free.len(), 64);
+    }
 
-   
+    #[test]
+    fn test_load_copy() {
+        let pairs: Vec<_> = (0..10_000).map(|key| (key * 3, key as u64)).collect();
+
+        for assume_unique in [false, true] {
+            let mut map = HashMap::<u64, 1024>::new();
+            assert_eq!(map.load_copy(&pairs, assume_unique), 10_000);
+            assert_eq!(map.entries(), 10_000);
+            assert_eq!(map.iter().count(), 10_000);
+            for &(key, val) in &pairs {
+                assert_eq!(map.lookup(key), Some(&val));
+            }
+            assert_eq!(map.lookup(1), None);
+            // Only the head of every chain isn't a collision
+            assert_eq!(map.collisions(), 10_000 - 1024);
+        }
 
+        // The cap holds on the fast path too
+        let mut capped = HashMap::<u64, 64>::new().with_max_entries(100);
+        assert_eq!(capped.load_copy(&pairs, true), 100);
+        assert_eq!(capped.entries(), 100);
 
+        // First wins
+        let mut map = HashMap::<u64, 8>::new();
+        let _ = map.insert(5, 0);
+        assert_eq!(map.load_copy(&[(1, 1), (2, 2), (1, 3), (5, 5), (2, 4)], false), 2);
+        assert_eq!(map.entries(), 3);
+        assert_eq!(map.lookup(1), Some(&1));
+        assert_eq!(map.lookup(2), Some(&2));
+        assert_eq!(map.lookup(5), Some(&0));
+    }
 
-    /// 10 threads attempting to insert the same keys
     #[test]
-    fn test_threads_3() {
+    fn test_iter_fused() {
+        let map = HashMap::<u64, 8>::new();
+        for key in 0..20 {
+            let _ = map.insert(key, key as u64);
+        }
 
-        let map = Arc::new(HashMap::<u64, 2048>::new()); 
+        let mut iter = map.iter();
+        assert_eq!(iter.by_ref().count(), 20);
+        for _ in 0..5 {
+            assert!(iter.next().is_none());
+        }
 
-        let handles: Vec<_> = (0..10).map(|x| {
-            let map_tx = map.clone();
-            std::thread::spawn(move || {
-                let mut rng = Rng::new(789678922);
-                for _ in 0..1024 {
-                    let _ = map_tx.insert(rng.rand(), 
-                        (rng.get_random(100000000) as u64) + 1).ok();                
+        // Not even a new entry in a bucket already walked wakes it up
+        let _ = map.insert(100, 0);
+        assert!(iter.next().is_none());
 
-                }          
-            })
-        }).collect();
+        let mut keys = map.keys();
+        keys.by_ref().for_each(drop);
+        assert!(keys.next().is_none() && keys.next().is_none());
+    }
 
-        for h in handles {
-            let _ = h.join();
+    #[test]
+    fn test_validate() {
+        let mut map = identity_map::<u64, 8>();
+        for key in 0..40 {
+            let _ = map.insert(key, key as u64);
         }
+        let _ = map.remove(3);
+        let _ = map.insert_or_replace(4, 0);
+        assert_eq!(map.validate(), Ok(()));
 
-        assert_eq!(map.entries(), 1024);
-        // if map.entries() > 1024 {
-        //     map.print_map()
-        // }
+        let head_ptr = map.buckets_mut()[1].load(Ordering::Relaxed);
+        let second_ptr = unsafe { (*head_ptr).next_ptr() };
 
+        // A chain looping back to its head
+        let mut tail_ptr = head_ptr;
+        while !unsafe { (*tail_ptr).next_ptr() }.is_null() {
+            tail_ptr = unsafe { (*tail_ptr).next_ptr() };
+        }
+        unsafe { (*tail_ptr).next.store(head_ptr, Ordering::Relaxed) };
+        assert_eq!(map.validate(), Err("bucket 1: the chain loops".into()));
+        unsafe { (*tail_ptr).next.store(ptr::null_mut(), Ordering::Relaxed) };
+
+        // Two live entries for a key
+        unsafe { (*second_ptr).key = 1 };
+        assert_eq!(map.validate(), Err("bucket 1: key 1 chained twice".into()));
+        unsafe { (*second_ptr).key = 9 };
+
+        // An entry in the wrong bucket
+        unsafe { (*second_ptr).key = 2 };
+        assert!(map.validate().unwrap_err().contains("belongs to bucket 2"));
+        unsafe { (*second_ptr).key = 9 };
+
+        // A counter out of sync
+        map.entries.add(5, 1);
+        assert_eq!(map.validate(), Err("39 live entries counted as 40".into()));
+        map.entries.sub(5, 1);
+
+        assert_eq!(map.validate(), Ok(()));
     }
 
-
-        /// 10 threads attempting to insert the same keys
-        #[test]
-        fn test_threads_3x() {
-    
-            let map = Arc::new(HashMap::<u64, 16384>::new()); 
-    
-            let handles: Vec<_> = (0..10).map(|x| {
-                let map_tx = map.clone();
-                std::thread::spawn(move || {
-                    let mut rng = Rng::new(789678922);
-                    for _ in 0..12000 {
-                        let _ = map_tx.insert(rng.rand(), 
-                            (rng.get_random(100000000) as u64) + 1).ok();                
-    
-                    }          
-                })
-            }).collect();
-    
-            for h in handles {
-                let _ = h.join();
+    /// Addresses are only reused across a `&mut` borrow, so a thread holding
+    /// an entry pointer never sees its address come back as another entry
+    #[test]
+    fn test_no_address_reuse_while_shared() {
+        let mut map = HashMap::<u64, 8>::new();
+        let mut seen = std::collections::HashSet::new();
+
+        for round in 0..8 {
+            for key in 0..32 {
+                let handle = map.insert_handle(round * 100 + key, 0).unwrap();
+                assert!(seen.insert(handle.value() as *const u64 as usize));
+            }
+            for key in 0..32 {
+                let _ = map.remove(round * 100 + key);
             }
-    
-            assert_eq!(map.entries(), 12000);
         }
+        assert_eq!(seen.len(), 8 * 32);
 
+        // Now they can come back
+        map.compact();
+        let reused = map.insert_handle(12345, 0).unwrap().value() as *const u64 as usize;
+        assert!(seen.contains(&reused));
+    }
 
     #[test]
-    fn test_vector_values() {
+    fn test_merge() {
+        let mut total = HashMap::<u64, 16>::new();
+        let worker = HashMap::<u64, 16>::new();
 
-        let map = HashMap::<Vec<u8>, 8>::new();       
-        
-        let _  = map.insert(0, vec![0u8, 255]);
-        let _  = map.insert(8, Vec::new());
-        let _  = map.insert(16, vec![0u8, 1u8, 1u8, 1u8, 1u8]);
+        for key in 0..20 {
+            let _ = total.insert(key, 1);
+        }
+        for key in 10..30 {
+            let _ = worker.insert(key, 10);
+        }
 
-        let _  = map.insert(16, Vec::new());
-        let _  = map.insert(16, Vec::new());
-        let _  = map.insert(16, Vec::new());
-        
-        assert_eq!(map.entries(), 3);
+        assert_eq!(total.merge(&worker, |ours, theirs| ours + theirs), 20);
+        assert_eq!(total.entries(), 30);
+        for key in 0..30 {
+            let expected = match key {
+                0..10  => 1,
+                10..20 => 11,
+                _      => 10,
+            };
+            assert_eq!(total.lookup(key), Some(&expected));
+        }
+        // Only read
+        assert_eq!(worker.entries(), 20);
+
+        // New keys stop at the cap, combined ones don't count against it
+        let mut capped = HashMap::<u64, 16>::new().with_max_entries(25);
+        assert_eq!(capped.merge(&total, |a, _| *a), 25);
+        assert_eq!(capped.merge(&total, |a, b| a + b), 25);
+        assert_eq!(capped.entries(), 25);
+        assert!(capped.iter().all(|(key, v)| *v == 2 * total.lookup(*key).unwrap()));
+    }
 
-        match map.insert(0, Vec::new()) {            
-            Err(HashMapErr::ExistentEntry(v)) => {
-                assert_eq!(255, v[1]);
-            },            
-            _ => assert!(false)
+    #[test]
+    fn test_collision_estimate() {
+        // Arithmetic progression of step N, the worst case of the identity
+        let keys: Vec<usize> = (0..1024).map(|key| key * 1024).collect();
+
+        let identity = identity_map::<u64, 1024>();
+        let fibonacci = HashMap::<u64, 1024>::new();
+        assert_eq!(identity.collision_estimate(&keys), 1023);
+        assert!(fibonacci.collision_estimate(&keys) < 1024 / 2);
+
+        for &key in &keys {
+            assert!(identity.insert(key, 0).is_ok());
+            assert!(fibonacci.insert(key, 0).is_ok());
         }
+        assert_eq!(identity.collisions(), 1023);
+        assert_eq!(fibonacci.collisions(), fibonacci.collision_estimate(&keys));
 
-        match map.insert(16, Vec::new()) {            
-            Err(HashMapErr::ExistentEntry(v)) => {
-                assert_eq!(0, v[0]);
-            },            
-            _ => assert!(false)
+        // Duplicates never collide
+        assert_eq!(identity.collision_estimate(&[3, 3, 3, 1027]), 1);
+        assert_eq!(identity.collision_estimate(&[]), 0);
+    }
+
+    /// Scrambled placement is a hasher of two words, not a table of `N`
+    #[test]
+    fn test_seeded_hasher_memory() {
+        let plain = identity_map::<u64, 1024>();
+        let seeded = HashMap::<u64, 1024>::new_with_seed(1337);
+        for key in 0..4096 {
+            let _ = plain.insert(key, key as u64);
+            let _ = seeded.insert(key, key as u64);
         }
+        assert!((0..4096).all(|key| plain.lookup(key) == seeded.lookup(key)));
+        assert!((0..1024).all(|key| plain.get_idx(key) == key));
+
+        assert_eq!(seeded.memory_usage(), plain.memory_usage());
+        // The counter's cache line alignment may even pad the words away
+        assert!(core::mem::size_of_val(&seeded) - core::mem::size_of_val(&plain)
+            <= core::mem::size_of::<SeededHasher>());
     }
 
     #[test]
-    fn test_collisions_1() {
-        let map = HashMap::<Vec<u8>, 8>::new();       
-        
-        let _  = map.insert(0, vec![0u8, 255]);
-        let _  = map.insert(8, Vec::new());
-        let _  = map.insert(16, vec![0u8, 1u8, 1u8, 1u8, 1u8]);
+    fn test_difference_intersection() {
+        let mut ours: HashMap<u64, 64> = (0..100).map(|key| (key, key as u64)).collect();
+        let mut theirs: HashMap<String, 16, FibonacciHasher> = (50..150).map(|key| (key, key.to_string())).collect();
+        let _ = ours.remove(10);
+        let _ = theirs.remove(60);
+
+        let mut diff: Vec<_> = ours.difference(&theirs).map(|(&key, &val)| (key, val)).collect();
+        diff.sort_unstable();
+        let expected: Vec<_> = (0..50).chain([60]).filter(|&key| key != 10).map(|key| (key, key as u64)).collect();
+        assert_eq!(diff, expected);
+
+        let mut common: Vec<_> = ours.intersection(&theirs).map(|(&key, _)| key).collect();
+        common.sort_unstable();
+        assert_eq!(common, (50..100).filter(|&key| key != 60).collect::<Vec<_>>());
+
+        let mut diff: Vec<_> = theirs.difference(&ours).map(|(&key, _)| key).collect();
+        diff.sort_unstable();
+        assert_eq!(diff, (100..150).collect::<Vec<_>>());
+        assert_eq!(ours.difference(&ours).count(), 0);
+        assert_eq!(ours.intersection(&ours).count(), ours.entries());
+    }
 
-        let _  = map.insert(24, Vec::new());
-        let _  = map.insert(32, Vec::new());
-        let _  = map.insert(2, Vec::new());
-        let _  = map.insert(24, Vec::new()); // key exists already
-        
-        assert_eq!(map.entries(), 6);
-        // the first entry is not a collision
-        // THe next 4 entries collide with the entry 0
-        assert_eq!(map.collisions(), 4); 
+    #[test]
+    fn test_from_raw_parts() {
+        let buckets: Box<[Bucket<String>; 8]> = unsafe { alloc_zeroed_array() };
+        let entry = |key: usize, val: &str| alloc_in(&Global, Entry::new(key, val.to_string()));
+
+        // 1 -> 9 -> 17 (removed) in bucket 4, 3 alone in bucket 6
+        let idx = |key| FibonacciHasher.index(key, 8);
+        assert!([1, 9, 17, 22].iter().all(|&key| idx(key) == 4) && idx(3) == 6);
+        let chain = [entry(1, "one"), entry(9, "nine"), entry(17, "")];
+        unsafe {
+            (*chain[0]).next.store(chain[1], Ordering::Relaxed);
+            (*chain[1]).next.store(chain[2], Ordering::Relaxed);
+            (*chain[2]).next.store(ptr::null_mut::<Entry<String>>().map_addr(|addr| addr | STATE_TOMBSTONE), Ordering::Relaxed);
+            (*chain[1]).seq.store(4, Ordering::Relaxed);
+        }
+        buckets[4].store(chain[0], Ordering::Relaxed);
+        buckets[6].store(entry(3, "three"), Ordering::Relaxed);
+
+        let map = unsafe { HashMap::<String, 8>::from_raw_parts(buckets, 3) };
+        assert_eq!(map.validate(), Ok(()));
+        assert_eq!(map.entries(), 3);
+        assert_eq!(map.collisions(), 2);
+        assert_eq!(map.lookup(9).unwrap(), "nine");
+        assert_eq!(map.lookup(3).unwrap(), "three");
+        assert_eq!(map.lookup(17), None);
+
+        // Inserts pick up from there, stamped after the adopted entries
+        assert!(map.insert(17, "seventeen".into()).is_ok());
+        assert!(map.insert(22, "twenty two".into()).is_ok());
+        assert_eq!(map.collisions(), 3);
+        assert_eq!(map.iter_ordered().last().unwrap(), (&22, &"twenty two".to_string()));
+        assert_eq!(map.entries(), 5);
+        assert_eq!(map.validate(), Ok(()));
     }
 
     #[test]
-    fn test_iter1() {
-        let map = HashMap::<Vec<u8>, 8>::new();       
-        
-        let _  = map.insert(0, vec![0u8, 255]);
-        let _  = map.insert(8, Vec::new());
-        let _  = map.insert(16, vec![0u8, 1u8, 1u8, 1u8, 1u8]);
+    fn test_memory_usage() {
+        let entry = core::mem::size_of::<Entry<u64>>();
+        let table = 64 * core::mem::size_of::<Bucket<u64>>();
+
+        let map = HashMap::<u64, 64>::new();
+        assert_eq!(map.memory_usage(), table);
+        for key in 0..100 {
+            let before = map.memory_usage();
+            let _ = map.insert(key, 0);
+            assert_eq!(map.memory_usage(), before + entry);
+        }
+        // Duplicates allocate nothing
+        let _ = map.insert(3, 1);
+        assert_eq!(map.memory_usage(), table + 100 * entry);
 
-        let _  = map.insert(24, Vec::new());
-        let _  = map.insert(32, vec![0u8, 2u8, 2u8, 2u8, 2u8]);        
-        let _  = map.insert(2, Vec::new());
-        let _  = map.insert(24, Vec::new()); // key exists already
-        
-        assert_eq!(map.collisions(), 4); 
+        // Freed entries kept for reuse still count
+        let mut map = map;
+        map.clear();
+        assert_eq!(map.memory_usage(), table + 64 * entry);
 
-        assert_eq!(6, map.iter().count())
-            
-    
+        static LAZY: StaticHashMap<u64, 64> = HashMap::new_const();
+        assert_eq!(LAZY.memory_usage(), table);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+
+    use loom::{sync::Arc, thread};
+
+    use super::*;
+
+    /// Two threads inserting the same two keys of a bucket in opposite
+    /// orders, through every interleaving loom finds
+    #[test]
+    fn loom_colliding_inserts() {
+        // Unbounded takes minutes in debug builds. Three preemptions still
+        // catch a lost CAS on the head or on the tail
+        let mut model = loom::model::Builder::new();
+        model.preemption_bound = Some(3);
+        model.check(|| {
+            let map = Arc::new(HashMap::<u64, 4, IdentityHasher>::with_hasher(IdentityHasher));
+
+            let handles: Vec<_> = [[1, 5], [5, 1]].into_iter().enumerate().map(|(t, keys)| {
+                let map = map.clone();
+                thread::spawn(move || keys.map(|key| map.insert(key, t as u64).is_ok()))
+            }).collect();
+            let won: Vec<[bool; 2]> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+            // One insert per key got in, the value left is the winner's
+            for (key, [first, second]) in [(1, [won[0][0], won[1][1]]), (5, [won[0][1], won[1][0]])] {
+                assert!(first != second, "key {key}: both or none inserted");
+                assert_eq!(map.lookup(key), Some(&(second as u64)));
+            }
+
+            // Neither entry got lost behind the other
+            assert_eq!(map.entries(), 2);
+            assert_eq!(map.collisions(), 1);
+            assert_eq!(map.iter().count(), 2);
+            assert_eq!(map.validate(), Ok(()));
+        });
     }
 }
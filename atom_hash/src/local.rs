@@ -0,0 +1,212 @@
+//! The chained map for a single thread.
+//!
+//! `HashMap` pays for its concurrency on every operation: bucket and `next`
+//! loads are atomic, inserts link entries with a CAS and bump an atomic
+//! counter. `LocalHashMap` has the same layout, a bucket array of chains of
+//! boxed entries appended at the tail, but with plain pointers in `Cell`s.
+//! That makes it `!Sync`, so the compiler keeps it on one thread, which can
+//! still insert through `&self` and keep the references it got back.
+//!
+//! Fed the same keys and values, both maps give the same answers and the
+//! same iteration order.
+
+use core::{cell::Cell, ptr};
+use alloc::boxed::Box;
+
+use crate::{alloc_zeroed_array, FibonacciHasher, HashMapErr, IndexHasher};
+
+struct LocalEntry<V> {
+    key         : usize,
+    val         : V,
+    next        : Cell<*mut LocalEntry<V>>,
+}
+
+pub struct LocalHashMap<V, const N: usize, H = FibonacciHasher> {
+
+    /// Number of entries in the table
+    entries         : Cell<usize>,
+
+    /// Heads of the chains, null for empty buckets
+    table           : Box<[Cell<*mut LocalEntry<V>>; N]>,
+
+    /// Picks the bucket for a key
+    hasher          : H,
+}
+
+/// The entries are owned by the map, moving it to another thread moves them
+unsafe impl<V: Send, const N: usize, H: Send> Send for LocalHashMap<V, N, H> {}
+
+impl<V, const N: usize> Default for LocalHashMap<V, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V, const N: usize> LocalHashMap<V, N> {
+
+    pub fn new() -> Self {
+        Self::with_hasher(FibonacciHasher)
+    }
+}
+
+impl<V, const N: usize, H: IndexHasher> LocalHashMap<V, N, H> {
+
+    /// Evaluated on construction, a map without buckets fails to compile
+    const NON_EMPTY: () = assert!(N > 0, "LocalHashMap needs at least one bucket");
+
+    /// Build a map placing keys with `hasher`
+    pub fn with_hasher(hasher: H) -> Self {
+        let () = Self::NON_EMPTY;
+
+        LocalHashMap {
+            entries:       Cell::new(0),
+            // All zeroes are null heads
+            table:         unsafe { alloc_zeroed_array() },
+            hasher,
+        }
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries.get()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries() == 0
+    }
+
+    #[inline]
+    fn bucket(&self, key: usize) -> &Cell<*mut LocalEntry<V>> {
+        &self.table[self.hasher.index(key, N)]
+    }
+
+    pub fn lookup(&self, key: usize) -> Option<&V> {
+        let mut entry_ptr = self.bucket(key).get();
+
+        while !entry_ptr.is_null() {
+            let entry = unsafe { &*entry_ptr };
+            if entry.key == key {
+                return Some(&entry.val);
+            }
+            entry_ptr = entry.next.get();
+        }
+
+        None
+    }
+
+    pub fn contains_key(&self, key: usize) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    /// Insert a entry into the table, keeping the value already there for a
+    /// duplicate key like `HashMap::insert`
+    #[must_use = "the insert may have been rejected"]
+    pub fn insert(&self, key: usize, value: V) -> Result<&V, HashMapErr<'_, V>> {
+        // The tail link to fill, the bucket itself for an empty chain
+        let mut link = self.bucket(key);
+
+        loop {
+            let entry_ptr = link.get();
+            if entry_ptr.is_null() {
+                break;
+            }
+
+            let entry = unsafe { &*entry_ptr };
+            if entry.key == key {
+                return Err(HashMapErr::ExistentEntry(&entry.val));
+            }
+            link = &entry.next;
+        }
+
+        let new_ptr = Box::into_raw(Box::new(LocalEntry {
+            key,
+            val:  value,
+            next: Cell::new(ptr::null_mut()),
+        }));
+        link.set(new_ptr);
+        self.entries.set(self.entries.get() + 1);
+
+        Ok(unsafe { &(*new_ptr).val })
+    }
+
+    /// Live pairs, bucket by bucket and in insertion order within a bucket
+    pub fn iter(&self) -> impl Iterator<Item = (&usize, &V)> + '_ {
+        self.table.iter().flat_map(|bucket| {
+            let mut entry_ptr = bucket.get();
+            core::iter::from_fn(move || {
+                let entry = unsafe { entry_ptr.as_ref()? };
+                entry_ptr = entry.next.get();
+                Some((&entry.key, &entry.val))
+            })
+        })
+    }
+}
+
+impl<V, const N: usize, H> Drop for LocalHashMap<V, N, H> {
+    fn drop(&mut self) {
+        for bucket in self.table.iter() {
+            let mut entry_ptr = bucket.get();
+            while !entry_ptr.is_null() {
+                let entry = unsafe { Box::from_raw(entry_ptr) };
+                entry_ptr = entry.next.get();
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use std::vec::Vec;
+    use std::string::{String, ToString};
+
+    use super::*;
+    use crate::{HashMap, IdentityHasher, SeededHasher};
+    use xorshift::Rng;
+
+    #[test]
+    fn test_local() {
+        let map = LocalHashMap::<String, 64, IdentityHasher>::with_hasher(IdentityHasher);
+        assert!(map.is_empty());
+
+        let first = map.insert(3, "three".to_string()).unwrap();
+        // Chained behind 3, the reference stays valid
+        assert_eq!(map.insert(67, "sixty-seven".to_string()).unwrap(), "sixty-seven");
+        assert_eq!(first, "three");
+
+        assert!(matches!(map.insert(3, "again".to_string()), Err(HashMapErr::ExistentEntry(val)) if val == "three"));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.lookup(67).map(String::as_str), Some("sixty-seven"));
+        assert!(!map.contains_key(131));
+    }
+
+    /// The same sequence, duplicates included, through both maps
+    #[test]
+    fn test_local_matches_atomic() {
+        let local = LocalHashMap::<u64, 1024, SeededHasher>::with_hasher(SeededHasher::new(1337));
+        let atomic = HashMap::<u64, 1024>::new_with_seed(1337);
+
+        // The value either map ended up with for the key
+        let kept = |res: Result<&u64, HashMapErr<'_, u64>>| match res {
+            Ok(val) | Err(HashMapErr::ExistentEntry(val)) => (res.is_ok(), *val),
+            Err(HashMapErr::HashMapFull) => unreachable!("uncapped maps"),
+        };
+
+        let mut rng = Rng::new(789678922);
+        for _ in 0..4096 {
+            // Few enough keys for repeats and long chains
+            let key = rng.get_random(3000);
+            let val = rng.get_random(100000000) as u64;
+            assert_eq!(kept(local.insert(key, val)), kept(atomic.insert(key, val)));
+        }
+
+        assert_eq!(local.entries(), atomic.entries());
+        for key in 0..3000 {
+            assert_eq!(local.lookup(key), atomic.lookup(key));
+        }
+        assert_eq!(local.iter().collect::<Vec<_>>(), atomic.iter().collect::<Vec<_>>());
+    }
+}
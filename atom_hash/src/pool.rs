@@ -0,0 +1,100 @@
+//! Entry slots reserved up front for every bucket, see
+//! `HashMap::with_capacity_hint`.
+//!
+//! One allocation holds `per_bucket` entries for each bucket, those of a
+//! bucket next to each other, so filling the first entries of a chain
+//! doesn't hit the allocator and walking them stays within a few cache lines.
+//! A bitmap tells the slots holding an entry, claimed with a `fetch_or` by
+//! the insert about to link one. Past its slots a bucket spills to the free
+//! list and the allocator like any other map.
+//!
+//! A slot is given back to its bucket when its entry is freed, never to the
+//! free list, whose entries are deallocated one by one.
+
+use core::{alloc::Layout, ptr::NonNull};
+use alloc::{alloc::handle_alloc_error, boxed::Box};
+
+use crate::{Allocator, Entry};
+use crate::sync::{AtomicUsize, Ordering};
+
+const WORD_BITS: usize = usize::BITS as usize;
+
+pub(crate) struct EntryPool<V> {
+    /// `per_bucket` slots for every bucket, bucket `idx` owning the ones
+    /// from `idx * per_bucket`
+    slots       : NonNull<Entry<V>>,
+    len         : usize,
+    per_bucket  : usize,
+    /// Bit `i` is set while slot `i` holds an entry
+    claimed     : Box<[AtomicUsize]>,
+}
+
+impl<V> EntryPool<V> {
+
+    /// Slots for `buckets` buckets, from `alloc`
+    pub(crate) fn new_in<A: Allocator>(buckets: usize, per_bucket: usize, alloc: &A) -> Self {
+        let len = buckets.checked_mul(per_bucket).expect("entry pool too big");
+        let layout = Layout::array::<Entry<V>>(len).expect("entry pool too big");
+
+        let slots = match alloc.allocate(layout) {
+            Ok(raw) => raw.cast::<Entry<V>>(),
+            Err(_)  => handle_alloc_error(layout),
+        };
+
+        EntryPool {
+            slots,
+            len,
+            per_bucket,
+            claimed:    (0..len.div_ceil(WORD_BITS)).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    /// A free slot of bucket `idx`, uninitialized
+    pub(crate) fn claim(&self, idx: usize) -> Option<*mut Entry<V>> {
+        let first = idx * self.per_bucket;
+
+        (first..first + self.per_bucket).find(|&slot| {
+            let mask = 1 << (slot % WORD_BITS);
+            self.claimed[slot / WORD_BITS].fetch_or(mask, Ordering::AcqRel) & mask == 0
+        })
+        .map(|slot| unsafe { self.slots.as_ptr().add(slot) })
+    }
+
+    /// Whether `entry_ptr` is one of the slots
+    pub(crate) fn contains(&self, entry_ptr: *const Entry<V>) -> bool {
+        let start = self.slots.as_ptr().addr();
+        let end = start + self.len * core::mem::size_of::<Entry<V>>();
+        (start..end).contains(&entry_ptr.addr())
+    }
+
+    /// Give back a slot from `claim`, whose entry is already dropped
+    pub(crate) fn unclaim(&self, entry_ptr: *mut Entry<V>) {
+        debug_assert!(self.contains(entry_ptr));
+
+        let slot = (entry_ptr.addr() - self.slots.as_ptr().addr()) / core::mem::size_of::<Entry<V>>();
+        let mask = 1 << (slot % WORD_BITS);
+        self.claimed[slot / WORD_BITS].fetch_and(!mask, Ordering::AcqRel);
+    }
+
+    pub(crate) fn per_bucket(&self) -> usize {
+        self.per_bucket
+    }
+
+    /// Slots holding an entry
+    pub(crate) fn claimed(&self) -> usize {
+        self.claimed.iter()
+            .map(|word| word.load(Ordering::Acquire).count_ones() as usize)
+            .sum()
+    }
+
+    /// Bytes of the slots
+    pub(crate) fn size(&self) -> usize {
+        self.len * core::mem::size_of::<Entry<V>>()
+    }
+
+    /// Give the slots back to `alloc`, once every entry in them is dropped
+    pub(crate) unsafe fn release<A: Allocator>(&mut self, alloc: &A) {
+        let layout = Layout::array::<Entry<V>>(self.len).unwrap();
+        alloc.deallocate(self.slots.cast(), layout);
+    }
+}
@@ -0,0 +1,109 @@
+//! Probe sequences for the maps storing keys inline in their slot array.
+//!
+//! Linear probing walks the slots after the hashed one, which keeps a probe
+//! run on a few cache lines but lets runs merge into long clusters: a key
+//! hashed anywhere in a cluster has to walk to its end. The other strategies
+//! jump further on every step, so keys hashed to the same area spread out.
+
+use crate::{FibonacciHasher, IndexHasher};
+
+/// How a probe moves on from a taken slot
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeStrategy {
+    /// The next slot, wrapping around
+    #[default]
+    Linear,
+
+    /// Offsets growing as the triangular numbers `i * (i + 1) / 2`, which
+    /// visit every slot of a power of two array
+    Quadratic,
+
+    /// A fixed step derived from a second hash of the key. Keys starting at
+    /// the same slot take different paths from there.
+    DoubleHash,
+}
+
+impl ProbeStrategy {
+
+    /// Whether the sequence visits every one of `n` slots
+    pub fn covers(self, n: usize) -> bool {
+        self == ProbeStrategy::Linear || n.is_power_of_two()
+    }
+
+    /// The `n` slot indices probed for `key`, starting from `start`
+    #[inline]
+    pub(crate) fn sequence(self, key: usize, start: usize, n: usize) -> Probe {
+        let (step, inc) = match self {
+            ProbeStrategy::Linear     => (1, 0),
+            ProbeStrategy::Quadratic  => (1, 1),
+            // Odd, so coprime with a power of two
+            ProbeStrategy::DoubleHash => (2 * FibonacciHasher.index(key, n / 2) + 1, 0),
+        };
+
+        Probe { cur: start, step: step % n, inc, left: n, n }
+    }
+}
+
+pub(crate) struct Probe {
+    cur         : usize,
+    /// Distance to the next slot, below `n`
+    step        : usize,
+    /// Added to `step` after every move
+    inc         : usize,
+    left        : usize,
+    n           : usize,
+}
+
+impl Iterator for Probe {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+
+        let idx = self.cur;
+
+        self.cur += self.step;
+        if self.cur >= self.n {
+            self.cur -= self.n;
+        }
+        self.step += self.inc;
+        if self.step >= self.n {
+            self.step -= self.n;
+        }
+
+        Some(idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Every strategy visits each slot exactly once
+    #[test]
+    fn test_probe_covers() {
+        for strategy in [ProbeStrategy::Linear, ProbeStrategy::Quadratic, ProbeStrategy::DoubleHash] {
+            for n in [1, 2, 8, 64, 1024] {
+                for key in [0, 1, 7, 12345, usize::MAX] {
+                    let mut seen: Vec<_> = strategy.sequence(key, key % n, n).collect();
+                    assert_eq!(seen[0], key % n);
+                    seen.sort();
+                    assert_eq!(seen, (0..n).collect::<Vec<_>>(), "{strategy:?} n={n} key={key}");
+                }
+            }
+        }
+
+        // Linear is the only one walking any size
+        let mut seen: Vec<_> = ProbeStrategy::Linear.sequence(0, 4, 7).collect();
+        seen.sort();
+        assert_eq!(seen, (0..7).collect::<Vec<_>>());
+        assert!(!ProbeStrategy::Quadratic.covers(7));
+    }
+}
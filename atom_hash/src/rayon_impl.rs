@@ -0,0 +1,45 @@
+//! Parallel iteration with `rayon`, behind the `rayon` feature.
+//!
+//! The bucket array is split in ranges handed out to the thread pool, each
+//! range walked with a plain `Iter`. Same guarantees as `iter`: entries
+//! inserted or removed concurrently may or may not show up.
+
+use rayon::prelude::*;
+
+use crate::{HashMap, Iter, IterState};
+
+/// Buckets walked by a single task, small enough to balance the load but
+/// large enough that the splitting doesn't cost more than the walk
+const BUCKETS_PER_TASK: usize = 1024;
+
+impl<V: Sync, const N: usize, H> HashMap<V, N, H> {
+
+    /// Parallel version of `iter`, yielding the live entries in no particular order
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (&usize, &V)> + '_ {
+        self.buckets().par_chunks(BUCKETS_PER_TASK).flat_map_iter(|buckets| Iter {
+            buckets,
+            current_bucket: 0,
+            state: IterState::NextBucket,
+        })
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_par_iter() {
+        let mut map = HashMap::<u64, 4096>::new();
+        for key in 0..20_000 {
+            let _ = map.insert(key * 3, key as u64);
+        }
+        let _ = map.remove(3);
+
+        let serial: u64 = map.iter().map(|(_, val)| *val).sum();
+        let parallel: u64 = map.par_iter().map(|(_, val)| *val).sum();
+        assert_eq!(parallel, serial);
+        assert_eq!(map.par_iter().count(), map.entries());
+    }
+}
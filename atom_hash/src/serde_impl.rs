@@ -0,0 +1,190 @@
+//! `serde` support, behind the `serde` feature.
+//!
+//! A map is written as its capacity followed by the sequence of its
+//! `(key, value)` pairs. Loading checks the capacity against the `N` of the
+//! map being built and fails on a mismatch, then re-inserts every pair.
+
+use core::{fmt, marker::PhantomData};
+use alloc::string::String;
+
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::{HashMap, IndexHasher};
+
+const FIELDS: &[&str] = &["capacity", "entries"];
+
+/// Serializes the live entries as a sequence
+struct Entries<'a, V, const N: usize, H>(&'a HashMap<V, N, H>);
+
+impl<V: Serialize, const N: usize, H> Serialize for Entries<'_, V, N, H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+impl<V: Serialize, const N: usize, H> Serialize for HashMap<V, N, H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("HashMap", 2)?;
+        state.serialize_field("capacity", &N)?;
+        state.serialize_field("entries", &Entries(self))?;
+        state.end()
+    }
+}
+
+/// Inserts the pairs straight into the map, no intermediate `Vec`
+struct EntriesSeed<'a, V, const N: usize, H>(&'a HashMap<V, N, H>);
+
+impl<'de, V: Deserialize<'de>, const N: usize, H: IndexHasher> DeserializeSeed<'de>
+    for EntriesSeed<'_, V, N, H> {
+
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, V: Deserialize<'de>, const N: usize, H: IndexHasher> Visitor<'de>
+    for EntriesSeed<'_, V, N, H> {
+
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of (key, value) pairs")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some((key, val)) = seq.next_element::<(usize, V)>()? {
+            // Duplicates keep the first value, like insert
+            let _ = self.0.insert(key, val);
+        }
+        Ok(())
+    }
+}
+
+fn check_capacity<E: de::Error, const N: usize>(capacity: usize) -> Result<(), E> {
+    if capacity != N {
+        return Err(E::custom(format_args!(
+            "capacity mismatch: map has {} buckets, input has {}", N, capacity)));
+    }
+    Ok(())
+}
+
+struct HashMapVisitor<V, const N: usize, H>(PhantomData<(V, H)>);
+
+impl<'de, V: Deserialize<'de>, const N: usize, H: IndexHasher + Default> Visitor<'de>
+    for HashMapVisitor<V, N, H> {
+
+    type Value = HashMap<V, N, H>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("struct HashMap")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let capacity: usize = seq.next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        check_capacity::<_, N>(capacity)?;
+
+        let map = HashMap::with_hasher(H::default());
+        seq.next_element_seed(EntriesSeed(&map))?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+
+        Ok(map)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let map = HashMap::with_hasher(H::default());
+        let mut capacity = false;
+        let mut entries = false;
+
+        while let Some(field) = access.next_key::<String>()? {
+            match field.as_str() {
+                "capacity" => {
+                    if capacity {
+                        return Err(de::Error::duplicate_field("capacity"));
+                    }
+                    check_capacity::<_, N>(access.next_value()?)?;
+                    capacity = true;
+                }
+
+                "entries" => {
+                    if entries {
+                        return Err(de::Error::duplicate_field("entries"));
+                    }
+                    access.next_value_seed(EntriesSeed(&map))?;
+                    entries = true;
+                }
+
+                _ => {
+                    access.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+
+        if !capacity {
+            return Err(de::Error::missing_field("capacity"));
+        }
+        if !entries {
+            return Err(de::Error::missing_field("entries"));
+        }
+
+        Ok(map)
+    }
+}
+
+impl<'de, V: Deserialize<'de>, const N: usize, H: IndexHasher + Default> Deserialize<'de>
+    for HashMap<V, N, H> {
+
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("HashMap", FIELDS, HashMapVisitor(PhantomData))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_serde_u64() {
+        let map = HashMap::<u64, 8>::new();
+        for key in [0, 8, 16, 3, 5] {
+            let _ = map.insert(key, key as u64 * 10);
+        }
+
+        let json = serde_json::to_string(&map).unwrap();
+        let copy: HashMap<u64, 8> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(copy.entries(), 5);
+        for key in [0, 8, 16, 3, 5] {
+            assert_eq!(*copy.lookup(key).unwrap(), key as u64 * 10);
+        }
+    }
+
+    #[test]
+    fn test_serde_string() {
+        let map = HashMap::<String, 64>::new();
+        let _ = map.insert(1337, "first string".into());
+        let _ = map.insert(1, "second string".into());
+
+        let json = serde_json::to_string(&map).unwrap();
+        let copy: HashMap<String, 64> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(copy.entries(), 2);
+        assert_eq!(copy.lookup(1337).unwrap(), "first string");
+        assert_eq!(copy.lookup(1).unwrap(), "second string");
+    }
+
+    #[test]
+    fn test_serde_capacity_mismatch() {
+        let map = HashMap::<u64, 8>::new();
+        let _ = map.insert(1, 1);
+
+        let json = serde_json::to_string(&map).unwrap();
+        let err = serde_json::from_str::<HashMap<u64, 16>>(&json).err().unwrap();
+        assert!(err.to_string().contains("capacity mismatch"));
+    }
+}
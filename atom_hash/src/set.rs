@@ -0,0 +1,329 @@
+//! A set of `usize` keys stored inline in the bucket array.
+//!
+//! `HashMap<(), N>` works as a set, but every key still costs an `Entry`
+//! allocation for its key and `next` pointer. `AtomicHashSet` keeps the keys
+//! right in an array of `AtomicUsize` and resolves collisions by linear
+//! probing, so inserting never allocates. The trade-offs are the ones the map
+//! moved away from: at most `N` keys, and lookups get slower as the array
+//! fills up since probe runs get longer. There is no removal, an emptied slot
+//! would cut the probe runs going through it.
+//!
+//! An empty slot holds 0, so the array comes zeroed from the allocator. The
+//! key 0 itself is tracked by a flag next to the array.
+//!
+//! Probing is linear unless another `ProbeStrategy` is picked on construction.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+
+use crate::{alloc_zeroed_array, FibonacciHasher, IndexHasher, ProbeStrategy};
+
+/// A slot nobody took yet
+const EMPTY: usize = 0;
+
+pub struct AtomicHashSet<const N: usize, H = FibonacciHasher> {
+
+    /// Number of keys in the set
+    entries         : AtomicUsize,
+
+    /// Whether the key 0, which can't be stored in a slot, is in the set
+    zero            : AtomicBool,
+
+    /// Keys, `EMPTY` for free slots
+    slots           : Box<[AtomicUsize; N]>,
+
+    /// Picks the first slot probed for a key
+    hasher          : H,
+
+    /// Picks the slots probed after it
+    probe           : ProbeStrategy,
+}
+
+impl<const N: usize> AtomicHashSet<N> {
+
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::with_hasher(FibonacciHasher)
+    }
+}
+
+impl<const N: usize, H: IndexHasher> AtomicHashSet<N, H> {
+
+    /// Evaluated on construction, a set without slots fails to compile
+    const NON_EMPTY: () = assert!(N > 0, "AtomicHashSet needs at least one slot");
+
+    /// Build a set probing from the slot picked by `hasher`
+    pub fn with_hasher(hasher: H) -> Self {
+        let () = Self::NON_EMPTY;
+
+        AtomicHashSet {
+            entries:       AtomicUsize::new(0),
+            zero:          AtomicBool::new(false),
+            // All zeroes are empty slots
+            slots:         unsafe { alloc_zeroed_array() },
+            hasher,
+            probe:         ProbeStrategy::Linear,
+        }
+    }
+
+    /// Probe with `strategy` instead of linearly
+    ///
+    /// # Panics
+    ///
+    /// If `strategy` can't reach every slot, only linear probing works with
+    /// a non power of two `N`.
+    pub fn with_probe(mut self, strategy: ProbeStrategy) -> Self {
+        assert!(strategy.covers(N), "{strategy:?} probing needs a power of two size");
+        self.probe = strategy;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of slots, the most keys the set can hold besides 0
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Slots probed for `key`, starting from its hashed one
+    #[inline]
+    fn probe(&self, key: usize) -> impl Iterator<Item = &AtomicUsize> + '_ {
+        let start = self.hasher.index(key, N);
+        self.probe.sequence(key, start, N).map(|idx| &self.slots[idx])
+    }
+
+    /// Longest probe run a lookup of a key in the set takes, in slots
+    pub fn max_probe(&self) -> usize {
+        self.slots.iter()
+            .map(|slot| slot.load(Ordering::Acquire))
+            .filter(|&key| key != EMPTY)
+            .map(|key| 1 + self.probe(key).take_while(|slot| slot.load(Ordering::Acquire) != key).count())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Add `key`, returning whether it wasn't there yet.
+    ///
+    /// # Panics
+    ///
+    /// If the key is not there and every slot is taken.
+    pub fn insert(&self, key: usize) -> bool {
+        if key == EMPTY {
+            let inserted = !self.zero.swap(true, Ordering::AcqRel);
+            if inserted {
+                self.entries.fetch_add(1, Ordering::AcqRel);
+            }
+            return inserted;
+        }
+
+        for slot in self.probe(key) {
+            let mut cur = slot.load(Ordering::Acquire);
+
+            if cur == EMPTY {
+                match slot.compare_exchange(EMPTY, key,
+                    Ordering::AcqRel,
+                    Ordering::Acquire) {
+                    Ok(_) => {
+                        self.entries.fetch_add(1, Ordering::AcqRel);
+                        return true;
+                    }
+                    // Someone took it first, maybe with our key
+                    Err(actual) => cur = actual,
+                }
+            }
+
+            // Slots are never emptied, so the key can't be further along
+            if cur == key {
+                return false;
+            }
+        }
+
+        // The probe sequence ends after visiting each slot once, so this is only
+        // reached with every slot taken by another key
+        panic!("AtomicHashSet is full");
+    }
+
+    pub fn contains(&self, key: usize) -> bool {
+        if key == EMPTY {
+            return self.zero.load(Ordering::Acquire);
+        }
+
+        for slot in self.probe(key) {
+            match slot.load(Ordering::Acquire) {
+                // Inserts fill the first empty slot of the run
+                EMPTY => return false,
+                cur if cur == key => return true,
+                _ => {}
+            }
+        }
+
+        false
+    }
+
+    /// Keys in the set, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        let zero = self.zero.load(Ordering::Acquire).then_some(0);
+
+        zero.into_iter().chain(self.slots.iter()
+            .map(|slot| slot.load(Ordering::Acquire))
+            .filter(|&key| key != EMPTY))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{FibonacciHasher, IdentityHasher};
+
+    #[test]
+    fn test_set() {
+        let set = AtomicHashSet::<2048>::new();
+        assert!(set.is_empty());
+
+        for key in 0..1000 {
+            assert!(set.insert(key * 3));
+        }
+        for key in 0..1000 {
+            assert!(!set.insert(key * 3));
+        }
+
+        assert_eq!(set.len(), 1000);
+        for key in 0..3000 {
+            assert_eq!(set.contains(key), key % 3 == 0);
+        }
+
+        let mut keys: Vec<_> = set.iter().collect();
+        keys.sort();
+        assert_eq!(keys, (0..1000).map(|key| key * 3).collect::<Vec<_>>());
+    }
+
+    /// Every slot taken, probe runs wrap around the end of the array
+    #[test]
+    fn test_set_full() {
+        let set = AtomicHashSet::<8, FibonacciHasher>::with_hasher(FibonacciHasher);
+
+        for key in 1..=8 {
+            assert!(set.insert(key));
+        }
+        assert!(set.insert(0));
+        assert_eq!(set.len(), 9);
+
+        for key in 0..=8 {
+            assert!(set.contains(key));
+            assert!(!set.insert(key));
+        }
+        assert!(!set.contains(9));
+    }
+
+    #[test]
+    #[should_panic(expected = "AtomicHashSet is full")]
+    fn test_set_overflow() {
+        let set = AtomicHashSet::<8>::new();
+        for key in 1..=9 {
+            set.insert(key);
+        }
+    }
+
+    #[test]
+    fn test_set_probe_strategies() {
+        let strategies = [ProbeStrategy::Linear, ProbeStrategy::Quadratic, ProbeStrategy::DoubleHash];
+
+        let max_probes: Vec<_> = strategies.iter().map(|&strategy| {
+            let set = AtomicHashSet::<1024, IdentityHasher>::with_hasher(IdentityHasher).with_probe(strategy);
+
+            // A cluster over the first 256 slots, then keys all hashed into it
+            for key in 1..=256 {
+                assert!(set.insert(key));
+            }
+            for key in 1..=256 {
+                assert!(set.insert(key * 1024 + 1));
+            }
+
+            assert_eq!(set.len(), 512);
+            for key in 1..=256 {
+                assert!(set.contains(key));
+                assert!(set.contains(key * 1024 + 1));
+                assert!(!set.contains(key * 1024 + 2));
+            }
+            assert_eq!(set.iter().count(), 512);
+
+            set.max_probe()
+        }).collect();
+
+        // Linear walks the cluster and every key added after it
+        assert_eq!(max_probes[0], 512);
+        assert!(max_probes[1] < max_probes[0]);
+        assert!(max_probes[2] < max_probes[1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Quadratic probing needs a power of two size")]
+    fn test_set_probe_size() {
+        let _ = AtomicHashSet::<1000>::new().with_probe(ProbeStrategy::Quadratic);
+    }
+
+    /// Two threads racing for the last free slot, every probe run wraps
+    #[test]
+    fn test_set_last_slot_race() {
+        for strategy in [ProbeStrategy::Linear, ProbeStrategy::Quadratic, ProbeStrategy::DoubleHash] {
+            let set = Arc::new(AtomicHashSet::<64, IdentityHasher>::with_hasher(IdentityHasher).with_probe(strategy));
+            for key in 2..=64 {
+                assert!(set.insert(key));
+            }
+
+            // Different keys, the loser finds the set truly full
+            let handles: Vec<_> = [1000, 2000].into_iter().map(|key| {
+                let set_tx = set.clone();
+                std::thread::spawn(move || set_tx.insert(key))
+            }).collect();
+            let results: Vec<_> = handles.into_iter().map(|h| h.join()).collect();
+
+            assert_eq!(results.iter().filter(|r| matches!(r, Ok(true))).count(), 1);
+            assert_eq!(results.iter().filter(|r| r.is_err()).count(), 1);
+            assert_eq!(set.len(), 64);
+            assert!(set.contains(1000) != set.contains(2000));
+        }
+
+        // The same key, the loser finds it in the slot it lost
+        let set = Arc::new(AtomicHashSet::<64, IdentityHasher>::with_hasher(IdentityHasher));
+        for key in 2..=64 {
+            set.insert(key);
+        }
+        let handles: Vec<_> = (0..2).map(|_| {
+            let set_tx = set.clone();
+            std::thread::spawn(move || set_tx.insert(1))
+        }).collect();
+        let inserted = handles.into_iter().map(|h| h.join().unwrap()).filter(|&ok| ok).count();
+        assert_eq!(inserted, 1);
+        assert_eq!(set.len(), 64);
+    }
+
+    #[test]
+    fn test_set_threads() {
+        let set = Arc::new(AtomicHashSet::<1024>::new());
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let set_tx = set.clone();
+            std::thread::spawn(move || {
+                (0..512).filter(|&key| set_tx.insert(key * 2)).count()
+            })
+        }).collect();
+
+        let inserted: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+        // Exactly one thread added every key
+        assert_eq!(inserted, 512);
+        assert_eq!(set.len(), 512);
+        assert!((0..512).all(|key| set.contains(key * 2)));
+    }
+}
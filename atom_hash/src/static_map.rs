@@ -0,0 +1,89 @@
+//! A `HashMap` built in a `const`, for statics.
+//!
+//! Nothing can be heap allocated at compile time, so the map itself, bucket
+//! array included, is boxed by the first access. Racing threads may both
+//! build one, only the first one published is kept. Until then a static
+//! takes a pointer in BSS however large `N` is.
+//!
+//! Only statics pay for the null check on every access, a `HashMap` built
+//! at run time allocates its buckets up front.
+
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use alloc::boxed::Box;
+
+use crate::HashMap;
+
+pub struct StaticHashMap<V, const N: usize> {
+
+    /// Null until first used
+    map             : AtomicPtr<HashMap<V, N>>,
+}
+
+/// Safety: the `HashMap` is owned and shared like a field would be.
+unsafe impl<V, const N: usize> Send for StaticHashMap<V, N> where HashMap<V, N>: Send {}
+
+/// Safety: see `Send`, every thread gets the same `&HashMap`.
+unsafe impl<V, const N: usize> Sync for StaticHashMap<V, N> where HashMap<V, N>: Sync {}
+
+impl<V, const N: usize> StaticHashMap<V, N> {
+
+    pub const fn new() -> Self {
+        StaticHashMap {
+            map:           AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    #[cold]
+    fn init(&self) -> &HashMap<V, N> {
+        let new_ptr = Box::into_raw(Box::new(HashMap::new()));
+
+        match self.map.compare_exchange(ptr::null_mut(), new_ptr,
+            Ordering::AcqRel,
+            Ordering::Acquire) {
+            Ok(_) => unsafe { &*new_ptr },
+            Err(map_ptr) => {
+                drop(unsafe { Box::from_raw(new_ptr) });
+                unsafe { &*map_ptr }
+            }
+        }
+    }
+}
+
+impl<V, const N: usize> Default for StaticHashMap<V, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V, const N: usize> Deref for StaticHashMap<V, N> {
+    type Target = HashMap<V, N>;
+
+    #[inline]
+    fn deref(&self) -> &HashMap<V, N> {
+        let map_ptr = self.map.load(Ordering::Acquire);
+        if map_ptr.is_null() {
+            return self.init();
+        }
+        unsafe { &*map_ptr }
+    }
+}
+
+impl<V, const N: usize> DerefMut for StaticHashMap<V, N> {
+    fn deref_mut(&mut self) -> &mut HashMap<V, N> {
+        if self.map.get_mut().is_null() {
+            self.init();
+        }
+        unsafe { &mut **self.map.get_mut() }
+    }
+}
+
+impl<V, const N: usize> Drop for StaticHashMap<V, N> {
+    fn drop(&mut self) {
+        let map_ptr = *self.map.get_mut();
+        if !map_ptr.is_null() {
+            drop(unsafe { Box::from_raw(map_ptr) });
+        }
+    }
+}
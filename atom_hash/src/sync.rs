@@ -0,0 +1,55 @@
+//! The atomics of the chained map, loom's when built with `--cfg loom`.
+//!
+//! `HashMap`, its entry counter, free list and chain index take their
+//! atomics from here, so `loom::model` can explore every interleaving and
+//! memory ordering of their CAS loops. Loom atomics only exist inside a
+//! model and can't be built in a `const`, so `--cfg loom` is for the loom
+//! tests alone: the other tests are compiled out. A cfg rather than a
+//! feature, so `--all-features` never turns it on:
+//! `RUSTFLAGS="--cfg loom" cargo test --lib loom`.
+//!
+//! `get_mut` has no loom counterpart, plain access under `&mut` goes through
+//! `UnsyncAccess` instead.
+
+#[cfg(not(loom))]
+pub(crate) use core::{hint::spin_loop, sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering}};
+
+#[cfg(loom)]
+pub(crate) use loom::{hint::spin_loop, sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering}};
+
+/// The value of an atomic we have exclusive access to
+pub(crate) trait UnsyncAccess<T> {
+    fn read_mut(&mut self) -> T;
+    fn write_mut(&mut self, val: T);
+}
+
+macro_rules! unsync_access {
+    ($atomic:ty, $val:ty $(, $param:ident)?) => {
+        impl$(<$param>)? UnsyncAccess<$val> for $atomic {
+            #[cfg(not(loom))]
+            #[inline]
+            fn read_mut(&mut self) -> $val {
+                *self.get_mut()
+            }
+
+            #[cfg(not(loom))]
+            #[inline]
+            fn write_mut(&mut self, val: $val) {
+                *self.get_mut() = val;
+            }
+
+            #[cfg(loom)]
+            fn read_mut(&mut self) -> $val {
+                self.with_mut(|cur| *cur)
+            }
+
+            #[cfg(loom)]
+            fn write_mut(&mut self, val: $val) {
+                self.with_mut(|cur| *cur = val);
+            }
+        }
+    };
+}
+
+unsync_access!(AtomicUsize, usize);
+unsync_access!(AtomicPtr<T>, *mut T, T);
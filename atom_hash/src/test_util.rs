@@ -0,0 +1,19 @@
+//! Fixtures shared by the test modules
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Counts drops in a shared counter, carrying a payload to check values by
+pub(crate) struct DropCounter(pub(crate) Arc<AtomicUsize>, pub(crate) usize);
+
+impl DropCounter {
+    pub(crate) fn new(drops: &Arc<AtomicUsize>) -> Self {
+        DropCounter(drops.clone(), 0)
+    }
+}
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -26,9 +26,11 @@ fn test_compare_perf() {
 
     let start = Instant::now();
 
-    let handles: Vec<_> = (0..10).map(|x| {
+    let mut seeder = Rng::new(12125125);
+
+    let handles: Vec<_> = (0..10).map(|_| {
+        let mut rng = seeder.split();
         std::thread::spawn(move || {
-            let mut rng = Rng::new(x + 12125125 );
             for _ in 0..MAP_SIZE/2 {
                 let _ = map.insert(rng.rand(), 
                     (rng.get_random(100000000) as u64) + 1).ok();                
@@ -55,10 +57,12 @@ fn test_compare_perf_brown() {
 
     let start = Instant::now();
 
-    let handles: Vec<_> = (0..10).map(|x| {
+    let mut seeder = Rng::new(12125125);
+
+    let handles: Vec<_> = (0..10).map(|_| {
         let xmap = map.clone();
+        let mut rng = seeder.split();
         std::thread::spawn(move || {
-            let mut rng = Rng::new(x + 12125125 );
             for _ in 0..MAP_SIZE/2 {
                 let _ = xmap.lock().unwrap().insert(rng.rand(), 
                     (rng.get_random(100000000) as u64) + 1);                
@@ -85,10 +89,12 @@ fn test_compare_perf_brown_rwlock() {
 
     let start = Instant::now();
 
-    let handles: Vec<_> = (0..10).map(|x| {
+    let mut seeder = Rng::new(12125125);
+
+    let handles: Vec<_> = (0..10).map(|_| {
         let locked_map = map.clone();
+        let mut rng = seeder.split();
         std::thread::spawn(move || {
-            let mut rng = Rng::new(x + 12125125 );
             for _ in 0..MAP_SIZE/2 {
                 let mut xmap = locked_map.write().unwrap();
                 let _ = xmap.insert(rng.rand(), 
@@ -167,6 +173,37 @@ fn atomhash_lookup_test() {
     let elapsed = start.elapsed().as_secs_f64();
 
     println!("Elapsed time: {:10.6}", elapsed);
+
+    println!("Starting batched lookups");
+
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..5).map(|x| {
+        std::thread::spawn(move || {
+            let mut rng = Rng::new(x + 12312545 );
+            let mut keys = vec![0; 1024];
+            let mut vals = vec![0; 1024];
+            let mut out = vec![None; 1024];
+            for _ in 0..MAP_SIZE/3/1024 {
+                for (key, val) in keys.iter_mut().zip(&mut vals) {
+                    *key = rng.rand();
+                    *val = (rng.get_random(100000000) as u64) + 1;
+                }
+                map.lookup_many(&keys, &mut out);
+                for (found, val) in out.iter().zip(&vals) {
+                    assert_eq!(*found.unwrap(), *val);
+                }
+            }
+        })
+    }).collect();
+
+    for h in handles {
+        let _ = h.join();
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!("Elapsed time: {:10.6}", elapsed);
 }
 
 fn hashbrown_lookup_test() {
@@ -217,11 +254,202 @@ fn hashbrown_lookup_test() {
     println!("Elapsed time: {:10.6}", elapsed);
 }
 
+/// Values small enough to live in the bucket array, the boxed entries
+/// against the inline slots. Half full, open addressing needs the room.
+fn inline_lookup_test() {
+    let boxed: &'static _ = Box::leak(Box::new(
+        HashMap::<u32, MAP_SIZE>::new_with_seed(1337)
+    ));
+    let inline: &'static _ = Box::leak(Box::new(
+        InlineHashMap::<u32, MAP_SIZE, SeededHasher>::with_hasher(SeededHasher::new(1337))
+    ));
+
+    let handles: Vec<_> = (0..5).map(|x| {
+        std::thread::spawn(move || {
+            let mut rng = Rng::new(x + 12312545 );
+            for _ in 0..MAP_SIZE/10 {
+                let key = rng.rand();
+                let val = rng.get_random(100000000) as u32 + 1;
+                let _ = boxed.insert(key, val);
+                inline.try_insert(key, val);
+            }
+        })
+    }).collect();
+
+    for h in handles {
+        let _ = h.join();
+    }
+
+    println!("Inserted entries: {} / {}", boxed.entries(), inline.len());
+
+    println!("Starting lookups, boxed values");
+
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..5).map(|x| {
+        std::thread::spawn(move || {
+            let mut rng = Rng::new(x + 12312545 );
+            for _ in 0..MAP_SIZE/10 {
+                assert_eq!(*boxed.lookup(rng.rand()).unwrap(),
+                     rng.get_random(100000000) as u32 + 1);
+            }
+        })
+    }).collect();
+
+    for h in handles {
+        let _ = h.join();
+    }
+
+    println!("Elapsed time: {:10.6}", start.elapsed().as_secs_f64());
+
+    println!("Starting lookups, inline values");
+
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..5).map(|x| {
+        std::thread::spawn(move || {
+            let mut rng = Rng::new(x + 12312545 );
+            for _ in 0..MAP_SIZE/10 {
+                assert_eq!(inline.lookup(rng.rand()).unwrap(),
+                     rng.get_random(100000000) as u32 + 1);
+            }
+        })
+    }).collect();
+
+    for h in handles {
+        let _ = h.join();
+    }
+
+    println!("Elapsed time: {:10.6}", start.elapsed().as_secs_f64());
+}
+
+/// The 10 threads of `test_threads_3x` inserting distinct keys, where every
+/// insert also bumps the entries counter
+fn threaded_insert_test() {
+    let map: &'static _ = Box::leak(Box::new(
+        HashMap::<u64, MAP_SIZE>::new_with_seed(1337)
+    ));
+
+    println!("Threaded inserts");
+
+    let start = Instant::now();
+
+    let mut seeder = Rng::new(12125125);
+
+    let handles: Vec<_> = (0..10).map(|_| {
+        let mut rng = seeder.split();
+        std::thread::spawn(move || {
+            for _ in 0..MAP_SIZE/20 {
+                let _ = map.insert(rng.rand(),
+                    (rng.get_random(100000000) as u64) + 1);
+            }
+        })
+    }).collect();
+
+    for h in handles {
+        let _ = h.join();
+    }
+
+    println!("Elapsed time: {:10.6}", start.elapsed().as_secs_f64());
+    println!("Inserted entries: {}", map.entries());
+}
+
+/// 10 threads inserting a range of keys split round robin, so the buckets
+/// they CAS are neighbours, with the plain and the interleaved bucket layout
+fn interleaved_insert_test() {
+    for interleave in [false, true] {
+        let mut map = HashMap::<u64, MAP_SIZE, IdentityHasher>::with_hasher(IdentityHasher);
+        if interleave {
+            map = map.with_interleaved_buckets();
+        }
+        let map: &'static _ = Box::leak(Box::new(map));
+
+        println!("Round robin inserts, interleaved buckets: {}", interleave);
+
+        let start = Instant::now();
+
+        let handles: Vec<_> = (0..10).map(|t| {
+            std::thread::spawn(move || {
+                for key in 0..MAP_SIZE/20 {
+                    let _ = map.insert(key * 10 + t, key as u64);
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            let _ = h.join();
+        }
+
+        println!("Elapsed time: {:10.6}", start.elapsed().as_secs_f64());
+        println!("Inserted entries: {}", map.entries());
+    }
+}
+
+/// One thread inserting, the atomic map against the one with plain pointers
+fn single_thread_insert_test() {
+    let atomic = HashMap::<u64, MAP_SIZE>::new_with_seed(1337);
+    let local = LocalHashMap::<u64, MAP_SIZE, SeededHasher>::with_hasher(SeededHasher::new(1337));
+
+    println!("Single thread inserts, atomic");
+
+    let start = Instant::now();
+    let mut rng = Rng::new(12312545);
+    for _ in 0..MAP_SIZE/3 {
+        let _ = atomic.insert(rng.rand(),
+            (rng.get_random(100000000) as u64) + 1);
+    }
+    println!("Elapsed time: {:10.6}", start.elapsed().as_secs_f64());
+
+    println!("Single thread inserts, local");
+
+    let start = Instant::now();
+    let mut rng = Rng::new(12312545);
+    for _ in 0..MAP_SIZE/3 {
+        let _ = local.insert(rng.rand(),
+            (rng.get_random(100000000) as u64) + 1);
+    }
+    println!("Elapsed time: {:10.6}", start.elapsed().as_secs_f64());
+
+    println!("Inserted entries: {} / {}", atomic.entries(), local.entries());
+}
+
+/// Dedup workload: inserts of keys already in the map, which fail before
+/// their entry is allocated, against the same loop paying an allocation each
+fn duplicate_insert_test() {
+    const KEYS: usize = 1 << 20;
+    let map = HashMap::<u64, MAP_SIZE>::new_with_seed(1337);
+    for key in 0..KEYS {
+        let _ = map.insert(key, key as u64);
+    }
+
+    println!("Duplicate inserts");
+
+    let start = Instant::now();
+    let mut rng = Rng::new(12312545);
+    for _ in 0..MAP_SIZE/3 {
+        let _ = map.insert(rng.get_random(KEYS), 1);
+    }
+    println!("Elapsed time: {:10.6}", start.elapsed().as_secs_f64());
+
+    println!("Duplicate inserts, boxing each value first");
+
+    let start = Instant::now();
+    let mut rng = Rng::new(12312545);
+    for _ in 0..MAP_SIZE/3 {
+        let boxed = std::hint::black_box(Box::new(1u64));
+        let _ = map.insert(rng.get_random(KEYS), *boxed);
+    }
+    println!("Elapsed time: {:10.6}", start.elapsed().as_secs_f64());
+
+    println!("Inserted entries: {}", map.entries());
+}
+
 fn test_lookups() {
     atomhash_lookup_test();
 
     hashbrown_lookup_test();
 
+    inline_lookup_test();
 }
 
 fn main() {
@@ -229,5 +457,12 @@ fn main() {
     //test_high_write_contention();
     test_lookups();
 
+    single_thread_insert_test();
+
+    threaded_insert_test();
+
+    interleaved_insert_test();
+
+    duplicate_insert_test();
 
 }
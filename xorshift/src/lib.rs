@@ -1,24 +1,102 @@
 #![no_std]
 
+extern crate alloc;
+
+use alloc::{collections::BTreeSet, vec::Vec};
+use core::fmt;
+
+/// Zero is a fixed point of xorshift, seeding with it gives this state
+const ZERO_SEED_STATE: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// A zero seed would yield nothing but zeroes
+#[inline]
+fn seed_state(seed: u64) -> u64 {
+    if seed == 0 { ZERO_SEED_STATE } else { seed }
+}
+
+/// Next output of the splitmix64 generator over `state`, which spreads
+/// nearby seeds apart. Used to fill the state of the generators from a seed.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Uniform value in `[lo, hi)` from the words of `next`, see
+/// `Rng::get_random_range`
+fn random_range(lo: usize, hi: usize, mut next: impl FnMut() -> u64) -> usize {
+    assert!(lo < hi, "empty range");
+
+    let span = (hi - lo) as u64;
+    // Largest multiple of span that fits, less than a span is rejected
+    let zone = u64::MAX - (u64::MAX - span + 1) % span;
+    loop {
+        let x = next();
+        if x <= zone {
+            return lo + (x % span) as usize;
+        }
+    }
+}
+
+#[cfg(feature = "xorshift128plus")]
+mod xorshift128plus;
+#[cfg(feature = "xorshift128plus")]
+pub use xorshift128plus::Rng128Plus;
+
+#[cfg(feature = "xoshiro256starstar")]
+mod xoshiro256starstar;
+#[cfg(feature = "xoshiro256starstar")]
+pub use xoshiro256starstar::Rng256StarStar;
+
+/// `sample_distinct` was asked for more distinct values than there are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooFewValues {
+    pub count:  usize,
+    pub modulo: usize,
+}
+
+impl fmt::Display for TooFewValues {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can't draw {} distinct values below {}", self.count, self.modulo)
+    }
+}
+
+impl core::error::Error for TooFewValues {}
+
+/// xorshift over a 64-bit word, whatever the target's `usize` width is,
+/// so a seed yields the same stream everywhere.
+///
+/// The state never becomes zero, its period is 2^64 - 1.
 pub struct Rng {
-    state: usize,
+    state: u64,
     iter:  usize
 }
 
 impl Rng {
 
+    /// Generator starting from `seed`. Zero, which xorshift can't leave,
+    /// is replaced by a fixed nonzero state.
     pub fn new(seed: usize) -> Self {
         Rng {
-            state: seed,
+            state: seed_state(seed as u64),
             iter:  0,
         }
     }
 
+    /// Restart from `seed`, remapping zero like `new`
     pub fn seed(&mut self, seed: usize) {
-        self.state = seed;
+        self.state = seed_state(seed as u64);
+    }
+
+    /// Whether the state is off the zero fixed point. Always true for a
+    /// generator built through this API.
+    pub fn is_valid_state(&self) -> bool {
+        self.state != 0
     }
 
-    pub fn get_state(&self) -> usize {
+    pub fn get_state(&self) -> u64 {
         self.state
     }
 
@@ -26,20 +104,118 @@ impl Rng {
         self.iter
     }
 
-    pub fn rand(&mut self) -> usize {
+    /// Next 64-bit word of the stream. The shifts are the ones this crate
+    /// always used, so 64-bit streams didn't change with the explicit state.
+    pub fn rand_u64(&mut self) -> u64 {
         self.state ^= self.state << 13;
         self.state ^= self.state >> 17;
         self.state ^= self.state << 5;
         self.iter += 1;
 
-        return self.state;
+        self.state
+    }
+
+    /// Next word truncated to `usize`
+    pub fn rand(&mut self) -> usize {
+        self.rand_u64() as usize
     }
 
+    /// Value in `[0, top)`. The plain modulo is slightly biased towards the
+    /// low values when `top` doesn't divide 2^64, which is negligible for a
+    /// small `top`. Use `get_random_range` when it matters.
     pub fn get_random(&mut self, top: usize) -> usize {
-        return self.rand() % top;
+        self.rand() % top
+    }
+
+    /// Uniform value in `[lo, hi)`. Words from the incomplete span at the
+    /// top of the word space are rejected and drawn again, so every value
+    /// is equally likely.
+    ///
+    /// # Panics
+    ///
+    /// If the range is empty.
+    pub fn get_random_range(&mut self, lo: usize, hi: usize) -> usize {
+        random_range(lo, hi, || self.rand_u64())
+    }
+
+    /// `count` distinct values in `[0, modulo)`, in the order drawn. Tests
+    /// inserting keys from `rand` can get a repeat and one entry less than
+    /// they expect, these can't.
+    ///
+    /// Draws are retried until they miss the values taken so far. When
+    /// most of the range is wanted, that turns into waiting for the last
+    /// few misses, so a partial shuffle of the whole range is used instead.
+    pub fn sample_distinct(&mut self, count: usize, modulo: usize) -> Result<Vec<usize>, TooFewValues> {
+        if count > modulo {
+            return Err(TooFewValues { count, modulo });
+        }
+
+        if count > modulo / 2 {
+            let mut values: Vec<usize> = (0..modulo).collect();
+            for i in 0..count {
+                let j = self.get_random_range(i, modulo);
+                values.swap(i, j);
+            }
+            values.truncate(count);
+            return Ok(values);
+        }
+
+        let mut seen = BTreeSet::new();
+        let mut values = Vec::with_capacity(count);
+        while values.len() < count {
+            let x = self.get_random_range(0, modulo);
+            if seen.insert(x) {
+                values.push(x);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Child generator for another thread. Nearby seeds give correlated
+    /// xorshift streams, so the child seed goes through a splitmix64 step
+    /// of the parent's next output instead.
+    pub fn split(&mut self) -> Rng {
+        let z = splitmix64(&mut self.rand_u64());
+
+        Rng {
+            state: seed_state(z),
+            iter:  0,
+        }
+    }
+
+    /// Fill `buf` with successive words, little endian. A tail shorter
+    /// than a word uses the low bytes of one more word.
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        let mut chunks = buf.chunks_exact_mut(8);
+        for chunk in &mut chunks {
+            chunk.copy_from_slice(&self.rand_u64().to_le_bytes());
+        }
+
+        let tail = chunks.into_remainder();
+        if !tail.is_empty() {
+            let word = self.rand_u64().to_le_bytes();
+            tail.copy_from_slice(&word[..tail.len()]);
+        }
     }
 }
 
+/// Plugs the generator into the `rand` ecosystem (distributions, shuffles).
+/// `next_u64` is `rand_u64`, `next_u32` its low half, and `fill_bytes` is
+/// the inherent one.
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        self.rand_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rand_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        Rng::fill_bytes(self, dest)
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -54,6 +230,156 @@ mod tests {
         }       
     }
 
+    /// Streams are fixed for a seed, on every target
+    #[test]
+    fn test_reference_vector() {
+        let mut rng = Rng::new(1);
+        let expected = [
+            0x42021,
+            0x1004080601,
+            0x421290949428c5,
+            0x140048203d8063,
+            0x83b5cc6350d3271b,
+        ];
+        for x in expected {
+            assert_eq!(rng.rand_u64(), x);
+        }
+        assert_eq!(rng.get_iteration(), 5);
+        assert_eq!(rng.get_state(), 0x83b5cc6350d3271b);
+
+        let mut rng = Rng::new(1);
+        assert_eq!(rng.rand(), 0x42021);
+    }
+
+    #[test]
+    fn test_fill_bytes() {
+        let mut buf1 = [0u8; 17];
+        let mut buf2 = [0u8; 17];
+
+        Rng::new(1337).fill_bytes(&mut buf1);
+        Rng::new(1337).fill_bytes(&mut buf2);
+        assert_eq!(buf1, buf2);
+
+        // Whole words first, the tail takes the low bytes of the third one
+        let mut rng = Rng::new(1337);
+        assert_eq!(buf1[..8], rng.rand_u64().to_le_bytes());
+        assert_eq!(buf1[8..16], rng.rand_u64().to_le_bytes());
+        assert_eq!(buf1[16], rng.rand_u64().to_le_bytes()[0]);
+
+        Rng::new(1338).fill_bytes(&mut buf2);
+        assert_ne!(buf1, buf2);
+    }
+
+    #[test]
+    fn test_split() {
+        let mut parent = Rng::new(12125125);
+        let mut child1 = parent.split();
+        let mut child2 = parent.split();
+
+        assert_ne!(child1.rand(), child2.rand());
+        for _ in 1..1000 {
+            assert_ne!(child1.rand(), child2.rand());
+        }
+
+        // Splitting is deterministic
+        let mut parent = Rng::new(12125125);
+        assert_eq!(parent.split().get_state(), Rng::new(12125125).split().get_state());
+        assert_ne!(parent.split().rand(), parent.rand());
+    }
+
+    #[test]
+    fn test_zero_seed() {
+        let mut rng = Rng::new(0);
+        assert!(rng.is_valid_state());
+        assert_ne!(rng.rand(), 0);
+
+        // The stream doesn't collapse
+        let mut zeroes = 0;
+        let mut prev = 0;
+        for _ in 0..1000 {
+            let x = rng.rand_u64();
+            assert_ne!(x, prev);
+            zeroes += (x == 0) as usize;
+            prev = x;
+        }
+        assert_eq!(zeroes, 0);
+        assert!(rng.is_valid_state());
+
+        rng.seed(0);
+        assert_eq!(rng.get_state(), Rng::new(0).get_state());
+        assert_eq!(rng.rand(), Rng::new(0).rand());
+    }
+
+    #[test]
+    fn test_get_random_range() {
+        let mut rng = Rng::new(1337);
+
+        // Chi-square over 10 buckets: 9 degrees of freedom, 27.88 is p = 0.001
+        const BUCKETS: usize = 10;
+        const DRAWS: usize = 100_000;
+        let mut counts = [0usize; BUCKETS];
+        for _ in 0..DRAWS {
+            let x = rng.get_random_range(100, 100 + BUCKETS);
+            assert!((100..100 + BUCKETS).contains(&x));
+            counts[x - 100] += 1;
+        }
+
+        let expected = (DRAWS / BUCKETS) as f64;
+        let chi2: f64 = counts.iter()
+            .map(|&c| (c as f64 - expected) * (c as f64 - expected) / expected)
+            .sum();
+        assert!(chi2 < 27.88, "chi2 = {}", chi2);
+
+        // Degenerate and full spans
+        assert_eq!(rng.get_random_range(5, 6), 5);
+        let _ = rng.get_random_range(0, usize::MAX);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_random_range_empty() {
+        Rng::new(1337).get_random_range(3, 3);
+    }
+
+    #[test]
+    fn test_sample_distinct() {
+        let mut rng = Rng::new(1337);
+
+        // Sparse draws, then most of the range, then all of it
+        for (count, modulo) in [(1000, usize::MAX), (1000, 1500), (64, 64), (0, 0)] {
+            let mut values = rng.sample_distinct(count, modulo).unwrap();
+            assert_eq!(values.len(), count);
+            assert!(values.iter().all(|&x| x < modulo));
+
+            values.sort();
+            values.dedup();
+            assert_eq!(values.len(), count, "duplicates for {count} below {modulo}");
+        }
+
+        assert_eq!(Rng::new(7).sample_distinct(100, 1000), Rng::new(7).sample_distinct(100, 1000));
+        assert_eq!(rng.sample_distinct(11, 10), Err(TooFewValues { count: 11, modulo: 10 }));
+    }
+
+    #[cfg(feature = "rand_core")]
+    #[test]
+    fn test_rand_core_shuffle() {
+        use rand::seq::SliceRandom;
+        use rand_core::RngCore;
+
+        let mut rng = Rng::new(1337);
+        assert_eq!(RngCore::next_u64(&mut rng), Rng::new(1337).rand_u64());
+
+        let mut a: [usize; 32] = core::array::from_fn(|i| i);
+        let mut b = a;
+        a.shuffle(&mut Rng::new(1337));
+        b.shuffle(&mut Rng::new(1337));
+        assert_eq!(a, b);
+        assert_ne!(a, core::array::from_fn(|i| i));
+
+        b.shuffle(&mut Rng::new(1338));
+        assert_ne!(a, b);
+    }
+
     // #[test]
     // fn test2() {
     //     let rngs: Vec<_> = (1..100).map(
@@ -0,0 +1,110 @@
+//! xorshift128+, two words of state and the sum of both as output.
+//!
+//! Passes BigCrush where plain xorshift64 fails, the lowest bit is still an
+//! LFSR though. The shifts are the 23, 17, 26 of Vigna's paper.
+
+use crate::{random_range, splitmix64};
+
+pub struct Rng128Plus {
+    state: [u64; 2],
+    iter:  usize,
+}
+
+impl Rng128Plus {
+
+    /// Generator whose state is filled by splitmix64 from `seed`, so nearby
+    /// seeds give unrelated streams. Every seed is fine, zero included.
+    pub fn new(seed: usize) -> Self {
+        let mut sm = seed as u64;
+        Self::from_state([splitmix64(&mut sm), splitmix64(&mut sm)])
+    }
+
+    /// Generator starting from `state` as is, for reference streams.
+    ///
+    /// # Panics
+    ///
+    /// If the state is all zeroes, which the generator can't leave.
+    pub fn from_state(state: [u64; 2]) -> Self {
+        assert!(state != [0; 2], "all zero state");
+        Rng128Plus { state, iter: 0 }
+    }
+
+    pub fn get_state(&self) -> [u64; 2] {
+        self.state
+    }
+
+    pub fn get_iteration(&self) -> usize {
+        self.iter
+    }
+
+    pub fn rand_u64(&mut self) -> u64 {
+        let [mut s1, s0] = self.state;
+        let result = s0.wrapping_add(s1);
+
+        s1 ^= s1 << 23;
+        self.state = [s0, s1 ^ s0 ^ (s1 >> 17) ^ (s0 >> 26)];
+        self.iter += 1;
+
+        result
+    }
+
+    /// Next word truncated to `usize`
+    pub fn rand(&mut self) -> usize {
+        self.rand_u64() as usize
+    }
+
+    /// Value in `[0, top)`, with the small modulo bias of `Rng::get_random`
+    pub fn get_random(&mut self, top: usize) -> usize {
+        self.rand() % top
+    }
+
+    /// Uniform value in `[lo, hi)`, like `Rng::get_random_range`
+    ///
+    /// # Panics
+    ///
+    /// If the range is empty.
+    pub fn get_random_range(&mut self, lo: usize, hi: usize) -> usize {
+        random_range(lo, hi, || self.rand_u64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_vector_128plus() {
+        let mut rng = Rng128Plus::from_state([1, 2]);
+        let expected = [
+            0x3,
+            0x800045,
+            0x2000104,
+            0x4000020010c3,
+            0xc00002103045,
+            0x1000801c450c4,
+        ];
+        for x in expected {
+            assert_eq!(rng.rand_u64(), x);
+        }
+        assert_eq!(rng.get_iteration(), 6);
+
+        // Seeding goes through splitmix64, whose stream from 1 starts so
+        assert_eq!(Rng128Plus::new(1).get_state(), [0x910a2dec89025cc1, 0xbeeb8da1658eec67]);
+        assert_ne!(Rng128Plus::new(0).rand(), 0);
+    }
+
+    #[test]
+    fn test_get_random_128plus() {
+        let mut rng = Rng128Plus::new(1337);
+        for _ in 0..1000 {
+            assert!(rng.get_random(10) < 10);
+            assert!((5..8).contains(&rng.get_random_range(5, 8)));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_state_128plus() {
+        Rng128Plus::from_state([0, 0]);
+    }
+}
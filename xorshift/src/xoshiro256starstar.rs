@@ -0,0 +1,117 @@
+//! xoshiro256**, four words of state scrambled by a multiply and rotate.
+//!
+//! The all-purpose generator of Blackman and Vigna: passes BigCrush with no
+//! weak low bits, at twice the state of `Rng128Plus`.
+
+use crate::{random_range, splitmix64};
+
+pub struct Rng256StarStar {
+    state: [u64; 4],
+    iter:  usize,
+}
+
+impl Rng256StarStar {
+
+    /// Generator whose state is filled by splitmix64 from `seed`, so nearby
+    /// seeds give unrelated streams. Every seed is fine, zero included.
+    pub fn new(seed: usize) -> Self {
+        let mut sm = seed as u64;
+        Self::from_state(core::array::from_fn(|_| splitmix64(&mut sm)))
+    }
+
+    /// Generator starting from `state` as is, for reference streams.
+    ///
+    /// # Panics
+    ///
+    /// If the state is all zeroes, which the generator can't leave.
+    pub fn from_state(state: [u64; 4]) -> Self {
+        assert!(state != [0; 4], "all zero state");
+        Rng256StarStar { state, iter: 0 }
+    }
+
+    pub fn get_state(&self) -> [u64; 4] {
+        self.state
+    }
+
+    pub fn get_iteration(&self) -> usize {
+        self.iter
+    }
+
+    pub fn rand_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        self.iter += 1;
+
+        result
+    }
+
+    /// Next word truncated to `usize`
+    pub fn rand(&mut self) -> usize {
+        self.rand_u64() as usize
+    }
+
+    /// Value in `[0, top)`, with the small modulo bias of `Rng::get_random`
+    pub fn get_random(&mut self, top: usize) -> usize {
+        self.rand() % top
+    }
+
+    /// Uniform value in `[lo, hi)`, like `Rng::get_random_range`
+    ///
+    /// # Panics
+    ///
+    /// If the range is empty.
+    pub fn get_random_range(&mut self, lo: usize, hi: usize) -> usize {
+        random_range(lo, hi, || self.rand_u64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The stream of the reference implementation from 1, 2, 3, 4
+    #[test]
+    fn test_reference_vector_256starstar() {
+        let mut rng = Rng256StarStar::from_state([1, 2, 3, 4]);
+        let expected = [
+            11520,
+            0,
+            1509978240,
+            1215971899390074240,
+            1216172134540287360,
+            607988272756665600,
+        ];
+        for x in expected {
+            assert_eq!(rng.rand_u64(), x);
+        }
+        assert_eq!(rng.get_iteration(), 6);
+
+        // Seeding goes through splitmix64, whose stream from 1 starts so
+        assert_eq!(Rng256StarStar::new(1).get_state(),
+            [0x910a2dec89025cc1, 0xbeeb8da1658eec67, 0xf893a2eefb32555e, 0x71c18690ee42c90b]);
+        assert_ne!(Rng256StarStar::new(0).rand(), 0);
+    }
+
+    #[test]
+    fn test_get_random_256starstar() {
+        let mut rng = Rng256StarStar::new(1337);
+        for _ in 0..1000 {
+            assert!(rng.get_random(10) < 10);
+            assert!((5..8).contains(&rng.get_random_range(5, 8)));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_state_256starstar() {
+        Rng256StarStar::from_state([0; 4]);
+    }
+}